@@ -0,0 +1,17 @@
+-- Hourly rollup of LaundryLog per pep, kept after raw rows are deleted by retention
+-- Maintained by the retention task in the app
+-- availability is the share of samples where the machine was idle and not flagged unavailable
+
+CREATE TABLE LaundryLogHourly (
+    pep_id NVARCHAR(255) NOT NULL,
+    hour DATETIMEOFFSET NOT NULL,
+    samples BIGINT NOT NULL,
+    press_start BIGINT NOT NULL,
+    running BIGINT NOT NULL,
+    idle BIGINT NOT NULL,
+    unknown BIGINT NOT NULL,
+    other BIGINT NOT NULL,
+    availability FLOAT NOT NULL,
+    PRIMARY KEY (pep_id, hour),
+    FOREIGN KEY (pep_id) REFERENCES PhysicalEndpoint(pep_id)
+);
@@ -0,0 +1,6 @@
+-- CSC fields kept so the CSC compatible api returns them as reported, NULL on older and backfilled rows
+-- time_remaining_reported is false when CSC sent no time remaining and 0 was stored
+-- Boolean columns converted to BIT
+
+ALTER TABLE LaundryLog ADD available BIT NULL;
+ALTER TABLE LaundryLog ADD time_remaining_reported BIT NULL;
@@ -0,0 +1,13 @@
+-- Full machine metadata from the CSC machines endpoint
+-- first_seen/last_seen track which machines are still in service
+-- controller_type is nullable, older controllers do not report a type
+-- JSON columns converted to NVARCHAR(MAX), boolean columns converted to BIT
+
+ALTER TABLE Machines ADD
+    capabilities NVARCHAR(MAX) NULL,
+    pricing NVARCHAR(MAX) NULL,
+    in_service BIT NULL,
+    free_play BIT NULL,
+    first_seen DATETIMEOFFSET NULL,
+    last_seen DATETIMEOFFSET NULL;
+ALTER TABLE Machines ALTER COLUMN controller_type NVARCHAR(MAX) NULL;
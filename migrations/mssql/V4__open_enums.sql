@@ -0,0 +1,23 @@
+-- Machine type and state accept values CSC adds later, e.g. 'combo' or 'outOfOrder'
+-- Drops the enum CHECK constraints from V1 and only requires a non empty value
+-- V1 constraints are unnamed, their generated names are looked up from sys.check_constraints
+-- Known MachineState enum values: pressStart, running, idle, unknown
+-- Known MachineType enum values: washer, dryer
+
+DECLARE @drop_sql NVARCHAR(MAX);
+
+SELECT @drop_sql = 'ALTER TABLE Machines DROP CONSTRAINT ' + QUOTENAME(name)
+FROM sys.check_constraints
+WHERE parent_object_id = OBJECT_ID('Machines') AND definition LIKE '%washer%';
+IF @drop_sql IS NOT NULL
+    EXEC sp_executesql @drop_sql;
+
+SET @drop_sql = NULL;
+SELECT @drop_sql = 'ALTER TABLE LaundryLog DROP CONSTRAINT ' + QUOTENAME(name)
+FROM sys.check_constraints
+WHERE parent_object_id = OBJECT_ID('LaundryLog') AND definition LIKE '%pressStart%';
+IF @drop_sql IS NOT NULL
+    EXEC sp_executesql @drop_sql;
+
+ALTER TABLE Machines ADD CONSTRAINT CK_Machines_type CHECK (LEN(type) > 0);
+ALTER TABLE LaundryLog ADD CONSTRAINT CK_LaundryLog_state CHECK (LEN(state) > 0);
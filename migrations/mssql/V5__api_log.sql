@@ -0,0 +1,21 @@
+-- Every request made to the CSC api, shares [timestamp] with the LaundryLog rows it produced
+-- Raw body stored as VARBINARY(MAX), gzip compressed when body_encoding is 'gzip'
+-- location_id and room_id use NVARCHAR(255) so they can be indexed
+
+CREATE TABLE ApiLog (
+    api_log_id BIGINT IDENTITY(1, 1) NOT NULL,
+    [timestamp] DATETIMEOFFSET NOT NULL,
+    url NVARCHAR(MAX) NOT NULL,
+    location_id NVARCHAR(255),
+    room_id NVARCHAR(255),
+    status INT,
+    latency_ms BIGINT NOT NULL,
+    error_kind NVARCHAR(32),
+    error NVARCHAR(MAX),
+    body VARBINARY(MAX),
+    body_encoding NVARCHAR(16),
+    PRIMARY KEY (api_log_id),
+    CHECK (body_encoding IN ('identity', 'gzip'))
+);
+
+CREATE INDEX IX_ApiLog_room_timestamp ON ApiLog (location_id, room_id, [timestamp]);
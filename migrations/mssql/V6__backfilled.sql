@@ -0,0 +1,4 @@
+-- Rows synthesized by the backfill job while the CSC api was not responding
+-- Boolean column converted to BIT
+
+ALTER TABLE LaundryLog ADD backfilled BIT NOT NULL CONSTRAINT DF_LaundryLog_backfilled DEFAULT 0;
@@ -0,0 +1,50 @@
+-- Load claiming, users claim running cycles and group them into laundry loads
+-- Table named Users because USER is a reserved keyword in SQL Server
+-- name uses NVARCHAR(255) so it can be unique
+-- Claimed cycles reference LaundryLog by (pep_id, cycle_start) without a foreign key
+
+CREATE TABLE Users (
+    user_id BIGINT IDENTITY(1, 1) NOT NULL,
+    name NVARCHAR(255) NOT NULL,
+    created_on DATETIMEOFFSET NOT NULL,
+    PRIMARY KEY (user_id),
+    CONSTRAINT UQ_Users_name UNIQUE (name)
+);
+
+CREATE TABLE LaundryLoad (
+    load_id BIGINT IDENTITY(1, 1) NOT NULL,
+    user_id BIGINT NOT NULL,
+    created_on DATETIMEOFFSET NOT NULL,
+    PRIMARY KEY (load_id),
+    FOREIGN KEY (user_id) REFERENCES Users(user_id)
+);
+
+CREATE TABLE WasherLaundryLoads (
+    load_id BIGINT NOT NULL,
+    pep_id NVARCHAR(255) NOT NULL,
+    cycle_start DATETIMEOFFSET NOT NULL,
+    PRIMARY KEY (pep_id, cycle_start),
+    FOREIGN KEY (load_id) REFERENCES LaundryLoad(load_id),
+    FOREIGN KEY (pep_id) REFERENCES PhysicalEndpoint(pep_id)
+);
+
+CREATE TABLE DryerLaundryLoad (
+    load_id BIGINT NOT NULL,
+    pep_id NVARCHAR(255) NOT NULL,
+    cycle_start DATETIMEOFFSET NOT NULL,
+    PRIMARY KEY (pep_id, cycle_start),
+    FOREIGN KEY (load_id) REFERENCES LaundryLoad(load_id),
+    FOREIGN KEY (pep_id) REFERENCES PhysicalEndpoint(pep_id)
+);
+
+CREATE TABLE UserLogClaim (
+    user_id BIGINT NOT NULL,
+    pep_id NVARCHAR(255) NOT NULL,
+    [timestamp] DATETIMEOFFSET NOT NULL,
+    claimed_on DATETIMEOFFSET NOT NULL,
+    PRIMARY KEY (user_id, pep_id, [timestamp]),
+    FOREIGN KEY (user_id) REFERENCES Users(user_id),
+    FOREIGN KEY (pep_id) REFERENCES PhysicalEndpoint(pep_id)
+);
+
+CREATE INDEX IX_LaundryLoad_user_id ON LaundryLoad (user_id);
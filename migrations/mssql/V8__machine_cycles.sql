@@ -0,0 +1,20 @@
+-- Cycles derived from LaundryLog state transitions, one row per run of a machine
+-- cycle_start is the first running LaundryLog row
+-- cycle_end and duration_seconds are null while the machine is running
+
+CREATE TABLE MachineCycles (
+    pep_id NVARCHAR(255) NOT NULL,
+    cycle_start DATETIMEOFFSET NOT NULL,
+    cycle_end DATETIMEOFFSET,
+    duration_seconds BIGINT,
+    machine_settings NVARCHAR(MAX),
+    interrupted BIT NOT NULL DEFAULT 0,
+    estimated_end BIT NOT NULL DEFAULT 0,
+    door_opened DATETIMEOFFSET,
+    door_open_seconds BIGINT,
+    updated_on DATETIMEOFFSET NOT NULL,
+    PRIMARY KEY (pep_id, cycle_start),
+    FOREIGN KEY (pep_id) REFERENCES PhysicalEndpoint(pep_id)
+);
+
+CREATE INDEX IX_MachineCycles_cycle_start ON MachineCycles (cycle_start);
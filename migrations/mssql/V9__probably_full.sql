@@ -0,0 +1,6 @@
+-- Laundry probably left in the machine after a cycle, maintained by cycle detection
+-- Boolean column converted to BIT
+
+ALTER TABLE MachineCycles ADD probably_full BIT NOT NULL CONSTRAINT DF_MachineCycles_probably_full DEFAULT 0;
+
+CREATE INDEX IX_MachineCycles_probably_full ON MachineCycles (pep_id) WHERE probably_full = 1;
@@ -0,0 +1,20 @@
+-- Hourly rollup of LaundryLog per pep, kept after raw rows are dropped by retention
+-- Continuous aggregate refreshed by a policy, the policy and raw retention are added by the app
+-- from the [retention] config. WITH NO DATA so it can be created inside the migration transaction
+-- availability is the share of samples where the machine was idle and not flagged unavailable
+
+CREATE MATERIALIZED VIEW LaundryLogHourly
+WITH (timescaledb.continuous) AS
+SELECT
+    pep_id,
+    time_bucket(INTERVAL '1 hour', "timestamp") AS hour,
+    count(*) AS samples,
+    count(*) FILTER (WHERE state = 'pressStart') AS press_start,
+    count(*) FILTER (WHERE state = 'running') AS running,
+    count(*) FILTER (WHERE state = 'idle') AS idle,
+    count(*) FILTER (WHERE state = 'unknown') AS unknown,
+    count(*) FILTER (WHERE state NOT IN ('pressStart', 'running', 'idle', 'unknown')) AS other,
+    avg(CASE WHEN state = 'idle' AND not_available_reason IS NULL THEN 1.0 ELSE 0.0 END) AS availability
+FROM LaundryLog
+GROUP BY pep_id, hour
+WITH NO DATA;
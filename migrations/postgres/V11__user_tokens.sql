@@ -0,0 +1,4 @@
+-- Bearer token a user claims and lists loads with, returned once when the user is created
+-- Users created before this have no token and can not authenticate
+
+ALTER TABLE Users ADD COLUMN token TEXT;
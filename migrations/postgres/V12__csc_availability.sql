@@ -0,0 +1,5 @@
+-- CSC fields kept so the CSC compatible api returns them as reported, NULL on older and backfilled rows
+-- time_remaining_reported is false when CSC sent no time remaining and 0 was stored
+
+ALTER TABLE LaundryLog ADD COLUMN available BOOLEAN;
+ALTER TABLE LaundryLog ADD COLUMN time_remaining_reported BOOLEAN;
@@ -0,0 +1,67 @@
+-- Postgres 18 + TimescaleDB Migration
+-- V1 shipped empty, tables are created here so databases that already ran it get them too
+-- Enums converted to TEXT with CHECK constraints; the app binds enum values as text parameters
+-- MachineState enum values: pressStart, running, idle, unknown
+-- MachineType enum values: washer, dryer
+-- UUID columns converted to TEXT; the app binds ids as text parameters
+-- TIMESTAMPTZ columns converted to TIMESTAMP; the app binds naive UTC timestamps
+-- Integer columns converted to BIGINT; the app binds all integers as 64 bit
+-- JSON columns converted to JSONB
+-- LaundryLog is a Timescale hypertable partitioned on "timestamp" with a 1 day chunk interval
+
+CREATE TABLE Locations (
+    location_id TEXT NOT NULL,
+    description TEXT,
+    label TEXT NOT NULL,
+    timezone TEXT NOT NULL,
+    PRIMARY KEY (location_id)
+);
+
+CREATE TABLE Rooms (
+    room_id TEXT NOT NULL,
+    description TEXT,
+    label TEXT NOT NULL,
+    PRIMARY KEY (room_id)
+);
+
+CREATE TABLE Machines (
+    machine_id TEXT NOT NULL,
+    qr_code_id TEXT NOT NULL,
+    nfc_id TEXT NOT NULL,
+    controller_type TEXT NOT NULL,
+    type TEXT NOT NULL,
+    license_plate VARCHAR(7) NOT NULL,
+    PRIMARY KEY (machine_id),
+    CHECK (type IN ('washer', 'dryer')),
+    CHECK (length(license_plate) <= 7)
+);
+
+CREATE TABLE PhysicalEndpoint (
+    pep_id TEXT NOT NULL UNIQUE,
+    added_on TIMESTAMP NOT NULL DEFAULT CURRENT_DATE,
+    room_id TEXT NOT NULL,
+    location_id TEXT NOT NULL,
+    machine_id TEXT NOT NULL,
+    sticker_number BIGINT NOT NULL,
+    PRIMARY KEY (room_id, location_id, machine_id, sticker_number),
+    FOREIGN KEY (room_id) REFERENCES Rooms(room_id),
+    FOREIGN KEY (location_id) REFERENCES Locations(location_id),
+    FOREIGN KEY (machine_id) REFERENCES Machines(machine_id)
+);
+
+CREATE TABLE LaundryLog (
+    pep_id TEXT NOT NULL,
+    timestamp TIMESTAMP NOT NULL,
+    time_remaining BIGINT NOT NULL,
+    not_available_reason TEXT,
+    door_closed BOOLEAN NOT NULL,
+    state TEXT NOT NULL,
+    machine_settings JSONB,
+    PRIMARY KEY (pep_id, timestamp),
+    CHECK (state IN ('pressStart', 'running', 'idle', 'unknown')),
+    FOREIGN KEY (pep_id) REFERENCES PhysicalEndpoint(pep_id)
+) WITH (
+    tsdb.hypertable,
+    tsdb.partition_column = 'timestamp',
+    tsdb.chunk_interval = '1 day'
+);
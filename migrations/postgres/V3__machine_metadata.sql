@@ -0,0 +1,11 @@
+-- Full machine metadata from the CSC machines endpoint
+-- first_seen/last_seen track which machines are still in service
+-- controller_type is nullable, older controllers do not report a type
+
+ALTER TABLE Machines ADD COLUMN capabilities JSONB;
+ALTER TABLE Machines ADD COLUMN pricing JSONB;
+ALTER TABLE Machines ADD COLUMN in_service BOOLEAN;
+ALTER TABLE Machines ADD COLUMN free_play BOOLEAN;
+ALTER TABLE Machines ADD COLUMN first_seen TIMESTAMP;
+ALTER TABLE Machines ADD COLUMN last_seen TIMESTAMP;
+ALTER TABLE Machines ALTER COLUMN controller_type DROP NOT NULL;
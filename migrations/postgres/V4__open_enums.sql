@@ -0,0 +1,10 @@
+-- Machine type and state accept values CSC adds later, e.g. 'combo' or 'outOfOrder'
+-- Drops the generated enum CHECK constraints from V2 and only requires a non empty value
+-- Known MachineState enum values: pressStart, running, idle, unknown
+-- Known MachineType enum values: washer, dryer
+
+ALTER TABLE Machines DROP CONSTRAINT machines_type_check;
+ALTER TABLE Machines ADD CONSTRAINT machines_type_check CHECK (length(type) > 0);
+
+ALTER TABLE LaundryLog DROP CONSTRAINT laundrylog_state_check;
+ALTER TABLE LaundryLog ADD CONSTRAINT laundrylog_state_check CHECK (length(state) > 0);
@@ -0,0 +1,21 @@
+-- Every request made to the CSC api, shares "timestamp" with the LaundryLog rows it produced
+-- Raw body stored as BYTEA, gzip compressed when body_encoding is 'gzip'
+-- ApiLog is not a hypertable, it is small compared to LaundryLog
+
+CREATE TABLE ApiLog (
+    api_log_id BIGINT GENERATED ALWAYS AS IDENTITY,
+    timestamp TIMESTAMP NOT NULL,
+    url TEXT NOT NULL,
+    location_id TEXT,
+    room_id TEXT,
+    status BIGINT,
+    latency_ms BIGINT NOT NULL,
+    error_kind TEXT,
+    error TEXT,
+    body BYTEA,
+    body_encoding TEXT,
+    PRIMARY KEY (api_log_id),
+    CHECK (body_encoding IN ('identity', 'gzip'))
+);
+
+CREATE INDEX IX_ApiLog_room_timestamp ON ApiLog (location_id, room_id, timestamp);
@@ -0,0 +1,3 @@
+-- Rows synthesized by the backfill job while the CSC api was not responding
+
+ALTER TABLE LaundryLog ADD COLUMN backfilled BOOLEAN NOT NULL DEFAULT FALSE;
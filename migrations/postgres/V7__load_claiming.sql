@@ -0,0 +1,48 @@
+-- Load claiming, users claim running cycles and group them into laundry loads
+-- Table named Users because USER is a reserved keyword
+-- Claimed cycles reference LaundryLog by (pep_id, cycle_start) without a foreign key, LaundryLog is a hypertable
+
+CREATE TABLE Users (
+    user_id BIGINT GENERATED ALWAYS AS IDENTITY,
+    name TEXT NOT NULL UNIQUE,
+    created_on TIMESTAMP NOT NULL,
+    PRIMARY KEY (user_id)
+);
+
+CREATE TABLE LaundryLoad (
+    load_id BIGINT GENERATED ALWAYS AS IDENTITY,
+    user_id BIGINT NOT NULL,
+    created_on TIMESTAMP NOT NULL,
+    PRIMARY KEY (load_id),
+    FOREIGN KEY (user_id) REFERENCES Users(user_id)
+);
+
+CREATE TABLE WasherLaundryLoads (
+    load_id BIGINT NOT NULL,
+    pep_id TEXT NOT NULL,
+    cycle_start TIMESTAMP NOT NULL,
+    PRIMARY KEY (pep_id, cycle_start),
+    FOREIGN KEY (load_id) REFERENCES LaundryLoad(load_id),
+    FOREIGN KEY (pep_id) REFERENCES PhysicalEndpoint(pep_id)
+);
+
+CREATE TABLE DryerLaundryLoad (
+    load_id BIGINT NOT NULL,
+    pep_id TEXT NOT NULL,
+    cycle_start TIMESTAMP NOT NULL,
+    PRIMARY KEY (pep_id, cycle_start),
+    FOREIGN KEY (load_id) REFERENCES LaundryLoad(load_id),
+    FOREIGN KEY (pep_id) REFERENCES PhysicalEndpoint(pep_id)
+);
+
+CREATE TABLE UserLogClaim (
+    user_id BIGINT NOT NULL,
+    pep_id TEXT NOT NULL,
+    timestamp TIMESTAMP NOT NULL,
+    claimed_on TIMESTAMP NOT NULL,
+    PRIMARY KEY (user_id, pep_id, timestamp),
+    FOREIGN KEY (user_id) REFERENCES Users(user_id),
+    FOREIGN KEY (pep_id) REFERENCES PhysicalEndpoint(pep_id)
+);
+
+CREATE INDEX IX_LaundryLoad_user_id ON LaundryLoad (user_id);
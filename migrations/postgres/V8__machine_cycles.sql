@@ -0,0 +1,20 @@
+-- Cycles derived from LaundryLog state transitions, one row per run of a machine
+-- cycle_start is the first running LaundryLog row, there is no foreign key since LaundryLog is a hypertable
+-- cycle_end and duration_seconds are null while the machine is running
+
+CREATE TABLE MachineCycles (
+    pep_id TEXT NOT NULL,
+    cycle_start TIMESTAMP NOT NULL,
+    cycle_end TIMESTAMP,
+    duration_seconds BIGINT,
+    machine_settings JSONB,
+    interrupted BOOLEAN NOT NULL DEFAULT FALSE,
+    estimated_end BOOLEAN NOT NULL DEFAULT FALSE,
+    door_opened TIMESTAMP,
+    door_open_seconds BIGINT,
+    updated_on TIMESTAMP NOT NULL,
+    PRIMARY KEY (pep_id, cycle_start),
+    FOREIGN KEY (pep_id) REFERENCES PhysicalEndpoint(pep_id)
+);
+
+CREATE INDEX IX_MachineCycles_cycle_start ON MachineCycles (cycle_start);
@@ -0,0 +1,17 @@
+-- Hourly rollup of LaundryLog per pep, kept after raw rows are deleted by retention
+-- Maintained by the retention task in the app
+-- availability is the share of samples where the machine was idle and not flagged unavailable
+
+CREATE TABLE LaundryLogHourly (
+    pep_id TEXT NOT NULL,
+    hour TEXT NOT NULL,
+    samples INTEGER NOT NULL,
+    press_start INTEGER NOT NULL,
+    running INTEGER NOT NULL,
+    idle INTEGER NOT NULL,
+    unknown INTEGER NOT NULL,
+    other INTEGER NOT NULL,
+    availability REAL NOT NULL,
+    PRIMARY KEY (pep_id, hour),
+    FOREIGN KEY (pep_id) REFERENCES PhysicalEndpoint(pep_id)
+);
@@ -0,0 +1,6 @@
+-- CSC fields kept so the CSC compatible api returns them as reported, NULL on older and backfilled rows
+-- time_remaining_reported is false when CSC sent no time remaining and 0 was stored
+-- Boolean columns converted to INTEGER (0 = false, 1 = true)
+
+ALTER TABLE LaundryLog ADD COLUMN available INTEGER;
+ALTER TABLE LaundryLog ADD COLUMN time_remaining_reported INTEGER;
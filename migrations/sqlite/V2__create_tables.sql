@@ -0,0 +1,2 @@
+-- Tables are created in V1, this version creates them for Postgres where V1 shipped empty
+-- Kept so migration versions match across databases
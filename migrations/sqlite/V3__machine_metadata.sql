@@ -0,0 +1,28 @@
+-- Full machine metadata from the CSC machines endpoint
+-- first_seen/last_seen track which machines are still in service
+-- controller_type is nullable, older controllers do not report a type
+-- SQLite cannot drop NOT NULL, Machines is rebuilt. The runner turns foreign_keys off around
+-- migrations and runs foreign_key_check afterwards
+-- JSON columns converted to TEXT, boolean columns converted to INTEGER
+
+CREATE TABLE Machines_new (
+    machine_id TEXT NOT NULL,
+    qr_code_id TEXT NOT NULL,
+    nfc_id TEXT NOT NULL,
+    controller_type TEXT,
+    type TEXT NOT NULL,
+    license_plate TEXT NOT NULL,
+    capabilities TEXT,
+    pricing TEXT,
+    in_service INTEGER,
+    free_play INTEGER,
+    first_seen TEXT,
+    last_seen TEXT,
+    PRIMARY KEY (machine_id),
+    CHECK (type IN ('washer', 'dryer')),
+    CHECK (length(license_plate) <= 7)
+);
+INSERT INTO Machines_new (machine_id, qr_code_id, nfc_id, controller_type, type, license_plate)
+SELECT machine_id, qr_code_id, nfc_id, controller_type, type, license_plate FROM Machines;
+DROP TABLE Machines;
+ALTER TABLE Machines_new RENAME TO Machines;
@@ -0,0 +1,48 @@
+-- Machine type and state accept values CSC adds later, e.g. 'combo' or 'outOfOrder'
+-- SQLite cannot drop a CHECK constraint, Machines and LaundryLog are rebuilt without the enum checks
+-- Rebuilds drop referenced tables, the runner turns foreign_keys off around migrations and runs
+-- foreign_key_check afterwards. The pragma is a no-op inside the migration's transaction
+-- Known MachineState enum values: pressStart, running, idle, unknown
+-- Known MachineType enum values: washer, dryer
+
+CREATE TABLE Machines_new (
+    machine_id TEXT NOT NULL,
+    qr_code_id TEXT NOT NULL,
+    nfc_id TEXT NOT NULL,
+    controller_type TEXT,
+    type TEXT NOT NULL,
+    license_plate TEXT NOT NULL,
+    capabilities TEXT,
+    pricing TEXT,
+    in_service INTEGER,
+    free_play INTEGER,
+    first_seen TEXT,
+    last_seen TEXT,
+    PRIMARY KEY (machine_id),
+    CHECK (length(type) > 0),
+    CHECK (length(license_plate) <= 7)
+);
+INSERT INTO Machines_new SELECT
+    machine_id, qr_code_id, nfc_id, controller_type, type, license_plate,
+    capabilities, pricing, in_service, free_play, first_seen, last_seen
+FROM Machines;
+DROP TABLE Machines;
+ALTER TABLE Machines_new RENAME TO Machines;
+
+CREATE TABLE LaundryLog_new (
+    pep_id TEXT NOT NULL,
+    timestamp TEXT NOT NULL,
+    time_remaining INTEGER NOT NULL,
+    not_available_reason TEXT,
+    door_closed INTEGER NOT NULL,
+    state TEXT NOT NULL,
+    machine_settings TEXT,
+    PRIMARY KEY (pep_id, timestamp),
+    CHECK (length(state) > 0),
+    FOREIGN KEY (pep_id) REFERENCES PhysicalEndpoint(pep_id)
+);
+INSERT INTO LaundryLog_new SELECT
+    pep_id, timestamp, time_remaining, not_available_reason, door_closed, state, machine_settings
+FROM LaundryLog;
+DROP TABLE LaundryLog;
+ALTER TABLE LaundryLog_new RENAME TO LaundryLog;
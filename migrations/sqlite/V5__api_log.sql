@@ -0,0 +1,20 @@
+-- Every request made to the CSC api, shares "timestamp" with the LaundryLog rows it produced
+-- Primary key "id" converted to INTEGER PRIMARY KEY AUTOINCREMENT
+-- Raw body stored as BLOB, gzip compressed when body_encoding is 'gzip'
+
+CREATE TABLE ApiLog (
+    api_log_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    timestamp TEXT NOT NULL,
+    url TEXT NOT NULL,
+    location_id TEXT,
+    room_id TEXT,
+    status INTEGER,
+    latency_ms INTEGER NOT NULL,
+    error_kind TEXT,
+    error TEXT,
+    body BLOB,
+    body_encoding TEXT,
+    CHECK (body_encoding IN ('identity', 'gzip'))
+);
+
+CREATE INDEX IX_ApiLog_room_timestamp ON ApiLog (location_id, room_id, timestamp);
@@ -0,0 +1,4 @@
+-- Rows synthesized by the backfill job while the CSC api was not responding
+-- Boolean column converted to INTEGER (0 = false, 1 = true)
+
+ALTER TABLE LaundryLog ADD COLUMN backfilled INTEGER NOT NULL DEFAULT 0;
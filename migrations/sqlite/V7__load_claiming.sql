@@ -0,0 +1,47 @@
+-- Load claiming, users claim running cycles and group them into laundry loads
+-- Table named Users because USER is a reserved keyword in other backends
+-- Primary keys converted to INTEGER PRIMARY KEY AUTOINCREMENT
+-- Claimed cycles reference LaundryLog by (pep_id, cycle_start) without a foreign key
+
+CREATE TABLE Users (
+    user_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL UNIQUE,
+    created_on TEXT NOT NULL
+);
+
+CREATE TABLE LaundryLoad (
+    load_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL,
+    created_on TEXT NOT NULL,
+    FOREIGN KEY (user_id) REFERENCES Users(user_id)
+);
+
+CREATE TABLE WasherLaundryLoads (
+    load_id INTEGER NOT NULL,
+    pep_id TEXT NOT NULL,
+    cycle_start TEXT NOT NULL,
+    PRIMARY KEY (pep_id, cycle_start),
+    FOREIGN KEY (load_id) REFERENCES LaundryLoad(load_id),
+    FOREIGN KEY (pep_id) REFERENCES PhysicalEndpoint(pep_id)
+);
+
+CREATE TABLE DryerLaundryLoad (
+    load_id INTEGER NOT NULL,
+    pep_id TEXT NOT NULL,
+    cycle_start TEXT NOT NULL,
+    PRIMARY KEY (pep_id, cycle_start),
+    FOREIGN KEY (load_id) REFERENCES LaundryLoad(load_id),
+    FOREIGN KEY (pep_id) REFERENCES PhysicalEndpoint(pep_id)
+);
+
+CREATE TABLE UserLogClaim (
+    user_id INTEGER NOT NULL,
+    pep_id TEXT NOT NULL,
+    timestamp TEXT NOT NULL,
+    claimed_on TEXT NOT NULL,
+    PRIMARY KEY (user_id, pep_id, timestamp),
+    FOREIGN KEY (user_id) REFERENCES Users(user_id),
+    FOREIGN KEY (pep_id) REFERENCES PhysicalEndpoint(pep_id)
+);
+
+CREATE INDEX IX_LaundryLoad_user_id ON LaundryLoad (user_id);
@@ -0,0 +1,20 @@
+-- Cycles derived from LaundryLog state transitions, one row per run of a machine
+-- cycle_start is the first running LaundryLog row
+-- cycle_end and duration_seconds are null while the machine is running
+
+CREATE TABLE MachineCycles (
+    pep_id TEXT NOT NULL,
+    cycle_start TEXT NOT NULL,
+    cycle_end TEXT,
+    duration_seconds INTEGER,
+    machine_settings TEXT,
+    interrupted INTEGER NOT NULL DEFAULT 0,
+    estimated_end INTEGER NOT NULL DEFAULT 0,
+    door_opened TEXT,
+    door_open_seconds INTEGER,
+    updated_on TEXT NOT NULL,
+    PRIMARY KEY (pep_id, cycle_start),
+    FOREIGN KEY (pep_id) REFERENCES PhysicalEndpoint(pep_id)
+);
+
+CREATE INDEX IX_MachineCycles_cycle_start ON MachineCycles (cycle_start);
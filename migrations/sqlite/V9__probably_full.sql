@@ -0,0 +1,5 @@
+-- Laundry probably left in the machine after a cycle, maintained by cycle detection
+
+ALTER TABLE MachineCycles ADD COLUMN probably_full INTEGER NOT NULL DEFAULT 0;
+
+CREATE INDEX IX_MachineCycles_probably_full ON MachineCycles (pep_id) WHERE probably_full = 1;
@@ -0,0 +1,589 @@
+use crate::db::{DbConfig, DbType, connect, embedded};
+use crate::logic::db::{value_as_bool, value_as_int, value_as_json, value_as_timestamp};
+use crate::utils::prelude::*;
+use clap::Args;
+use color_eyre::eyre::{OptionExt, bail};
+use sql_middleware::{CustomDbRow, MiddlewarePoolConnection, RowValues};
+
+#[derive(Debug, Args)]
+pub(crate) struct CopyArgs {
+    /// Rows read per query, memory use is bound by this
+    #[arg(long, default_value_t = 10_000)]
+    batch: i64,
+}
+
+/// How a column is normalized between backends, e.g. sqlite stores booleans as integers
+#[derive(Clone, Copy)]
+enum Kind {
+    Text,
+    Int,
+    Float,
+    Bool,
+    Timestamp,
+    Json,
+    Blob,
+}
+
+/// Where the rows of a query start
+enum Start {
+    All,
+    /// At the last copied first order column, inclusive
+    Resume,
+    /// After the last row read
+    After,
+}
+
+struct Table {
+    name: &'static str,
+    columns: &'static [(&'static str, Kind)],
+    /// Rows are read in this order. A timestamp or id first column is where a copy resumes from
+    order: &'static [&'static str],
+    /// Matched on by mssql, the other backends ignore any conflict
+    key: &'static [&'static str],
+    /// Identity column whose values are copied as is
+    identity: Option<&'static str>,
+    /// A Timescale continuous aggregate on postgres, computed from `laundrylog` instead of written
+    aggregate: bool,
+}
+
+impl Table {
+    fn index(&self, column: &str) -> Result<usize> {
+        self.columns
+            .iter()
+            .position(|(name, _)| *name == column)
+            .ok_or_eyre(format!("Unknown column {}.{}", self.name, column))
+    }
+
+    fn kind(&self, column: &str) -> Result<Kind> {
+        Ok(self.columns[self.index(column)?].1)
+    }
+
+    /// Text keys compare differently between backends, those tables are small and copied whole
+    fn resumable(&self) -> Result<bool> {
+        Ok(matches!(
+            self.kind(self.order[0])?,
+            Kind::Timestamp | Kind::Int
+        ))
+    }
+
+    fn column_list(&self) -> String {
+        self.columns
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// Copies every table from `source` into `target` in foreign key order, then compares row counts.
+/// Rows already in the target are skipped, so an interrupted copy is resumed by running it again.
+#[instrument(skip_all, fields(source = ?source.r#type, target = ?target.r#type))]
+pub(crate) async fn copy(source: DbConfig, target: DbConfig, args: CopyArgs) -> Result<()> {
+    if source == target {
+        bail!("Source and target are the same database");
+    }
+
+    let source_pool = connect(&source).await?;
+    let target_pool = connect(&target).await?;
+
+    info!("Applying migrations to the target");
+    let report = embedded::run_async(target.clone()).await?;
+    info!(
+        "Migrations complete: Applied {} migrations",
+        report.applied_migrations().len()
+    );
+
+    let mut from = source_pool.get_connection().await?;
+    let mut to = target_pool.get_connection().await?;
+    // Rolled up hours outlive their raw rows, the aggregate could not compute them again
+    let computed = |table: &Table| table.aggregate && target.r#type == DbType::Postgres;
+    for table in TABLES.iter().filter(|table| computed(table)) {
+        if count(table, &mut from).await? > 0 {
+            bail!(
+                "{} has rows in the source, a Postgres target computes it from laundrylog and \
+                can not keep them",
+                table.name
+            );
+        }
+        info!("{}: computed from laundrylog, not copied", table.name);
+    }
+
+    let tables: Vec<&Table> = TABLES.iter().filter(|table| !computed(table)).collect();
+    for table in &tables {
+        copy_table(
+            table,
+            source.r#type,
+            &mut from,
+            target.r#type,
+            &mut to,
+            args.batch,
+        )
+        .await?;
+    }
+
+    let mut mismatched = Vec::new();
+    for table in &tables {
+        let (expected, actual) = (count(table, &mut from).await?, count(table, &mut to).await?);
+        match expected == actual {
+            true => info!("{}: {} rows", table.name, actual),
+            false => {
+                warn!(
+                    "{}: {} rows in the source, {} in the target",
+                    table.name, expected, actual
+                );
+                mismatched.push(table.name);
+            }
+        }
+    }
+    if !mismatched.is_empty() {
+        bail!("Row counts differ for {}", mismatched.join(", "));
+    }
+    info!("Copy complete");
+    Ok(())
+}
+
+#[instrument(skip_all, fields(table = table.name))]
+async fn copy_table(
+    table: &Table,
+    source_type: DbType,
+    from: &mut MiddlewarePoolConnection,
+    target_type: DbType,
+    to: &mut MiddlewarePoolConnection,
+    batch: i64,
+) -> Result<()> {
+    // The last batch may have been cut short, rows at the last copied key are read again
+    let resume = match table.resumable()? {
+        true => last_copied(table, to).await?,
+        false => None,
+    };
+    if let Some(resume) = &resume {
+        info!("Resuming from {:?}", resume);
+    }
+
+    let first = match resume {
+        Some(_) => select_query(source_type, table, Start::Resume),
+        None => select_query(source_type, table, Start::All),
+    };
+    let next = select_query(source_type, table, Start::After);
+    let mut cursor: Option<Vec<RowValues>> = None;
+    let mut total = 0;
+    loop {
+        let (query, mut params) = match &cursor {
+            Some(cursor) => (&next, cursor.clone()),
+            None => (&first, resume.iter().cloned().collect()),
+        };
+        params.push(RowValues::Int(batch));
+        let rows = from.query(query).params(&params).select().await?.results;
+        let Some(last) = rows.last() else {
+            break;
+        };
+        cursor = Some(
+            table
+                .order
+                .iter()
+                .map(|name| column(table, name, last))
+                .collect::<Result<_>>()?,
+        );
+
+        // Placeholders per statement are limited, mssql allows the fewest
+        let per_statement = (max_params(target_type) / table.columns.len()).max(1);
+        for chunk in rows.chunks(per_statement) {
+            let mut values = Vec::with_capacity(chunk.len() * table.columns.len());
+            for row in chunk {
+                for (name, _) in table.columns {
+                    values.push(column(table, name, row)?);
+                }
+            }
+            to.query(&insert_query(target_type, table, chunk.len()))
+                .params(&values)
+                .dml()
+                .await?;
+        }
+
+        total += rows.len();
+        info!("Copied {} rows", total);
+        if (rows.len() as i64) < batch {
+            break;
+        }
+    }
+
+    if target_type == DbType::Postgres
+        && let Some(identity) = table.identity
+    {
+        // Explicit values do not advance the sequence, mssql and sqlite keep up on their own
+        to.query(&format!(
+            "SELECT setval(pg_get_serial_sequence('{0}', '{identity}'), MAX({identity})) FROM {0}",
+            table.name
+        ))
+        .select()
+        .await?;
+    }
+    Ok(())
+}
+
+/// Greatest first order column in the target
+async fn last_copied(
+    table: &Table,
+    to: &mut MiddlewarePoolConnection,
+) -> Result<Option<RowValues>> {
+    let column = table.order[0];
+    let rows = to
+        .query(&format!("SELECT MAX({column}) FROM {}", table.name))
+        .select()
+        .await?
+        .results;
+    match rows.first().and_then(|row| row.get_by_index(0)) {
+        None | Some(RowValues::Null) => Ok(None),
+        Some(value) => Ok(Some(convert(table, column, value)?)),
+    }
+}
+
+async fn count(table: &Table, conn: &mut MiddlewarePoolConnection) -> Result<i64> {
+    let rows = conn
+        .query(&format!("SELECT COUNT(*) FROM {}", table.name))
+        .select()
+        .await?
+        .results;
+    rows.first()
+        .and_then(|row| row.get_by_index(0))
+        .and_then(value_as_int)
+        .ok_or_eyre(format!("Failed to count {}", table.name))
+}
+
+/// Reads a column of a row read with `Table::column_list`
+fn column(table: &Table, column: &str, row: &CustomDbRow) -> Result<RowValues> {
+    let value = row
+        .get_by_index(table.index(column)?)
+        .ok_or_eyre(format!("Missing column {}.{}", table.name, column))?;
+    convert(table, column, value)
+}
+
+/// Converts a value to the kind every backend accepts as a parameter
+fn convert(table: &Table, column: &str, value: &RowValues) -> Result<RowValues> {
+    if matches!(value, RowValues::Null) {
+        return Ok(RowValues::Null);
+    }
+    let converted = match table.kind(column)? {
+        Kind::Text => value.as_text().map(|v| RowValues::Text(v.to_string())),
+        Kind::Int => value_as_int(value).map(RowValues::Int),
+        Kind::Float => match value {
+            RowValues::Float(v) => Some(RowValues::Float(*v)),
+            RowValues::Int(v) => Some(RowValues::Float(*v as f64)),
+            // Postgres averages are numeric
+            _ => value
+                .as_text()
+                .and_then(|v| v.parse().ok())
+                .map(RowValues::Float),
+        },
+        Kind::Bool => value_as_bool(value).map(RowValues::Bool),
+        Kind::Timestamp => value_as_timestamp(value).map(RowValues::Timestamp),
+        Kind::Json => value_as_json(value).map(RowValues::JSON),
+        Kind::Blob => match value {
+            RowValues::Blob(v) => Some(RowValues::Blob(v.clone())),
+            _ => None,
+        },
+    };
+    converted.ok_or_eyre(format!(
+        "Invalid value in {}.{}: {:?}",
+        table.name, column, value
+    ))
+}
+
+fn max_params(db_type: DbType) -> usize {
+    match db_type {
+        DbType::Mssql => 2000,
+        _ => 30000,
+    }
+}
+
+/// Rows after the cursor in `order`, lexicographically. The limit is always the last parameter.
+fn select_query(db_type: DbType, table: &Table, start: Start) -> String {
+    let columns = table.column_list();
+    let order = table.order.join(", ");
+    let (condition, limit) = match start {
+        Start::All => (String::new(), 1),
+        Start::Resume => (format!("WHERE {} >= $1 ", table.order[0]), 2),
+        Start::After => {
+            let after = (0..table.order.len())
+                .map(|i| {
+                    let mut terms: Vec<String> = table.order[..i]
+                        .iter()
+                        .enumerate()
+                        .map(|(j, column)| format!("{column} = ${}", j + 1))
+                        .collect();
+                    terms.push(format!("{} > ${}", table.order[i], i + 1));
+                    format!("({})", terms.join(" AND "))
+                })
+                .collect::<Vec<_>>()
+                .join(" OR ");
+            (format!("WHERE {after} "), table.order.len() + 1)
+        }
+    };
+    let name = table.name;
+    match db_type {
+        DbType::Mssql => {
+            format!("SELECT TOP (${limit}) {columns} FROM {name} {condition}ORDER BY {order}")
+        }
+        _ => format!("SELECT {columns} FROM {name} {condition}ORDER BY {order} LIMIT ${limit}"),
+    }
+}
+
+/// Inserts `rows` rows, skipping those already in the target
+fn insert_query(db_type: DbType, table: &Table, rows: usize) -> String {
+    let name = table.name;
+    let columns = table.column_list();
+    let width = table.columns.len();
+    let values = (0..rows)
+        .map(|row| {
+            let placeholders = (1..=width)
+                .map(|i| format!("${}", row * width + i))
+                .collect::<Vec<_>>()
+                .join(", ");
+            format!("({placeholders})")
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    match db_type {
+        DbType::Mssql => {
+            let on = table
+                .key
+                .iter()
+                .map(|v| format!("target.{v} = source.{v}"))
+                .collect::<Vec<_>>()
+                .join(" AND ");
+            let source = table
+                .columns
+                .iter()
+                .map(|(v, _)| format!("source.{v}"))
+                .collect::<Vec<_>>()
+                .join(", ");
+            let merge = format!(
+                "MERGE {name} AS target USING (VALUES {values}) AS source ({columns}) ON {on} \
+                WHEN NOT MATCHED THEN INSERT ({columns}) VALUES ({source});"
+            );
+            match table.identity {
+                Some(_) => {
+                    format!(
+                        "SET IDENTITY_INSERT {name} ON; {merge} SET IDENTITY_INSERT {name} OFF;"
+                    )
+                }
+                None => merge,
+            }
+        }
+        _ => {
+            // Sqlite accepts explicit values for AUTOINCREMENT columns
+            let overriding = match (db_type, table.identity) {
+                (DbType::Postgres, Some(_)) => "OVERRIDING SYSTEM VALUE ",
+                _ => "",
+            };
+            format!(
+                "INSERT INTO {name} ({columns}) {overriding}VALUES {values} ON CONFLICT DO NOTHING"
+            )
+        }
+    }
+}
+
+/// Foreign key order
+const TABLES: &[Table] = &[
+    Table {
+        name: "locations",
+        columns: &[
+            ("location_id", Kind::Text),
+            ("description", Kind::Text),
+            ("label", Kind::Text),
+            ("timezone", Kind::Text),
+        ],
+        order: &["location_id"],
+        key: &["location_id"],
+        identity: None,
+        aggregate: false,
+    },
+    Table {
+        name: "rooms",
+        columns: &[
+            ("room_id", Kind::Text),
+            ("description", Kind::Text),
+            ("label", Kind::Text),
+        ],
+        order: &["room_id"],
+        key: &["room_id"],
+        identity: None,
+        aggregate: false,
+    },
+    Table {
+        name: "machines",
+        columns: &[
+            ("machine_id", Kind::Text),
+            ("qr_code_id", Kind::Text),
+            ("nfc_id", Kind::Text),
+            ("controller_type", Kind::Text),
+            ("type", Kind::Text),
+            ("license_plate", Kind::Text),
+            ("capabilities", Kind::Json),
+            ("pricing", Kind::Json),
+            ("in_service", Kind::Bool),
+            ("free_play", Kind::Bool),
+            ("first_seen", Kind::Timestamp),
+            ("last_seen", Kind::Timestamp),
+        ],
+        order: &["machine_id"],
+        key: &["machine_id"],
+        identity: None,
+        aggregate: false,
+    },
+    Table {
+        name: "physicalendpoint",
+        columns: &[
+            ("pep_id", Kind::Text),
+            ("added_on", Kind::Timestamp),
+            ("room_id", Kind::Text),
+            ("location_id", Kind::Text),
+            ("machine_id", Kind::Text),
+            ("sticker_number", Kind::Int),
+        ],
+        order: &["pep_id"],
+        key: &["pep_id"],
+        identity: None,
+        aggregate: false,
+    },
+    Table {
+        name: "laundrylog",
+        columns: &[
+            ("pep_id", Kind::Text),
+            ("\"timestamp\"", Kind::Timestamp),
+            ("time_remaining", Kind::Int),
+            ("not_available_reason", Kind::Text),
+            ("door_closed", Kind::Bool),
+            ("state", Kind::Text),
+            ("machine_settings", Kind::Json),
+            ("backfilled", Kind::Bool),
+            ("available", Kind::Bool),
+            ("time_remaining_reported", Kind::Bool),
+        ],
+        order: &["\"timestamp\"", "pep_id"],
+        key: &["pep_id", "\"timestamp\""],
+        identity: None,
+        aggregate: false,
+    },
+    Table {
+        name: "laundryloghourly",
+        columns: &[
+            ("pep_id", Kind::Text),
+            ("hour", Kind::Timestamp),
+            ("samples", Kind::Int),
+            ("press_start", Kind::Int),
+            ("running", Kind::Int),
+            ("idle", Kind::Int),
+            ("unknown", Kind::Int),
+            ("other", Kind::Int),
+            ("availability", Kind::Float),
+        ],
+        order: &["hour", "pep_id"],
+        key: &["pep_id", "hour"],
+        identity: None,
+        aggregate: true,
+    },
+    Table {
+        name: "apilog",
+        columns: &[
+            ("api_log_id", Kind::Int),
+            ("\"timestamp\"", Kind::Timestamp),
+            ("url", Kind::Text),
+            ("location_id", Kind::Text),
+            ("room_id", Kind::Text),
+            ("status", Kind::Int),
+            ("latency_ms", Kind::Int),
+            ("error_kind", Kind::Text),
+            ("error", Kind::Text),
+            ("body", Kind::Blob),
+            ("body_encoding", Kind::Text),
+        ],
+        order: &["api_log_id"],
+        key: &["api_log_id"],
+        identity: Some("api_log_id"),
+        aggregate: false,
+    },
+    Table {
+        name: "machinecycles",
+        columns: &[
+            ("pep_id", Kind::Text),
+            ("cycle_start", Kind::Timestamp),
+            ("cycle_end", Kind::Timestamp),
+            ("duration_seconds", Kind::Int),
+            ("machine_settings", Kind::Json),
+            ("interrupted", Kind::Bool),
+            ("estimated_end", Kind::Bool),
+            ("door_opened", Kind::Timestamp),
+            ("door_open_seconds", Kind::Int),
+            ("updated_on", Kind::Timestamp),
+            ("probably_full", Kind::Bool),
+        ],
+        order: &["cycle_start", "pep_id"],
+        key: &["pep_id", "cycle_start"],
+        identity: None,
+        aggregate: false,
+    },
+    Table {
+        name: "users",
+        columns: &[
+            ("user_id", Kind::Int),
+            ("name", Kind::Text),
+            ("created_on", Kind::Timestamp),
+            ("token", Kind::Text),
+        ],
+        order: &["user_id"],
+        key: &["user_id"],
+        identity: Some("user_id"),
+        aggregate: false,
+    },
+    Table {
+        name: "laundryload",
+        columns: &[
+            ("load_id", Kind::Int),
+            ("user_id", Kind::Int),
+            ("created_on", Kind::Timestamp),
+        ],
+        order: &["load_id"],
+        key: &["load_id"],
+        identity: Some("load_id"),
+        aggregate: false,
+    },
+    Table {
+        name: "washerlaundryloads",
+        columns: &[
+            ("load_id", Kind::Int),
+            ("pep_id", Kind::Text),
+            ("cycle_start", Kind::Timestamp),
+        ],
+        order: &["cycle_start", "pep_id"],
+        key: &["pep_id", "cycle_start"],
+        identity: None,
+        aggregate: false,
+    },
+    Table {
+        name: "dryerlaundryload",
+        columns: &[
+            ("load_id", Kind::Int),
+            ("pep_id", Kind::Text),
+            ("cycle_start", Kind::Timestamp),
+        ],
+        order: &["cycle_start", "pep_id"],
+        key: &["pep_id", "cycle_start"],
+        identity: None,
+        aggregate: false,
+    },
+    Table {
+        name: "userlogclaim",
+        columns: &[
+            ("user_id", Kind::Int),
+            ("pep_id", Kind::Text),
+            ("\"timestamp\"", Kind::Timestamp),
+            ("claimed_on", Kind::Timestamp),
+        ],
+        order: &["\"timestamp\"", "user_id", "pep_id"],
+        key: &["user_id", "pep_id", "\"timestamp\""],
+        identity: None,
+        aggregate: false,
+    },
+];
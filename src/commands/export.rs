@@ -0,0 +1,266 @@
+use crate::commands::{BatchReader, RangeArgs, Source};
+use crate::db::{DbConfig, connect};
+use crate::logic::db::{value_as_bool, value_as_int, value_as_json, value_as_timestamp};
+use crate::utils::prelude::*;
+use chrono::NaiveDateTime;
+use clap::{Args, ValueEnum};
+use color_eyre::eyre::OptionExt;
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use serde::Serialize;
+use sql_middleware::CustomDbRow;
+use std::fs::File;
+use std::io::{BufWriter, Write, stdout};
+use std::path::PathBuf;
+
+/// Same format as the http api
+pub(super) const TIMESTAMP_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.f";
+
+/// Column order of every export, the same for every backend
+const COLUMNS: [&str; 15] = [
+    "timestamp",
+    "pep_id",
+    "location_id",
+    "location_label",
+    "room_id",
+    "room_label",
+    "sticker_number",
+    "machine_id",
+    "machine_type",
+    "state",
+    "time_remaining",
+    "door_closed",
+    "not_available_reason",
+    "backfilled",
+    "machine_settings",
+];
+
+#[derive(Debug, Args)]
+pub(crate) struct ExportArgs {
+    #[command(flatten)]
+    range: RangeArgs,
+    #[arg(long, value_enum, default_value_t = ExportFormat::Csv)]
+    format: ExportFormat,
+    /// File to write, stdout when missing or `-`
+    #[arg(long, short)]
+    output: Option<PathBuf>,
+    /// Gzip the output. Implied by an output ending in `.gz`
+    #[arg(long)]
+    gzip: bool,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub(crate) enum ExportFormat {
+    Csv,
+    /// One JSON object per line
+    Jsonl,
+}
+
+/// A `LaundryLog` row joined with where the machine is
+#[derive(Serialize)]
+pub(super) struct ExportRow {
+    #[serde(serialize_with = "serialize_timestamp")]
+    pub(super) timestamp: NaiveDateTime,
+    pub(super) pep_id: String,
+    pub(super) location_id: String,
+    pub(super) location_label: String,
+    pub(super) room_id: String,
+    pub(super) room_label: String,
+    pub(super) sticker_number: i64,
+    pub(super) machine_id: String,
+    pub(super) machine_type: String,
+    pub(super) state: String,
+    pub(super) time_remaining: i64,
+    pub(super) door_closed: bool,
+    pub(super) not_available_reason: Option<String>,
+    pub(super) backfilled: bool,
+    pub(super) machine_settings: Option<serde_json::Value>,
+}
+
+fn serialize_timestamp<S: serde::Serializer>(
+    value: &NaiveDateTime,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(&value.format(TIMESTAMP_FORMAT))
+}
+
+impl ExportRow {
+    pub(super) fn from_row(row: &CustomDbRow) -> Result<Self> {
+        let column = |index: usize| {
+            row.get_by_index(index)
+                .ok_or_eyre(format!("Failed to get row by index {index}"))
+        };
+        let text = |index: usize| -> Result<String> {
+            Ok(column(index)?
+                .as_text()
+                .ok_or_eyre(format!("Invalid text at index {index}"))?
+                .to_string())
+        };
+        let int = |index: usize| -> Result<i64> {
+            value_as_int(column(index)?).ok_or_eyre(format!("Invalid int at index {index}"))
+        };
+        let boolean = |index: usize| -> Result<bool> {
+            value_as_bool(column(index)?).ok_or_eyre(format!("Invalid bool at index {index}"))
+        };
+
+        Ok(Self {
+            timestamp: value_as_timestamp(column(0)?).ok_or_eyre("Invalid timestamp")?,
+            pep_id: text(1)?,
+            location_id: text(2)?,
+            location_label: text(3)?,
+            room_id: text(4)?,
+            room_label: text(5)?,
+            sticker_number: int(6)?,
+            machine_id: text(7)?,
+            machine_type: text(8)?,
+            state: text(9)?,
+            time_remaining: int(10)?,
+            door_closed: boolean(11)?,
+            not_available_reason: column(12)?.as_text().map(str::to_string),
+            backfilled: boolean(13)?,
+            machine_settings: value_as_json(column(14)?),
+        })
+    }
+
+    /// Settings are written as JSON text, csv has no nesting
+    fn record(&self) -> [String; 15] {
+        [
+            self.timestamp.format(TIMESTAMP_FORMAT).to_string(),
+            self.pep_id.clone(),
+            self.location_id.clone(),
+            self.location_label.clone(),
+            self.room_id.clone(),
+            self.room_label.clone(),
+            self.sticker_number.to_string(),
+            self.machine_id.clone(),
+            self.machine_type.clone(),
+            self.state.clone(),
+            self.time_remaining.to_string(),
+            self.door_closed.to_string(),
+            self.not_available_reason.clone().unwrap_or_default(),
+            self.backfilled.to_string(),
+            self.machine_settings
+                .as_ref()
+                .map(|v| v.to_string())
+                .unwrap_or_default(),
+        ]
+    }
+}
+
+/// Destination of the export, finished explicitly so gzip errors are not lost on drop
+enum Output {
+    Plain(BufWriter<Box<dyn Write>>),
+    Gzip(GzEncoder<BufWriter<Box<dyn Write>>>),
+}
+
+impl Output {
+    fn open(args: &ExportArgs) -> Result<Self> {
+        let (inner, gzip): (Box<dyn Write>, bool) = match &args.output {
+            Some(path) if path.as_os_str() != "-" => (
+                Box::new(File::create(path)?),
+                args.gzip || path.extension().is_some_and(|v| v == "gz"),
+            ),
+            _ => (Box::new(stdout()), args.gzip),
+        };
+        let inner = BufWriter::new(inner);
+        Ok(match gzip {
+            true => Output::Gzip(GzEncoder::new(inner, Compression::default())),
+            false => Output::Plain(inner),
+        })
+    }
+
+    fn finish(self) -> Result<()> {
+        match self {
+            Output::Plain(mut v) => v.flush()?,
+            Output::Gzip(v) => v.finish()?.flush()?,
+        }
+        Ok(())
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Output::Plain(v) => v.write(buf),
+            Output::Gzip(v) => v.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Output::Plain(v) => v.flush(),
+            Output::Gzip(v) => v.flush(),
+        }
+    }
+}
+
+enum RowWriter {
+    Csv(csv::Writer<Output>),
+    Jsonl(Output),
+}
+
+impl RowWriter {
+    fn new(format: ExportFormat, output: Output) -> Result<Self> {
+        Ok(match format {
+            ExportFormat::Csv => {
+                let mut writer = csv::Writer::from_writer(output);
+                writer.write_record(COLUMNS)?;
+                RowWriter::Csv(writer)
+            }
+            ExportFormat::Jsonl => RowWriter::Jsonl(output),
+        })
+    }
+
+    fn write(&mut self, row: &ExportRow) -> Result<()> {
+        match self {
+            RowWriter::Csv(writer) => writer.write_record(row.record())?,
+            RowWriter::Jsonl(output) => {
+                serde_json::to_writer(&mut *output, row)?;
+                output.write_all(b"\n")?;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<()> {
+        match self {
+            RowWriter::Csv(writer) => writer.into_inner().map_err(|e| e.into_error())?,
+            RowWriter::Jsonl(output) => output,
+        }
+        .finish()
+    }
+}
+
+/// Streams `LaundryLog` rows in the range to a file, a batch at a time
+#[instrument(skip_all)]
+pub(crate) async fn export(db_config: DbConfig, args: ExportArgs) -> Result<()> {
+    let db_type = db_config.r#type;
+    let pool = connect(&db_config).await?;
+    let mut conn = pool.get_connection().await?;
+
+    let mut writer = RowWriter::new(args.format, Output::open(&args)?)?;
+    let mut reader = BatchReader::new(db_type, &LAUNDRY_LOG, &args.range);
+    let mut total = 0;
+    while let Some(rows) = reader.next(&mut conn).await? {
+        for row in rows.iter() {
+            writer.write(&ExportRow::from_row(row)?)?;
+        }
+        total += rows.len();
+        info!("Exported {} rows, up to {}", total, reader.position());
+    }
+
+    writer.finish()?;
+    info!("Export complete: {} rows", total);
+    Ok(())
+}
+
+/// Columns are read by index in `ExportRow::from_row`
+pub(super) const LAUNDRY_LOG: Source = Source {
+    columns: "l.\"timestamp\", l.pep_id, p.location_id, lo.label, p.room_id, r.label, p.sticker_number, \
+    p.machine_id, m.type, l.state, l.time_remaining, l.door_closed, l.not_available_reason, l.backfilled, l.machine_settings",
+    from: "laundrylog l JOIN physicalendpoint p ON l.pep_id = p.pep_id \
+    JOIN machines m ON p.machine_id = m.machine_id JOIN locations lo ON p.location_id = lo.location_id \
+    JOIN rooms r ON p.room_id = r.room_id",
+    key: "l.\"timestamp\"",
+    pep_id: "l.pep_id",
+};
@@ -0,0 +1,138 @@
+pub(crate) mod copy;
+pub(crate) mod export;
+#[cfg(feature = "parquet")]
+pub(crate) mod parquet;
+
+use crate::db::DbType;
+use crate::logic::db::value_as_timestamp;
+use crate::utils::prelude::*;
+use chrono::{NaiveDateTime, Utc};
+use clap::Args;
+use color_eyre::eyre::OptionExt;
+use sql_middleware::{CustomDbRow, MiddlewarePoolConnection, RowValues};
+
+/// Range and filters shared by every export
+#[derive(Debug, Args)]
+pub(crate) struct RangeArgs {
+    /// Start of the range, inclusive. e.g. 2026-01-01T00:00:00
+    #[arg(long)]
+    from: NaiveDateTime,
+    /// End of the range, exclusive. Defaults to now
+    #[arg(long)]
+    to: Option<NaiveDateTime>,
+    #[arg(long)]
+    location_id: Option<String>,
+    #[arg(long)]
+    room_id: Option<String>,
+    /// Rows read per query, memory use is bound by this
+    #[arg(long, default_value_t = 10_000)]
+    batch: i64,
+}
+
+/// A table joined with `physicalendpoint p`, read in (`key`, `pep_id`) order.
+/// The first two columns must be `key` and `pep_id`.
+pub(crate) struct Source {
+    pub(crate) columns: &'static str,
+    pub(crate) from: &'static str,
+    /// Timestamp column the range applies to
+    pub(crate) key: &'static str,
+    pub(crate) pep_id: &'static str,
+}
+
+/// Reads a source a batch at a time with keyset pagination, so memory use does not grow with the
+/// range
+pub(crate) struct BatchReader<'a> {
+    query: String,
+    range: &'a RangeArgs,
+    to: NaiveDateTime,
+    /// Last key and pep_id read, every pep_id sorts after the empty string
+    cursor: (NaiveDateTime, String),
+    done: bool,
+}
+
+impl<'a> BatchReader<'a> {
+    pub(crate) fn new(db_type: DbType, source: &Source, range: &'a RangeArgs) -> Self {
+        Self {
+            query: batch_query(db_type, source, range),
+            range,
+            to: range.to.unwrap_or_else(|| Utc::now().naive_utc()),
+            cursor: (range.from, String::new()),
+            done: false,
+        }
+    }
+
+    /// Next batch, `None` once the range is exhausted
+    pub(crate) async fn next(
+        &mut self,
+        conn: &mut MiddlewarePoolConnection,
+    ) -> Result<Option<Vec<CustomDbRow>>> {
+        if self.done {
+            return Ok(None);
+        }
+
+        let mut params = vec![
+            RowValues::Timestamp(self.cursor.0),
+            RowValues::Text(self.cursor.1.clone()),
+            RowValues::Timestamp(self.to),
+        ];
+        params.extend(self.range.location_id.iter().cloned().map(RowValues::Text));
+        params.extend(self.range.room_id.iter().cloned().map(RowValues::Text));
+        params.push(RowValues::Int(self.range.batch));
+
+        let rows = conn
+            .query(&self.query)
+            .params(&params)
+            .select()
+            .await?
+            .results;
+        self.done = (rows.len() as i64) < self.range.batch;
+        let Some(last) = rows.last() else {
+            return Ok(None);
+        };
+        self.cursor = (
+            last.get_by_index(0)
+                .and_then(value_as_timestamp)
+                .ok_or_eyre("Invalid key column")?,
+            last.get_by_index(1)
+                .and_then(|v| v.as_text())
+                .ok_or_eyre("Invalid pep_id column")?
+                .to_string(),
+        );
+        Ok(Some(rows))
+    }
+
+    /// Key of the last row read
+    pub(crate) fn position(&self) -> NaiveDateTime {
+        self.cursor.0
+    }
+}
+
+/// Filters are only added when given, the limit is always the last parameter
+fn batch_query(db_type: DbType, source: &Source, range: &RangeArgs) -> String {
+    let Source {
+        columns,
+        from,
+        key,
+        pep_id,
+    } = source;
+
+    let mut filters = String::new();
+    let mut index = 4;
+    if range.location_id.is_some() {
+        filters.push_str(&format!(" AND p.location_id = ${index}"));
+        index += 1;
+    }
+    if range.room_id.is_some() {
+        filters.push_str(&format!(" AND p.room_id = ${index}"));
+        index += 1;
+    }
+
+    let condition = format!(
+        "WHERE ({key} > $1 OR ({key} = $1 AND {pep_id} > $2)) AND {key} < $3{filters} \
+        ORDER BY {key}, {pep_id}"
+    );
+    match db_type {
+        DbType::Mssql => format!("SELECT TOP (${index}) {columns} FROM {from} {condition}"),
+        _ => format!("SELECT {columns} FROM {from} {condition} LIMIT ${index}"),
+    }
+}
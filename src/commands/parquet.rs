@@ -0,0 +1,398 @@
+use crate::commands::export::{ExportRow, LAUNDRY_LOG};
+use crate::commands::{BatchReader, RangeArgs, Source};
+use crate::db::{DbConfig, connect};
+use crate::logic::db::{value_as_bool, value_as_int, value_as_json, value_as_timestamp};
+use crate::utils::prelude::*;
+use arrow_array::types::Int32Type;
+use arrow_array::{
+    ArrayRef, BooleanArray, DictionaryArray, Int32Array, Int64Array, RecordBatch, StringArray,
+    TimestampMicrosecondArray,
+};
+use arrow_schema::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime};
+use clap::{Args, ValueEnum};
+use color_eyre::eyre::{OptionExt, bail};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use sql_middleware::{CustomDbRow, MiddlewarePoolConnection};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, create_dir_all};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+#[derive(Debug, Args)]
+pub(crate) struct ParquetArgs {
+    #[command(flatten)]
+    range: RangeArgs,
+    /// Directory the partitions are written into
+    #[arg(long, short)]
+    output: PathBuf,
+    /// Tables to export, comma separated
+    #[arg(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_values_t = [Table::Laundrylog, Table::Cycles]
+    )]
+    tables: Vec<Table>,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub(crate) enum Table {
+    /// `LaundryLog` joined with where the machine is, partitioned by `timestamp`
+    Laundrylog,
+    /// `MachineCycles`, partitioned by `cycle_start`
+    Cycles,
+}
+
+/// Writes each table into `{output}/{table}/date={date}/location_id={location_id}/part-0.parquet`
+#[instrument(skip_all)]
+pub(crate) async fn export_parquet(db_config: DbConfig, args: ParquetArgs) -> Result<()> {
+    // Partition files are replaced, a range starting or ending mid day would cut one short
+    for (name, value) in [("--from", Some(args.range.from)), ("--to", args.range.to)] {
+        if let Some(value) = value
+            && value.time() != NaiveTime::MIN
+        {
+            bail!(
+                "{name} must be a UTC midnight, e.g. {}",
+                value.date().and_time(NaiveTime::MIN)
+            );
+        }
+    }
+
+    let db_type = db_config.r#type;
+    let pool = connect(&db_config).await?;
+    let mut conn = pool.get_connection().await?;
+
+    for table in args.tables.iter().copied() {
+        let mut writer = PartitionWriter::new(args.output.join(table.name()), table.schema());
+        let mut reader = BatchReader::new(db_type, table.source(), &args.range);
+        let total = match table {
+            Table::Laundrylog => {
+                write_table(&mut conn, &mut reader, &mut writer, ExportRow::from_row).await?
+            }
+            Table::Cycles => {
+                write_table(&mut conn, &mut reader, &mut writer, CycleRow::from_row).await?
+            }
+        };
+        writer.close()?;
+        info!("Exported {} rows of {}", total, table.name());
+    }
+    Ok(())
+}
+
+/// A row that can be written into a partition
+trait PartitionRow: Sized {
+    fn partition(&self) -> (NaiveDate, &str);
+    fn batch(schema: &SchemaRef, rows: &[&Self]) -> Result<RecordBatch>;
+}
+
+async fn write_table<R: PartitionRow>(
+    conn: &mut MiddlewarePoolConnection,
+    reader: &mut BatchReader<'_>,
+    writer: &mut PartitionWriter,
+    from_row: fn(&CustomDbRow) -> Result<R>,
+) -> Result<usize> {
+    let mut total = 0;
+    while let Some(rows) = reader.next(conn).await? {
+        let rows = rows.iter().map(from_row).collect::<Result<Vec<_>>>()?;
+        // Ordered by date, a batch only closes the partitions of earlier dates
+        let mut partitions: BTreeMap<(NaiveDate, &str), Vec<&R>> = BTreeMap::new();
+        for row in rows.iter() {
+            partitions.entry(row.partition()).or_default().push(row);
+        }
+        for ((date, location_id), rows) in partitions {
+            let batch = R::batch(&writer.schema, &rows)?;
+            writer.write(date, location_id, &batch)?;
+        }
+
+        total += rows.len();
+        info!("Exported {} rows, up to {}", total, reader.position());
+    }
+    Ok(total)
+}
+
+/// Files of the current date, one per location. Files are replaced, export whole days.
+struct PartitionWriter {
+    root: PathBuf,
+    schema: SchemaRef,
+    date: Option<NaiveDate>,
+    writers: HashMap<String, ArrowWriter<File>>,
+}
+
+impl PartitionWriter {
+    fn new(root: PathBuf, schema: SchemaRef) -> Self {
+        Self {
+            root,
+            schema,
+            date: None,
+            writers: HashMap::new(),
+        }
+    }
+
+    fn write(&mut self, date: NaiveDate, location_id: &str, batch: &RecordBatch) -> Result<()> {
+        if self.date != Some(date) {
+            self.close()?;
+            self.date = Some(date);
+        }
+
+        if !self.writers.contains_key(location_id) {
+            let dir = self
+                .root
+                .join(format!("date={date}"))
+                .join(format!("location_id={location_id}"));
+            create_dir_all(&dir)?;
+            let properties = WriterProperties::builder()
+                .set_compression(Compression::SNAPPY)
+                .build();
+            let writer = ArrowWriter::try_new(
+                File::create(dir.join("part-0.parquet"))?,
+                self.schema.clone(),
+                Some(properties),
+            )?;
+            self.writers.insert(location_id.to_string(), writer);
+        }
+        self.writers
+            .get_mut(location_id)
+            .ok_or_eyre("Missing partition writer")?
+            .write(batch)?;
+        Ok(())
+    }
+
+    /// Writes the footer of every open file
+    fn close(&mut self) -> Result<()> {
+        for (_, writer) in self.writers.drain() {
+            writer.close()?;
+        }
+        Ok(())
+    }
+}
+
+fn timestamp_type() -> DataType {
+    DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into()))
+}
+
+/// Repeated and enum like strings
+fn dictionary_type() -> DataType {
+    DataType::Dictionary(Box::new(DataType::Int32), Box::new(DataType::Utf8))
+}
+
+fn timestamps(values: impl Iterator<Item = Option<NaiveDateTime>>) -> ArrayRef {
+    Arc::new(
+        values
+            .map(|v| v.map(|v| v.and_utc().timestamp_micros()))
+            .collect::<TimestampMicrosecondArray>()
+            .with_timezone("UTC"),
+    )
+}
+
+fn dictionary<'a>(values: impl Iterator<Item = Option<&'a str>>) -> ArrayRef {
+    Arc::new(values.collect::<DictionaryArray<Int32Type>>())
+}
+
+/// Settings are kept as JSON text, CSC changes their shape per machine
+fn settings<'a>(values: impl Iterator<Item = Option<&'a serde_json::Value>>) -> ArrayRef {
+    Arc::new(
+        values
+            .map(|v| v.map(|v| v.to_string()))
+            .collect::<StringArray>(),
+    )
+}
+
+impl Table {
+    fn name(self) -> &'static str {
+        match self {
+            Table::Laundrylog => "laundrylog",
+            Table::Cycles => "cycles",
+        }
+    }
+
+    fn source(self) -> &'static Source {
+        match self {
+            Table::Laundrylog => &LAUNDRY_LOG,
+            Table::Cycles => &MACHINE_CYCLES,
+        }
+    }
+
+    fn schema(self) -> SchemaRef {
+        let fields = match self {
+            Table::Laundrylog => vec![
+                Field::new("timestamp", timestamp_type(), false),
+                Field::new("pep_id", DataType::Utf8, false),
+                Field::new("location_id", dictionary_type(), false),
+                Field::new("location_label", dictionary_type(), false),
+                Field::new("room_id", dictionary_type(), false),
+                Field::new("room_label", dictionary_type(), false),
+                Field::new("sticker_number", DataType::Int32, false),
+                Field::new("machine_id", DataType::Utf8, false),
+                Field::new("machine_type", dictionary_type(), false),
+                Field::new("state", dictionary_type(), false),
+                Field::new("time_remaining", DataType::Int32, false),
+                Field::new("door_closed", DataType::Boolean, false),
+                Field::new("not_available_reason", dictionary_type(), true),
+                Field::new("backfilled", DataType::Boolean, false),
+                Field::new("machine_settings", DataType::Utf8, true),
+            ],
+            Table::Cycles => vec![
+                Field::new("cycle_start", timestamp_type(), false),
+                Field::new("pep_id", DataType::Utf8, false),
+                Field::new("location_id", dictionary_type(), false),
+                Field::new("room_id", dictionary_type(), false),
+                Field::new("sticker_number", DataType::Int32, false),
+                Field::new("machine_type", dictionary_type(), false),
+                Field::new("cycle_end", timestamp_type(), true),
+                Field::new("duration_seconds", DataType::Int64, true),
+                Field::new("machine_settings", DataType::Utf8, true),
+                Field::new("interrupted", DataType::Boolean, false),
+                Field::new("estimated_end", DataType::Boolean, false),
+                Field::new("door_opened", timestamp_type(), true),
+                Field::new("door_open_seconds", DataType::Int64, true),
+                Field::new("probably_full", DataType::Boolean, false),
+            ],
+        };
+        Arc::new(Schema::new(fields))
+    }
+}
+
+impl PartitionRow for ExportRow {
+    fn partition(&self) -> (NaiveDate, &str) {
+        (self.timestamp.date(), &self.location_id)
+    }
+
+    fn batch(schema: &SchemaRef, rows: &[&Self]) -> Result<RecordBatch> {
+        let columns: Vec<ArrayRef> = vec![
+            timestamps(rows.iter().map(|v| Some(v.timestamp))),
+            Arc::new(StringArray::from_iter_values(
+                rows.iter().map(|v| &v.pep_id),
+            )),
+            dictionary(rows.iter().map(|v| Some(v.location_id.as_str()))),
+            dictionary(rows.iter().map(|v| Some(v.location_label.as_str()))),
+            dictionary(rows.iter().map(|v| Some(v.room_id.as_str()))),
+            dictionary(rows.iter().map(|v| Some(v.room_label.as_str()))),
+            Arc::new(Int32Array::from_iter_values(
+                rows.iter().map(|v| v.sticker_number as i32),
+            )),
+            Arc::new(StringArray::from_iter_values(
+                rows.iter().map(|v| &v.machine_id),
+            )),
+            dictionary(rows.iter().map(|v| Some(v.machine_type.as_str()))),
+            dictionary(rows.iter().map(|v| Some(v.state.as_str()))),
+            Arc::new(Int32Array::from_iter_values(
+                rows.iter().map(|v| v.time_remaining as i32),
+            )),
+            Arc::new(BooleanArray::from_iter(
+                rows.iter().map(|v| Some(v.door_closed)),
+            )),
+            dictionary(rows.iter().map(|v| v.not_available_reason.as_deref())),
+            Arc::new(BooleanArray::from_iter(
+                rows.iter().map(|v| Some(v.backfilled)),
+            )),
+            settings(rows.iter().map(|v| v.machine_settings.as_ref())),
+        ];
+        Ok(RecordBatch::try_new(schema.clone(), columns)?)
+    }
+}
+
+/// A `MachineCycles` row joined with where the machine is
+struct CycleRow {
+    cycle_start: NaiveDateTime,
+    pep_id: String,
+    location_id: String,
+    room_id: String,
+    sticker_number: i64,
+    machine_type: String,
+    cycle_end: Option<NaiveDateTime>,
+    duration_seconds: Option<i64>,
+    machine_settings: Option<serde_json::Value>,
+    interrupted: bool,
+    estimated_end: bool,
+    door_opened: Option<NaiveDateTime>,
+    door_open_seconds: Option<i64>,
+    probably_full: bool,
+}
+
+impl CycleRow {
+    fn from_row(row: &CustomDbRow) -> Result<Self> {
+        let column = |index: usize| {
+            row.get_by_index(index)
+                .ok_or_eyre(format!("Failed to get row by index {index}"))
+        };
+        let text = |index: usize| -> Result<String> {
+            Ok(column(index)?
+                .as_text()
+                .ok_or_eyre(format!("Invalid text at index {index}"))?
+                .to_string())
+        };
+        let boolean = |index: usize| -> Result<bool> {
+            value_as_bool(column(index)?).ok_or_eyre(format!("Invalid bool at index {index}"))
+        };
+
+        Ok(Self {
+            cycle_start: value_as_timestamp(column(0)?).ok_or_eyre("Invalid cycle_start")?,
+            pep_id: text(1)?,
+            location_id: text(2)?,
+            room_id: text(3)?,
+            sticker_number: value_as_int(column(4)?).ok_or_eyre("Invalid sticker_number")?,
+            machine_type: text(5)?,
+            cycle_end: value_as_timestamp(column(6)?),
+            duration_seconds: value_as_int(column(7)?),
+            machine_settings: value_as_json(column(8)?),
+            interrupted: boolean(9)?,
+            estimated_end: boolean(10)?,
+            door_opened: value_as_timestamp(column(11)?),
+            door_open_seconds: value_as_int(column(12)?),
+            probably_full: boolean(13)?,
+        })
+    }
+}
+
+impl PartitionRow for CycleRow {
+    fn partition(&self) -> (NaiveDate, &str) {
+        (self.cycle_start.date(), &self.location_id)
+    }
+
+    fn batch(schema: &SchemaRef, rows: &[&Self]) -> Result<RecordBatch> {
+        let columns: Vec<ArrayRef> = vec![
+            timestamps(rows.iter().map(|v| Some(v.cycle_start))),
+            Arc::new(StringArray::from_iter_values(
+                rows.iter().map(|v| &v.pep_id),
+            )),
+            dictionary(rows.iter().map(|v| Some(v.location_id.as_str()))),
+            dictionary(rows.iter().map(|v| Some(v.room_id.as_str()))),
+            Arc::new(Int32Array::from_iter_values(
+                rows.iter().map(|v| v.sticker_number as i32),
+            )),
+            dictionary(rows.iter().map(|v| Some(v.machine_type.as_str()))),
+            timestamps(rows.iter().map(|v| v.cycle_end)),
+            Arc::new(Int64Array::from_iter(
+                rows.iter().map(|v| v.duration_seconds),
+            )),
+            settings(rows.iter().map(|v| v.machine_settings.as_ref())),
+            Arc::new(BooleanArray::from_iter(
+                rows.iter().map(|v| Some(v.interrupted)),
+            )),
+            Arc::new(BooleanArray::from_iter(
+                rows.iter().map(|v| Some(v.estimated_end)),
+            )),
+            timestamps(rows.iter().map(|v| v.door_opened)),
+            Arc::new(Int64Array::from_iter(
+                rows.iter().map(|v| v.door_open_seconds),
+            )),
+            Arc::new(BooleanArray::from_iter(
+                rows.iter().map(|v| Some(v.probably_full)),
+            )),
+        ];
+        Ok(RecordBatch::try_new(schema.clone(), columns)?)
+    }
+}
+
+/// Columns are read by index in `CycleRow::from_row`
+const MACHINE_CYCLES: Source = Source {
+    columns: "c.cycle_start, c.pep_id, p.location_id, p.room_id, p.sticker_number, m.type, c.cycle_end, \
+    c.duration_seconds, c.machine_settings, c.interrupted, c.estimated_end, c.door_opened, c.door_open_seconds, c.probably_full",
+    from: "machinecycles c JOIN physicalendpoint p ON c.pep_id = p.pep_id \
+    JOIN machines m ON p.machine_id = m.machine_id",
+    key: "c.cycle_start",
+    pep_id: "c.pep_id",
+};
@@ -1,3 +1,4 @@
+use crate::models::secret;
 use color_eyre::{Report, eyre::Result};
 use refinery::config::{Config, ConfigDbType};
 use serde::Deserialize;
@@ -5,14 +6,23 @@ use sql_middleware::{ConfigAndPool, DatabaseType};
 use std::fmt::{Debug, Formatter};
 use std::result::Result as StdResult;
 use std::str::FromStr;
+use std::time::Duration;
+use tracing::{info, instrument, warn};
 
 #[cfg(not(any(feature = "postgres", feature = "sqlite", feature = "mssql")))]
 compile_error!("Requires at least one db driver.");
 
+mod connection;
+mod pool;
+
+pub(crate) use pool::{Connection, Pool};
+
 pub(crate) mod embedded {
     use crate::db::{DbConfig, DbType};
     use crate::utils::prelude::*;
-    use refinery::{Report as RunnerReport, config::Config};
+    use refinery::Report as RunnerReport;
+    #[cfg(any(feature = "postgres", feature = "mssql"))]
+    use refinery::config::Config;
     // Dynamically compile in migrations for each driver
 
     #[cfg(feature = "postgres")]
@@ -33,29 +43,53 @@ pub(crate) mod embedded {
     #[instrument(skip_all, name = "migrations", fields(db_type = ?db_config.r#type))]
     pub(crate) async fn run_async(db_config: DbConfig) -> Result<RunnerReport> {
         let db_type = db_config.r#type;
-        let mut config: Config = db_config.try_into().map_err(Report::msg)?;
 
         #[allow(unreachable_patterns)]
         match db_type {
             // Migrations are not compiled in if feature disabled
             #[cfg(feature = "postgres")]
             DbType::Postgres => {
+                let mut config: Config = db_config.try_into().map_err(Report::msg)?;
                 let runner = postgres::migrations::runner();
                 Ok(runner.run_async(&mut config).await?)
             }
             #[cfg(feature = "sqlite")]
-            DbType::Sqlite => {
-                let runner = sqlite::migrations::runner();
-                Ok(runner.run(&mut config)?)
-            }
+            DbType::Sqlite => run_sqlite(db_config),
             #[cfg(feature = "mssql")]
             DbType::Mssql => {
+                let mut config: Config = db_config.try_into().map_err(Report::msg)?;
                 let runner = mssql::migrations::runner();
                 Ok(runner.run_async(&mut config).await?)
             }
 
-            _ => panic!("{:?} driver and feature not selected", db_type),
+            _ => Err(Report::msg(db_type.not_compiled())),
+        }
+    }
+
+    /// Table rebuilds drop tables other rows still reference, sqlite only allows that with
+    /// foreign keys off. Refinery runs each migration in a transaction where the pragma is a
+    /// no-op, so it is set on the connection around the runner instead.
+    #[cfg(feature = "sqlite")]
+    fn run_sqlite(db_config: DbConfig) -> Result<RunnerReport> {
+        let path = db_config.path.ok_or(Report::msg("Missing path"))?;
+        let mut conn = rusqlite::Connection::open(path)?;
+        conn.pragma_update(None, "foreign_keys", "OFF")?;
+
+        let report = sqlite::migrations::runner().run(&mut conn)?;
+
+        let violations = conn
+            .prepare("PRAGMA foreign_key_check")?
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        if !violations.is_empty() {
+            return Err(Report::msg(format!(
+                "Foreign key violations after migrations in: {}",
+                violations.join(", ")
+            )));
         }
+
+        conn.pragma_update(None, "foreign_keys", "ON")?;
+        Ok(report)
     }
 }
 
@@ -70,25 +104,260 @@ static ENV_DB_NAME: &str = "DB_NAME";
 /// Database Username.
 static ENV_DB_USER: &str = "DB_USER";
 /// Database password.
+/// `DB_PASS_FILE`, `DB_USER_FILE` and `DB_URL_FILE` read a mounted secret instead.
 static ENV_DB_PASS: &str = "DB_PASS";
 /// Database file path. Used for sqlite.
 static ENV_DB_PATH: &str = "DB_PATH";
+/// Connection attempts at startup before giving up.
+static ENV_DB_RETRIES: &str = "DB_RETRIES";
+/// Seconds between connection attempts at startup.
+static ENV_DB_BACKOFF: &str = "DB_BACKOFF";
+/// Connection url, infers `DB_TYPE`. Fills in any of the fields above that are not set.
+static ENV_DB_URL: &str = "DB_URL";
+/// Postgres `sslmode`. Only `disable` and `prefer` are accepted, the pools connect without TLS.
+static ENV_DB_SSLMODE: &str = "DB_SSLMODE";
+/// Postgres CA certificate path. Refused, the pools connect without TLS.
+static ENV_DB_SSLROOTCERT: &str = "DB_SSLROOTCERT";
+/// MSSQL `TrustServerCertificate`. Only `true` is accepted, the certificate is not validated.
+static ENV_DB_TRUSTCERT: &str = "DB_TRUSTCERT";
 
 #[derive(Clone, Deserialize, Eq, PartialEq)]
+#[serde(try_from = "RawDbConfig")]
 pub(crate) struct DbConfig {
     pub(crate) r#type: DbType,
     pub(crate) host: Option<String>,
     pub(crate) port: Option<u16>,
-    #[serde(alias = "name")]
     pub(crate) db_name: Option<String>,
-    #[serde(alias = "user")]
     pub(crate) user_name: Option<String>,
-    #[serde(alias = "pass")]
     pub(crate) password: Option<String>,
     pub(crate) path: Option<String>,
+    /// TLS options, only kept to be checked by `problems`
+    pub(crate) ssl_mode: Option<String>,
+    pub(crate) ssl_root_cert: Option<String>,
+    pub(crate) trust_cert: Option<bool>,
+    pub(crate) pool: PoolConfig,
+    /// Sqlite only
+    pub(crate) sqlite: SqliteConfig,
+    /// Connection attempts at startup, the database may still be starting
+    pub(crate) retries: u32,
+    /// Seconds between connection attempts at startup
+    pub(crate) backoff: u64,
+}
+
+/// `DbConfig` as written, before `url` is merged in
+#[derive(Deserialize)]
+struct RawDbConfig {
+    r#type: Option<DbType>,
+    #[serde(default, deserialize_with = "secret::optional")]
+    url: Option<String>,
+    host: Option<String>,
+    port: Option<u16>,
+    #[serde(alias = "name")]
+    db_name: Option<String>,
+    #[serde(alias = "user", default, deserialize_with = "secret::optional")]
+    user_name: Option<String>,
+    #[serde(alias = "pass", default, deserialize_with = "secret::optional")]
+    password: Option<String>,
+    path: Option<String>,
+    #[serde(alias = "sslmode")]
+    ssl_mode: Option<String>,
+    #[serde(alias = "sslrootcert")]
+    ssl_root_cert: Option<String>,
+    #[serde(alias = "trustcert")]
+    trust_cert: Option<bool>,
+    #[serde(default)]
+    pool: PoolConfig,
+    #[serde(default)]
+    sqlite: SqliteConfig,
+    #[serde(default = "DbConfig::default_retries")]
+    retries: u32,
+    #[serde(default = "DbConfig::default_backoff")]
+    backoff: u64,
+}
+
+impl DbConfig {
+    fn default_retries() -> u32 {
+        10
+    }
+    fn default_backoff() -> u64 {
+        3
+    }
+
+    /// Every field the chosen backend needs but is missing or unusable, with its config key.
+    /// `section` is where the config was read from, `db` or `source`.
+    pub(crate) fn problems(&self, section: &str) -> Vec<String> {
+        let key = |name: &str| {
+            format!(
+                "{section}.{name} ({}_{})",
+                section.to_uppercase(),
+                name.to_uppercase()
+            )
+        };
+        let mut problems = Vec::new();
+        if !self.r#type.is_compiled() {
+            problems.push(format!("{}: {}", key("type"), self.r#type.not_compiled()));
+        }
+
+        let required: &[(&str, bool)] = match self.r#type {
+            DbType::Sqlite => &[("path", self.path.is_some())],
+            DbType::Postgres | DbType::Mssql => &[
+                ("host", self.host.is_some()),
+                ("name", self.db_name.is_some()),
+                ("user", self.user_name.is_some()),
+                ("pass", self.password.is_some()),
+            ],
+        };
+        for (name, _) in required.iter().filter(|(_, set)| !set) {
+            problems.push(format!("{}: required for {:?}", key(name), self.r#type));
+        }
+
+        // sql-middleware builds its pools without a TLS connector and never validates the MSSQL
+        // certificate, anything asking for more is refused instead of silently ignored.
+        if let Some(mode) = &self.ssl_mode {
+            if self.r#type != DbType::Postgres {
+                problems.push(format!("{}: Postgres only", key("sslmode")));
+            } else if !matches!(mode.to_lowercase().as_str(), "disable" | "prefer") {
+                problems.push(format!(
+                    "{}: '{mode}' is not supported, connections do not use TLS",
+                    key("sslmode")
+                ));
+            }
+        }
+        if self.ssl_root_cert.is_some() {
+            problems.push(format!(
+                "{}: not supported, connections do not use TLS",
+                key("sslrootcert")
+            ));
+        }
+        match self.trust_cert {
+            Some(_) if self.r#type != DbType::Mssql => {
+                problems.push(format!("{}: MSSQL only", key("trustcert")));
+            }
+            Some(false) => problems.push(format!(
+                "{}: false is not supported, the server certificate is not validated",
+                key("trustcert")
+            )),
+            _ => {}
+        }
+
+        if self.r#type != DbType::Postgres && self.pool.statement_timeout.is_some() {
+            problems.push(format!(
+                "{section}.pool.statement_timeout: not supported for {:?}",
+                self.r#type
+            ));
+        }
+        // sql-middleware does not expose one, idle connections stay open until the server closes them
+        if self.pool.idle_timeout.is_some() {
+            problems.push(format!(
+                "{section}.pool.idle_timeout: not supported for {:?}",
+                self.r#type
+            ));
+        }
+        if self.pool.max == Some(0) {
+            problems.push(format!("{section}.pool.max: must be at least 1"));
+        }
+        problems
+    }
+}
+
+/// Fields set directly win over the url
+impl TryFrom<RawDbConfig> for DbConfig {
+    type Error = String;
+
+    fn try_from(raw: RawDbConfig) -> StdResult<Self, Self::Error> {
+        let url = match &raw.url {
+            Some(url) => connection::parse(url)?,
+            None => Default::default(),
+        };
+        let r#type = match (raw.r#type, url.r#type) {
+            (Some(set), Some(inferred)) if set != inferred => {
+                return Err(format!(
+                    "'{ENV_DB_TYPE}' is {set:?} but '{ENV_DB_URL}' is for {inferred:?}"
+                ));
+            }
+            (Some(v), _) | (None, Some(v)) => v,
+            (None, None) => return Err(format!("MISSING '{ENV_DB_TYPE}' or '{ENV_DB_URL}'")),
+        };
+
+        Ok(Self {
+            r#type,
+            host: raw.host.or(url.host),
+            port: raw.port.or(url.port).or(r#type.default_port()),
+            db_name: raw.db_name.or(url.db_name),
+            user_name: raw.user_name.or(url.user_name),
+            password: raw.password.or(url.password),
+            path: raw.path.or(url.path),
+            ssl_mode: raw.ssl_mode.or(url.ssl_mode),
+            ssl_root_cert: raw.ssl_root_cert.or(url.ssl_root_cert),
+            trust_cert: raw.trust_cert.or(url.trust_cert),
+            pool: raw.pool,
+            sqlite: raw.sqlite,
+            retries: raw.retries,
+            backoff: raw.backoff,
+        })
+    }
+}
+
+/// Limits applied by `Pool`, every key is optional
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq)]
+pub(crate) struct PoolConfig {
+    /// Connections handed out at once, further requests wait for one to be returned
+    pub(crate) max: Option<usize>,
+    /// Seconds to wait for a connection
+    pub(crate) timeout: Option<u64>,
+    /// Seconds a statement may run before the server cancels it. Postgres only
+    #[serde(alias = "statementtimeout")]
+    pub(crate) statement_timeout: Option<u64>,
+    /// Seconds before an unused connection is closed. Refused, the pools keep them open
+    #[serde(alias = "idletimeout")]
+    pub(crate) idle_timeout: Option<u64>,
+}
+
+/// Applied to every sqlite connection, the defaults allow concurrent writers to wait
+/// instead of failing with `database is locked`
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+#[serde(default)]
+pub(crate) struct SqliteConfig {
+    /// Write ahead logging, readers do not block the writer
+    pub(crate) wal: bool,
+    /// Milliseconds a writer waits for a lock
+    #[serde(alias = "busytimeout")]
+    pub(crate) busy_timeout: u64,
+    pub(crate) synchronous: SqliteSynchronous,
 }
 
-// Hide password from debug
+impl Default for SqliteConfig {
+    fn default() -> Self {
+        Self {
+            wal: true,
+            busy_timeout: 5000,
+            synchronous: SqliteSynchronous::Normal,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum SqliteSynchronous {
+    Off,
+    /// Safe with WAL, a power loss may only lose the last commits
+    Normal,
+    Full,
+    Extra,
+}
+
+impl SqliteSynchronous {
+    fn pragma(self) -> &'static str {
+        match self {
+            SqliteSynchronous::Off => "OFF",
+            SqliteSynchronous::Normal => "NORMAL",
+            SqliteSynchronous::Full => "FULL",
+            SqliteSynchronous::Extra => "EXTRA",
+        }
+    }
+}
+
+// Hide credentials from debug
 impl Debug for DbConfig {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("DbConfig")
@@ -96,8 +365,14 @@ impl Debug for DbConfig {
             .field("host", &self.host)
             .field("port", &self.port)
             .field("db_name", &self.db_name)
-            .field("username", &self.user_name)
             .field("path", &self.path)
+            .field("ssl_mode", &self.ssl_mode)
+            .field("ssl_root_cert", &self.ssl_root_cert)
+            .field("trust_cert", &self.trust_cert)
+            .field("pool", &self.pool)
+            .field("sqlite", &self.sqlite)
+            .field("retries", &self.retries)
+            .field("backoff", &self.backoff)
             .finish()
     }
 }
@@ -185,7 +460,7 @@ impl TryFrom<DbConfig> for Config {
                 Ok(Config::new(ConfigDbType::Sqlite)
                     .set_db_path(&value.path.ok_or("Missing path")?))
             }
-            _ => panic!("{:?} driver and feature not selected", value.r#type),
+            _ => Err(value.r#type.not_compiled()),
         }
     }
 }
@@ -231,7 +506,7 @@ fn new_config(value: DbConfig) -> StdResult<Config, String> {
 
 #[derive(Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Deserialize)]
 #[non_exhaustive]
-#[serde(rename_all = "lowercase")]
+#[serde(try_from = "String")]
 pub(crate) enum DbType {
     Postgres,
     Sqlite,
@@ -242,7 +517,7 @@ impl FromStr for DbType {
     type Err = String;
     fn from_str(value: &str) -> Result<Self, Self::Err> {
         match value.to_lowercase().as_str() {
-            "postgres" | "pgsql " => Ok(Self::Postgres),
+            "postgres" | "pgsql" => Ok(Self::Postgres),
             "mssql" | "sqlserver" => Ok(Self::Mssql),
             "sqlite" => Ok(Self::Sqlite),
             _ => Err(format!("Unknown database type: {}", value)),
@@ -250,17 +525,62 @@ impl FromStr for DbType {
     }
 }
 
-impl From<DbType> for DatabaseType {
-    fn from(value: DbType) -> Self {
+/// Config and env values go through `from_str`, so every alias is accepted
+impl TryFrom<String> for DbType {
+    type Error = String;
+    fn try_from(value: String) -> StdResult<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl DbType {
+    fn is_compiled(self) -> bool {
+        match self {
+            DbType::Postgres => cfg!(feature = "postgres"),
+            DbType::Sqlite => cfg!(feature = "sqlite"),
+            DbType::Mssql => cfg!(feature = "mssql"),
+        }
+    }
+
+    /// Used when neither `port` nor the url sets one
+    fn default_port(self) -> Option<u16> {
+        match self {
+            DbType::Postgres => Some(5432),
+            DbType::Mssql => Some(1433),
+            DbType::Sqlite => None,
+        }
+    }
+
+    /// Feature that compiles in the driver, any number can be enabled at once
+    fn feature(self) -> &'static str {
+        match self {
+            DbType::Postgres => "postgres",
+            DbType::Sqlite => "sqlite",
+            DbType::Mssql => "mssql",
+        }
+    }
+
+    fn not_compiled(self) -> String {
+        format!(
+            "{self:?} driver not compiled in, build with the `{}` or `all-db` feature",
+            self.feature()
+        )
+    }
+}
+
+impl TryFrom<DbType> for DatabaseType {
+    type Error = String;
+
+    fn try_from(value: DbType) -> StdResult<Self, Self::Error> {
         #[allow(unreachable_patterns)]
         match value {
             #[cfg(feature = "postgres")]
-            DbType::Postgres => Self::Postgres,
+            DbType::Postgres => Ok(Self::Postgres),
             #[cfg(feature = "sqlite")]
-            DbType::Sqlite => Self::Sqlite,
+            DbType::Sqlite => Ok(Self::Sqlite),
             #[cfg(feature = "mssql")]
-            DbType::Mssql => Self::Mssql,
-            _ => unimplemented!(),
+            DbType::Mssql => Ok(Self::Mssql),
+            _ => Err(value.not_compiled()),
         }
     }
 }
@@ -274,26 +594,68 @@ impl From<DbType> for ConfigDbType {
     }
 }
 
-pub(crate) async fn new_pool(config: DbConfig) -> Result<ConfigAndPool> {
+/// Builds the pool and checks it can reach the database before any session setup
+async fn new_pool(config: DbConfig) -> Result<Pool> {
+    let db_config = config.clone();
     #[allow(unreachable_patterns)]
-    match config.r#type {
+    let inner = match config.r#type {
         #[cfg(feature = "postgres")]
         DbType::Postgres => {
             let cfg = config.try_into().map_err(Report::msg)?;
-            Ok(ConfigAndPool::new_postgres(cfg).await?)
+            ConfigAndPool::new_postgres(cfg).await?
         }
         #[cfg(feature = "sqlite")]
         DbType::Sqlite => {
             let mut cfg: sql_middleware::SqliteOptions = config.try_into().map_err(Report::msg)?;
             cfg = cfg.with_translation(true);
-            Ok(ConfigAndPool::new_sqlite(cfg).await?)
+            ConfigAndPool::new_sqlite(cfg).await?
         }
         #[cfg(feature = "mssql")]
         DbType::Mssql => {
             let cfg = config.try_into().map_err(Report::msg)?;
-            Ok(ConfigAndPool::new_mssql(cfg).await?)
+            ConfigAndPool::new_mssql(cfg).await?
+        }
+        _ => return Err(Report::msg(config.r#type.not_compiled())),
+    };
+
+    let pool = Pool::new(inner, &db_config);
+    ping(&pool).await?;
+    pool.init().await?;
+    Ok(pool)
+}
+
+/// Runs a trivial query to check the database is reachable through the pool
+pub(crate) async fn ping(pool: &Pool) -> Result<()> {
+    let mut conn = pool.get_connection().await?;
+    conn.query("SELECT 1").select().await?;
+    Ok(())
+}
+
+/// Creates the pool until the database answers, `config.retries` times at most. Some drivers
+/// connect while the pool is built, so the whole construction is retried.
+/// Containers are often started alongside the database and come up before it accepts connections.
+#[instrument(skip_all)]
+pub(crate) async fn connect(config: &DbConfig) -> Result<Pool> {
+    let attempts = config.retries.max(1);
+    let mut attempt = 1;
+    loop {
+        match new_pool(config.clone()).await {
+            Ok(pool) => {
+                info!("Database reachable");
+                return Ok(pool);
+            }
+            Err(e) if attempt < attempts => {
+                warn!(
+                    "Database unreachable, attempt {}/{}, see '{ENV_DB_RETRIES}' and '{ENV_DB_BACKOFF}': {}",
+                    attempt, attempts, e
+                );
+                attempt += 1;
+                tokio::time::sleep(Duration::from_secs(config.backoff)).await;
+            }
+            Err(e) => {
+                return Err(e.wrap_err(format!("Database unreachable after {attempts} attempts")));
+            }
         }
-        _ => panic!("{:?} driver and feature not selected", config.r#type),
     }
 }
 
@@ -0,0 +1,119 @@
+use crate::db::DbType;
+use percent_encoding::percent_decode_str;
+use std::result::Result as StdResult;
+use url::Url;
+
+/// Fields read from a connection url, each fills in a `DbConfig` field that is not set
+#[derive(Default)]
+pub(super) struct ConnectionUrl {
+    pub(super) r#type: Option<DbType>,
+    pub(super) host: Option<String>,
+    pub(super) port: Option<u16>,
+    pub(super) db_name: Option<String>,
+    pub(super) user_name: Option<String>,
+    pub(super) password: Option<String>,
+    pub(super) path: Option<String>,
+    pub(super) ssl_mode: Option<String>,
+    pub(super) ssl_root_cert: Option<String>,
+    pub(super) trust_cert: Option<bool>,
+}
+
+/// Accepts `postgres://`, `postgresql://`, `sqlite://`, `mssql://`, `sqlserver://`, and
+/// ADO style `Server=host,1433;Database=...` strings for mssql
+pub(super) fn parse(value: &str) -> StdResult<ConnectionUrl, String> {
+    if let Some(path) = value
+        .strip_prefix("sqlite://")
+        .or_else(|| value.strip_prefix("sqlite:"))
+    {
+        return Ok(ConnectionUrl {
+            r#type: Some(DbType::Sqlite),
+            path: Some(path.to_string()),
+            ..Default::default()
+        });
+    }
+    if !value.contains("://") {
+        return parse_ado(value);
+    }
+
+    let url = Url::parse(value).map_err(|e| format!("Invalid database url: {e}"))?;
+    let r#type = match url.scheme() {
+        "postgres" | "postgresql" => DbType::Postgres,
+        "mssql" | "sqlserver" => DbType::Mssql,
+        scheme => return Err(format!("Unknown database url scheme: {scheme}")),
+    };
+    let mut parsed = ConnectionUrl {
+        r#type: Some(r#type),
+        host: url.host_str().map(str::to_string),
+        port: url.port(),
+        db_name: Some(url.path().trim_start_matches('/'))
+            .filter(|v| !v.is_empty())
+            .map(decode)
+            .transpose()?,
+        user_name: Some(url.username())
+            .filter(|v| !v.is_empty())
+            .map(decode)
+            .transpose()?,
+        password: url.password().map(decode).transpose()?,
+        ..Default::default()
+    };
+    for (key, value) in url.query_pairs() {
+        match key.to_lowercase().as_str() {
+            "sslmode" => parsed.ssl_mode = Some(value.into_owned()),
+            "sslrootcert" => parsed.ssl_root_cert = Some(value.into_owned()),
+            "trustservercertificate" => parsed.trust_cert = Some(boolean(&value)?),
+            _ => return Err(format!("Unknown database url option: {key}")),
+        }
+    }
+    Ok(parsed)
+}
+
+/// `Server=tcp:host,1433;Database=laundry;User Id=sa;Password=...;TrustServerCertificate=true`
+fn parse_ado(value: &str) -> StdResult<ConnectionUrl, String> {
+    let mut parsed = ConnectionUrl {
+        r#type: Some(DbType::Mssql),
+        ..Default::default()
+    };
+    for pair in value.split(';').map(str::trim).filter(|v| !v.is_empty()) {
+        let (key, value) = pair
+            .split_once('=')
+            .ok_or(format!("Invalid connection string part: {pair}"))?;
+        let value = value.trim().to_string();
+        match key.trim().to_lowercase().as_str() {
+            "server" | "data source" | "address" | "addr" => {
+                let server = value.strip_prefix("tcp:").unwrap_or(&value);
+                match server.split_once(',') {
+                    Some((host, port)) => {
+                        parsed.host = Some(host.to_string());
+                        parsed.port = Some(
+                            port.trim()
+                                .parse()
+                                .map_err(|_| format!("Invalid port: {port}"))?,
+                        );
+                    }
+                    None => parsed.host = Some(server.to_string()),
+                }
+            }
+            "database" | "initial catalog" => parsed.db_name = Some(value),
+            "user id" | "uid" | "user" => parsed.user_name = Some(value),
+            "password" | "pwd" => parsed.password = Some(value),
+            "trustservercertificate" => parsed.trust_cert = Some(boolean(&value)?),
+            key => return Err(format!("Unknown connection string key: {key}")),
+        }
+    }
+    Ok(parsed)
+}
+
+fn decode(value: &str) -> StdResult<String, String> {
+    Ok(percent_decode_str(value)
+        .decode_utf8()
+        .map_err(|e| format!("Invalid database url: {e}"))?
+        .into_owned())
+}
+
+fn boolean(value: &str) -> StdResult<bool, String> {
+    match value.to_lowercase().as_str() {
+        "true" | "yes" => Ok(true),
+        "false" | "no" => Ok(false),
+        _ => Err(format!("Invalid boolean: {value}")),
+    }
+}
@@ -0,0 +1,139 @@
+use crate::db::{DbConfig, DbType, PoolConfig, SqliteConfig};
+use crate::logic::db::value_as_int;
+use crate::utils::prelude::*;
+use color_eyre::eyre::eyre;
+use sql_middleware::{ConfigAndPool, MiddlewarePoolConnection};
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// `ConfigAndPool` with the limits of `DbConfig.pool`.
+/// sql-middleware builds its pools with a fixed size and no idle timeout, so `max` and `timeout`
+/// are applied around it. `max` can only lower the number of connections in use, never raise it.
+#[derive(Clone)]
+pub(crate) struct Pool {
+    inner: ConfigAndPool,
+    db_type: DbType,
+    config: PoolConfig,
+    sqlite: SqliteConfig,
+    permits: Option<Arc<Semaphore>>,
+}
+
+/// A pooled connection, counted against `PoolConfig.max` until dropped
+pub(crate) struct Connection {
+    conn: MiddlewarePoolConnection,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Deref for Connection {
+    type Target = MiddlewarePoolConnection;
+
+    fn deref(&self) -> &Self::Target {
+        &self.conn
+    }
+}
+
+impl DerefMut for Connection {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.conn
+    }
+}
+
+impl Pool {
+    pub(super) fn new(inner: ConfigAndPool, config: &DbConfig) -> Self {
+        Self {
+            inner,
+            db_type: config.r#type,
+            config: config.pool.clone(),
+            sqlite: config.sqlite.clone(),
+            permits: config.pool.max.map(|v| Arc::new(Semaphore::new(v))),
+        }
+    }
+
+    /// Waits at most `PoolConfig.timeout` seconds for a free connection
+    pub(crate) async fn get_connection(&self) -> Result<Connection> {
+        let acquire = async {
+            let permit = match &self.permits {
+                Some(permits) => Some(permits.clone().acquire_owned().await?),
+                None => None,
+            };
+            let mut conn = self.inner.get_connection().await?;
+            self.prepare(&mut conn).await?;
+            Ok::<_, Report>(Connection {
+                conn,
+                _permit: permit,
+            })
+        };
+
+        match self.config.timeout {
+            Some(timeout) => tokio::time::timeout(Duration::from_secs(timeout), acquire)
+                .await
+                .map_err(|_| eyre!("No database connection available after {timeout} seconds"))?,
+            None => acquire.await,
+        }
+    }
+
+    /// Settings last for the connection, but the pool has no connect hook.
+    /// Postgres gets `statement_timeout` on every checkout, a single `SET` costs less than a query
+    /// to check it. The sqlite temp schema is private to a connection, so its `user_version` marks
+    /// one that is already set up.
+    async fn prepare(&self, conn: &mut MiddlewarePoolConnection) -> Result<()> {
+        match self.db_type {
+            DbType::Postgres => {
+                if let Some(timeout) = self.config.statement_timeout {
+                    conn.query(&format!("SET statement_timeout = {}", timeout * 1000))
+                        .dml()
+                        .await?;
+                }
+                return Ok(());
+            }
+            DbType::Mssql => return Ok(()),
+            DbType::Sqlite => {}
+        }
+        let prepared = conn
+            .query("PRAGMA temp.user_version")
+            .select()
+            .await?
+            .results
+            .first()
+            .and_then(|row| row.get_by_index(0))
+            .and_then(value_as_int)
+            .is_some_and(|v| v == PREPARED);
+        if prepared {
+            return Ok(());
+        }
+
+        conn.query(&format!(
+            "PRAGMA busy_timeout = {}",
+            self.sqlite.busy_timeout
+        ))
+        .select()
+        .await?;
+        conn.query(&format!(
+            "PRAGMA synchronous = {}",
+            self.sqlite.synchronous.pragma()
+        ))
+        .dml()
+        .await?;
+        conn.query(&format!("PRAGMA temp.user_version = {PREPARED}"))
+            .dml()
+            .await?;
+        debug!("Sqlite connection prepared");
+        Ok(())
+    }
+
+    /// Switches sqlite to write ahead logging, readers no longer block the writer.
+    /// Stored in the database file, so only needed once.
+    pub(super) async fn init(&self) -> Result<()> {
+        if self.db_type == DbType::Sqlite && self.sqlite.wal {
+            let mut conn = self.get_connection().await?;
+            conn.query("PRAGMA journal_mode = WAL").select().await?;
+            info!("Sqlite journal mode set to WAL");
+        }
+        Ok(())
+    }
+}
+
+/// Any value but 0, the `user_version` of a new temp schema
+const PREPARED: i64 = 1;
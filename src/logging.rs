@@ -0,0 +1,219 @@
+#[cfg(feature = "otel")]
+use crate::models::config::OtlpConfig;
+use crate::models::config::{LogConfig, LogFileConfig, LogFormat, LogRotation, LogTime};
+use crate::utils::prelude::*;
+#[cfg(feature = "otel")]
+use opentelemetry::trace::TracerProvider;
+#[cfg(feature = "otel")]
+use opentelemetry_otlp::WithExportConfig;
+#[cfg(feature = "otel")]
+use opentelemetry_sdk::{Resource, trace::SdkTracerProvider};
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::time::{ChronoLocal, ChronoUtc, FormatTime};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer, Registry, fmt};
+
+type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+/// Flushes the log file and exports remaining spans when dropped, keep it alive until exit
+pub(crate) struct LogGuard {
+    _file: Option<WorkerGuard>,
+    #[cfg(feature = "otel")]
+    tracer: Option<SdkTracerProvider>,
+}
+
+#[cfg(feature = "otel")]
+impl Drop for LogGuard {
+    fn drop(&mut self) {
+        if let Some(tracer) = self.tracer.take()
+            && let Err(e) = tracer.shutdown()
+        {
+            error!("Failed to export remaining spans: {:?}", e);
+        }
+    }
+}
+
+/// Where console records are written
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Console {
+    Stdout,
+    /// For commands that may write their output to stdout
+    Stderr,
+}
+
+/// Installs the global subscriber
+pub(crate) fn init(config: &LogConfig, console: Console) -> Result<LogGuard> {
+    let mut layers: Vec<BoxedLayer> = vec![match console {
+        Console::Stdout => layer(config, std::io::stdout, true),
+        Console::Stderr => layer(config, std::io::stderr, true),
+    }];
+
+    let file = match &config.file {
+        Some(file) => {
+            let (writer, guard) = tracing_appender::non_blocking(appender(file)?);
+            layers.push(layer(config, writer, false));
+            Some(guard)
+        }
+        None => None,
+    };
+
+    #[cfg(feature = "otel")]
+    let tracer = match &config.otlp {
+        Some(otlp) => {
+            let (layer, tracer) = otlp_layer(otlp)?;
+            layers.push(layer);
+            Some(tracer)
+        }
+        None => None,
+    };
+
+    tracing_subscriber::registry()
+        .with(layers)
+        .with(filter(config)?)
+        .try_init()?;
+
+    #[cfg(not(feature = "otel"))]
+    if config.otlp.is_some() {
+        warn!("OTLP configured but the otel feature is not enabled");
+    }
+
+    Ok(LogGuard {
+        _file: file,
+        #[cfg(feature = "otel")]
+        tracer,
+    })
+}
+
+/// `RUST_LOG` wins over the config when set
+fn filter(config: &LogConfig) -> Result<EnvFilter> {
+    if std::env::var_os(EnvFilter::DEFAULT_ENV).is_some() {
+        return Ok(EnvFilter::try_from_default_env()?);
+    }
+
+    let mut directives = vec![config.level.clone()];
+    directives.extend(
+        config
+            .modules
+            .iter()
+            .map(|(module, level)| format!("{module}={level}")),
+    );
+    Ok(EnvFilter::try_new(directives.join(","))?)
+}
+
+fn layer<W>(config: &LogConfig, writer: W, ansi: bool) -> BoxedLayer
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let layer = fmt::layer()
+        .with_writer(writer)
+        .with_ansi(ansi)
+        .with_timer(Timer(config.time));
+    match config.format {
+        LogFormat::Text => layer.boxed(),
+        // Span fields such as task_id, location_id and room_id are kept on every record
+        LogFormat::Json => layer
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .boxed(),
+    }
+}
+
+/// Spans are batched and exported from a background thread
+#[cfg(feature = "otel")]
+fn otlp_layer(config: &OtlpConfig) -> Result<(BoxedLayer, SdkTracerProvider)> {
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_endpoint(config.endpoint.clone())
+        .build()?;
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(
+            Resource::builder()
+                .with_service_name(config.service_name.clone())
+                .build(),
+        )
+        .build();
+    let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+    Ok((
+        tracing_opentelemetry::layer().with_tracer(tracer).boxed(),
+        provider,
+    ))
+}
+
+fn appender(config: &LogFileConfig) -> Result<RollingFileAppender> {
+    let rotation = match config.rotation {
+        LogRotation::Minutely => Rotation::MINUTELY,
+        LogRotation::Hourly => Rotation::HOURLY,
+        LogRotation::Daily => Rotation::DAILY,
+        LogRotation::Never => Rotation::NEVER,
+    };
+    let mut builder = RollingFileAppender::builder()
+        .rotation(rotation)
+        .filename_prefix(&config.prefix);
+    if let Some(keep) = config.keep {
+        builder = builder.max_log_files(keep);
+    }
+    Ok(builder.build(&config.directory)?)
+}
+
+/// Both timers are chrono based, `local-time` can not read the offset once the runtime has threads
+#[derive(Clone, Copy)]
+struct Timer(LogTime);
+
+impl FormatTime for Timer {
+    fn format_time(&self, w: &mut Writer<'_>) -> std::fmt::Result {
+        match self.0 {
+            LogTime::Utc => ChronoUtc::rfc_3339().format_time(w),
+            LogTime::Local => ChronoLocal::rfc_3339().format_time(w),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "otel"))]
+mod tests {
+    use super::*;
+    use crate::testing::serve_once;
+    use tokio::task::spawn_blocking;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn spans_exported_to_collector() -> Result<()> {
+        let (address, collector) = serve_once("200 OK").await?;
+        let config = OtlpConfig {
+            endpoint: format!("http://{address}/v1/traces"),
+            service_name: "laundry-data-test".to_string(),
+        };
+
+        let (layer, provider) = otlp_layer(&config)?;
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            let lookup = tracing::info_span!(parent: None, "location", location_id = "loc-1");
+            lookup.in_scope(|| info!("Looked up"));
+        });
+        // Blocks until the batch was sent
+        spawn_blocking(move || provider.shutdown()).await??;
+
+        let request = collector.await??;
+        assert!(request.head.starts_with("post /v1/traces "));
+        assert!(
+            request
+                .head
+                .contains("content-type: application/x-protobuf")
+        );
+        // Strings are stored as is in the protobuf payload
+        for expected in ["laundry-data-test", "location", "loc-1"] {
+            assert!(
+                request
+                    .body
+                    .windows(expected.len())
+                    .any(|v| v == expected.as_bytes()),
+                "{expected} missing from the export"
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,510 @@
+use crate::db::Pool;
+use crate::logic::cycles::SAME_CYCLE_TOLERANCE;
+use crate::logic::db::{optional_text, value_as_bool, value_as_int, value_as_timestamp};
+use crate::models::api::ModeType;
+use crate::models::config::BackfillConfig;
+use crate::utils::prelude::*;
+use chrono::{NaiveDateTime, TimeDelta, Utc};
+use color_eyre::eyre::OptionExt;
+use sql_middleware::{MiddlewarePoolConnection, QueryAndParams, RowValues};
+use std::collections::BTreeMap;
+use tokio::time::{Duration, interval};
+
+/// A single `LaundryLog` row for one pep
+#[derive(Debug, Clone)]
+struct LogRow {
+    timestamp: NaiveDateTime,
+    time_remaining: i64,
+    not_available_reason: Option<String>,
+    door_closed: bool,
+    state: ModeType,
+    /// Copied as is, the column type differs across databases
+    machine_settings: RowValues,
+    backfilled: bool,
+}
+
+impl LogRow {
+    /// Only compares the columns backfilling can change
+    fn same_estimate(&self, other: &LogRow) -> bool {
+        self.time_remaining == other.time_remaining
+            && self.state == other.state
+            && self.door_closed == other.door_closed
+            && self.not_available_reason == other.not_available_reason
+    }
+}
+
+#[derive(Debug, Default)]
+struct BackfillStats {
+    inserted: usize,
+    updated: usize,
+    deleted: usize,
+}
+
+/// Long-lived task that fills gaps in each pep's `LaundryLog` series
+#[instrument(skip_all, fields(task_id=%id()))]
+pub(crate) async fn backfill_task(
+    config: BackfillConfig,
+    pool: Pool,
+    cancel_token: CancellationToken,
+) -> () {
+    info!("Initializing backfill task");
+    let mut ticker = interval(Duration::from_secs(config.interval));
+
+    loop {
+        tokio::select! {
+            _ = cancel_token.cancelled() => {debug!("Got cancel");break},
+            _ = ticker.tick() => {}
+        }
+
+        let mut conn = match pool.get_connection().await {
+            Ok(v) => v,
+            Err(e) => {
+                error!("Failed to get db connection: {:?}", e);
+                continue;
+            }
+        };
+        if let Err(e) = backfill_all(&mut conn, &config).await {
+            error!("Backfill failed: {:?}", e);
+        }
+    }
+}
+
+#[instrument(skip_all)]
+async fn backfill_all(conn: &mut MiddlewarePoolConnection, config: &BackfillConfig) -> Result<()> {
+    let now = Utc::now().naive_utc();
+    let since = now - TimeDelta::hours(config.lookback);
+
+    let query = QueryAndParams::new(SELECT_PEPS_QUERY, vec![RowValues::Timestamp(since)]);
+    let result = conn.query(&query.query).params(&query.params).select().await?;
+    let mut pep_ids = vec![];
+    for row in result.results.iter() {
+        let value = row
+            .get_by_index(0)
+            .ok_or_eyre("Failed to get row by index 0")?;
+        pep_ids.push(
+            value
+                .as_text()
+                .ok_or_eyre("Failed to convert to text")?
+                .to_string(),
+        );
+    }
+
+    let mut total = BackfillStats::default();
+    for pep_id in pep_ids {
+        match backfill_pep(conn, config, &pep_id, since, now).await {
+            Ok(stats) => {
+                total.inserted += stats.inserted;
+                total.updated += stats.updated;
+                total.deleted += stats.deleted;
+            }
+            Err(e) => error!(pep_id, "Failed to backfill: {:?}", e),
+        }
+    }
+    debug!("Backfill complete: {:?}", total);
+    Ok(())
+}
+
+/// Fills every gap between real rows, and the trailing gap up to now while an outage is ongoing.
+/// Previously backfilled rows are recalculated once the next real row is known.
+#[instrument(skip_all, fields(pep_id = pep_id))]
+async fn backfill_pep(
+    conn: &mut MiddlewarePoolConnection,
+    config: &BackfillConfig,
+    pep_id: &str,
+    since: NaiveDateTime,
+    now: NaiveDateTime,
+) -> Result<BackfillStats> {
+    let rows = load_rows(conn, pep_id, since).await?;
+    let real: Vec<&LogRow> = rows.iter().filter(|v| !v.backfilled).collect();
+    let mut existing: BTreeMap<NaiveDateTime, &LogRow> = rows
+        .iter()
+        .filter(|v| v.backfilled)
+        .map(|v| (v.timestamp, v))
+        .collect();
+    let (desired, ranges) = plan(config, &real, now);
+
+    let mut stats = BackfillStats::default();
+    for row in desired {
+        match existing.remove(&row.timestamp) {
+            Some(old) if old.same_estimate(&row) => {}
+            Some(_) => {
+                update_row(conn, pep_id, &row).await?;
+                stats.updated += 1;
+            }
+            None => {
+                insert_row(conn, pep_id, &row).await?;
+                stats.inserted += 1;
+            }
+        }
+    }
+
+    // Backfilled rows off the grid, e.g. written while a slow poll was in flight
+    for (timestamp, _) in existing {
+        if ranges
+            .iter()
+            .any(|(start, end)| timestamp > *start && timestamp < *end)
+        {
+            delete_row(conn, pep_id, timestamp).await?;
+            stats.deleted += 1;
+        }
+    }
+
+    Ok(stats)
+}
+
+/// Every synthesized row for the gaps between `real` rows and after the last one, and the ranges
+/// they cover.
+fn plan(
+    config: &BackfillConfig,
+    real: &[&LogRow],
+    now: NaiveDateTime,
+) -> (Vec<LogRow>, Vec<(NaiveDateTime, NaiveDateTime)>) {
+    let step = TimeDelta::seconds(config.step);
+    let gap_after = TimeDelta::seconds(config.gap_after);
+    let max_gap = TimeDelta::minutes(config.max_gap);
+
+    let mut desired = vec![];
+    let mut ranges = vec![];
+
+    for pair in real.windows(2) {
+        let (prev, next) = (pair[0], pair[1]);
+        let gap = next.timestamp - prev.timestamp;
+        if gap < gap_after || gap > max_gap {
+            continue;
+        }
+        let mut timestamp = prev.timestamp + step;
+        while timestamp < next.timestamp - step / 2 {
+            desired.push(synthesize(prev, Some(next), timestamp));
+            timestamp += step;
+        }
+        ranges.push((prev.timestamp, next.timestamp));
+    }
+
+    if let Some(last) = real.last()
+        && now - last.timestamp >= gap_after
+    {
+        // Stay a step behind now so an in-flight poll is not backfilled over
+        let end = (now - step).min(last.timestamp + max_gap);
+        let mut timestamp = last.timestamp + step;
+        while timestamp <= end {
+            desired.push(synthesize(last, None, timestamp));
+            timestamp += step;
+        }
+        ranges.push((last.timestamp, end + step));
+    }
+    (desired, ranges)
+}
+
+/// Estimates the row at `timestamp` from the last real row and, once service resumed, the next
+/// real row.
+///
+/// Without a next row the previous row is carried forward, a running machine counts down and is
+/// assumed idle when it reaches zero. With a next row a running machine in the same cycle is
+/// interpolated. Any other state change is placed at the predicted end of the previous cycle, or
+/// halfway through the gap when a new cycle started since its start time is unknown.
+fn synthesize(prev: &LogRow, next: Option<&LogRow>, timestamp: NaiveDateTime) -> LogRow {
+    let forward = carry_forward(prev, timestamp);
+    let Some(next) = next else { return forward };
+
+    if prev.state == ModeType::Running && next.state == ModeType::Running {
+        let elapsed = (next.timestamp - prev.timestamp).num_minutes();
+        let predicted = prev.time_remaining - elapsed;
+        if (predicted - next.time_remaining).abs() <= SAME_CYCLE_TOLERANCE {
+            let span = (next.timestamp - prev.timestamp).num_seconds().max(1);
+            let done = (timestamp - prev.timestamp).num_seconds();
+            let time_remaining =
+                prev.time_remaining + (next.time_remaining - prev.time_remaining) * done / span;
+            return LogRow {
+                time_remaining,
+                state: ModeType::Running,
+                ..forward
+            };
+        }
+    } else if prev.state == next.state {
+        return forward;
+    }
+
+    let prev_end = if prev.state == ModeType::Running {
+        (prev.timestamp + TimeDelta::minutes(prev.time_remaining)).min(next.timestamp)
+    } else {
+        prev.timestamp
+    };
+    let change_at = if next.state == ModeType::Running {
+        prev_end + (next.timestamp - prev_end) / 2
+    } else {
+        prev_end
+    };
+
+    if timestamp < change_at {
+        forward
+    } else {
+        carry_backward(next, timestamp)
+    }
+}
+
+fn carry_forward(prev: &LogRow, timestamp: NaiveDateTime) -> LogRow {
+    let mut row = LogRow {
+        timestamp,
+        backfilled: true,
+        ..prev.clone()
+    };
+    if prev.state == ModeType::Running {
+        let elapsed = (timestamp - prev.timestamp).num_minutes();
+        row.time_remaining = (prev.time_remaining - elapsed).max(0);
+        if row.time_remaining == 0 {
+            row.state = ModeType::Idle;
+        }
+    }
+    row
+}
+
+fn carry_backward(next: &LogRow, timestamp: NaiveDateTime) -> LogRow {
+    let mut row = LogRow {
+        timestamp,
+        backfilled: true,
+        ..next.clone()
+    };
+    if next.state == ModeType::Running {
+        row.time_remaining = next.time_remaining + (next.timestamp - timestamp).num_minutes();
+    }
+    row
+}
+
+async fn load_rows(
+    conn: &mut MiddlewarePoolConnection,
+    pep_id: &str,
+    since: NaiveDateTime,
+) -> Result<Vec<LogRow>> {
+    let query = QueryAndParams::new(
+        SELECT_ROWS_QUERY,
+        vec![
+            RowValues::Text(pep_id.to_string()),
+            RowValues::Timestamp(since),
+        ],
+    );
+    let result = conn.query(&query.query).params(&query.params).select().await?;
+
+    let mut rows = vec![];
+    for row in result.results.iter() {
+        let column = |index: usize| {
+            row.get_by_index(index)
+                .ok_or_eyre(format!("Failed to get row by index {index}"))
+        };
+        rows.push(LogRow {
+            timestamp: value_as_timestamp(column(0)?).ok_or_eyre("Invalid timestamp")?,
+            time_remaining: value_as_int(column(1)?).ok_or_eyre("Invalid time_remaining")?,
+            not_available_reason: column(2)?.as_text().map(str::to_string),
+            door_closed: value_as_bool(column(3)?).ok_or_eyre("Invalid door_closed")?,
+            state: ModeType::from(
+                column(4)?
+                    .as_text()
+                    .ok_or_eyre("Invalid state")?
+                    .to_string(),
+            ),
+            machine_settings: column(5)?.clone(),
+            backfilled: value_as_bool(column(6)?).ok_or_eyre("Invalid backfilled")?,
+        });
+    }
+    Ok(rows)
+}
+
+async fn insert_row(conn: &mut MiddlewarePoolConnection, pep_id: &str, row: &LogRow) -> Result<()> {
+    let query = QueryAndParams::new(
+        INSERT_BACKFILL_QUERY,
+        vec![
+            RowValues::Text(pep_id.to_string()),             // pep_id
+            RowValues::Timestamp(row.timestamp),             // timestamp
+            RowValues::Int(row.time_remaining),              // time_remaining
+            optional_text(&row.not_available_reason),        // not_available_reason
+            RowValues::Bool(row.door_closed),                // door_closed
+            RowValues::Text(row.state.as_str().to_string()), // state
+            row.machine_settings.clone(),                    // machine_settings
+            RowValues::Bool(true),                           // backfilled
+        ],
+    );
+    conn.query(&query.query).params(&query.params).dml().await?;
+    Ok(())
+}
+
+async fn update_row(conn: &mut MiddlewarePoolConnection, pep_id: &str, row: &LogRow) -> Result<()> {
+    let query = QueryAndParams::new(
+        UPDATE_BACKFILL_QUERY,
+        vec![
+            RowValues::Int(row.time_remaining),              // time_remaining
+            optional_text(&row.not_available_reason),        // not_available_reason
+            RowValues::Bool(row.door_closed),                // door_closed
+            RowValues::Text(row.state.as_str().to_string()), // state
+            RowValues::Text(pep_id.to_string()),             // pep_id
+            RowValues::Timestamp(row.timestamp),             // timestamp
+            RowValues::Bool(true),                           // backfilled
+        ],
+    );
+    conn.query(&query.query).params(&query.params).dml().await?;
+    Ok(())
+}
+
+async fn delete_row(
+    conn: &mut MiddlewarePoolConnection,
+    pep_id: &str,
+    timestamp: NaiveDateTime,
+) -> Result<()> {
+    let query = QueryAndParams::new(
+        DELETE_BACKFILL_QUERY,
+        vec![
+            RowValues::Text(pep_id.to_string()),
+            RowValues::Timestamp(timestamp),
+            RowValues::Bool(true),
+        ],
+    );
+    conn.query(&query.query).params(&query.params).dml().await?;
+    Ok(())
+}
+
+const SELECT_PEPS_QUERY: &str = "SELECT DISTINCT pep_id FROM laundrylog WHERE \"timestamp\" >= $1";
+const SELECT_ROWS_QUERY: &str = "SELECT \"timestamp\", time_remaining, not_available_reason, door_closed, state, machine_settings, backfilled \
+    FROM laundrylog WHERE pep_id = $1 AND \"timestamp\" >= $2 ORDER BY \"timestamp\"";
+
+const INSERT_BACKFILL_QUERY: &str = "INSERT INTO laundrylog(pep_id, \"timestamp\", time_remaining, not_available_reason, door_closed, state, machine_settings, backfilled) \
+    VALUES ($1, $2, $3, $4, $5, $6, $7, $8)";
+// backfilled is checked so a real row is never overwritten
+const UPDATE_BACKFILL_QUERY: &str = "UPDATE laundrylog SET time_remaining = $1, not_available_reason = $2, door_closed = $3, state = $4 \
+    WHERE pep_id = $5 AND \"timestamp\" = $6 AND backfilled = $7";
+const DELETE_BACKFILL_QUERY: &str =
+    "DELETE FROM laundrylog WHERE pep_id = $1 AND \"timestamp\" = $2 AND backfilled = $3";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::at;
+
+    fn config() -> BackfillConfig {
+        BackfillConfig {
+            interval: 300,
+            step: 60,
+            gap_after: 180,
+            max_gap: 360,
+            lookback: 24,
+        }
+    }
+
+    fn row(seconds: i64, state: ModeType, time_remaining: i64) -> LogRow {
+        LogRow {
+            timestamp: at(seconds),
+            time_remaining,
+            not_available_reason: None,
+            door_closed: true,
+            state,
+            machine_settings: RowValues::Null,
+            backfilled: false,
+        }
+    }
+
+    #[test]
+    fn carry_forward_counts_down_to_idle() {
+        let prev = row(0, ModeType::Running, 3);
+
+        let running = carry_forward(&prev, at(120));
+        assert_eq!(running.state, ModeType::Running);
+        assert_eq!(running.time_remaining, 1);
+        assert!(running.backfilled);
+
+        let done = carry_forward(&prev, at(300));
+        assert_eq!(done.state, ModeType::Idle);
+        assert_eq!(done.time_remaining, 0);
+    }
+
+    #[test]
+    fn carry_forward_keeps_other_states() {
+        let prev = row(0, ModeType::PressStart, 0);
+        let row = carry_forward(&prev, at(1800));
+        assert_eq!(row.state, ModeType::PressStart);
+        assert_eq!(row.timestamp, at(1800));
+    }
+
+    #[test]
+    fn synthesize_interpolates_same_cycle() {
+        let prev = row(0, ModeType::Running, 30);
+        let next = row(600, ModeType::Running, 20);
+        let row = synthesize(&prev, Some(&next), at(300));
+        assert_eq!(row.state, ModeType::Running);
+        assert_eq!(row.time_remaining, 25);
+    }
+
+    #[test]
+    fn synthesize_new_cycle_starts_halfway() {
+        // Predicted end after 5 minutes, the new cycle is placed halfway to the next row
+        let prev = row(0, ModeType::Running, 5);
+        let next = row(1200, ModeType::Running, 40);
+
+        let counting = synthesize(&prev, Some(&next), at(180));
+        assert_eq!(counting.state, ModeType::Running);
+        assert_eq!(counting.time_remaining, 2);
+
+        let finished = synthesize(&prev, Some(&next), at(480));
+        assert_eq!(finished.state, ModeType::Idle);
+
+        let started = synthesize(&prev, Some(&next), at(900));
+        assert_eq!(started.state, ModeType::Running);
+        assert_eq!(started.time_remaining, 45);
+    }
+
+    #[test]
+    fn synthesize_state_change_at_predicted_end() {
+        let prev = row(0, ModeType::Running, 5);
+        let next = row(1200, ModeType::PressStart, 0);
+        assert_eq!(
+            synthesize(&prev, Some(&next), at(240)).state,
+            ModeType::Running
+        );
+        assert_eq!(
+            synthesize(&prev, Some(&next), at(360)).state,
+            ModeType::PressStart
+        );
+    }
+
+    #[test]
+    fn plan_fills_gap_between_rows() {
+        let rows = [row(0, ModeType::Idle, 0), row(300, ModeType::Idle, 0)];
+        let real: Vec<&LogRow> = rows.iter().collect();
+        let (desired, ranges) = plan(&config(), &real, at(300));
+
+        let timestamps: Vec<_> = desired.iter().map(|v| v.timestamp).collect();
+        assert_eq!(timestamps, vec![at(60), at(120), at(180), at(240)]);
+        assert!(desired.iter().all(|v| v.backfilled));
+        assert_eq!(ranges, vec![(at(0), at(300))]);
+    }
+
+    #[test]
+    fn plan_skips_short_and_long_gaps() {
+        let rows = [
+            row(0, ModeType::Idle, 0),
+            row(120, ModeType::Idle, 0),
+            row(120 + 7 * 3600, ModeType::Idle, 0),
+        ];
+        let real: Vec<&LogRow> = rows.iter().collect();
+        let (desired, ranges) = plan(&config(), &real, at(120 + 7 * 3600));
+        assert!(desired.is_empty());
+        assert!(ranges.is_empty());
+    }
+
+    #[test]
+    fn plan_trails_now_by_a_step() {
+        let rows = [row(0, ModeType::Running, 30)];
+        let real: Vec<&LogRow> = rows.iter().collect();
+        let (desired, ranges) = plan(&config(), &real, at(600));
+
+        assert_eq!(desired.len(), 9);
+        assert_eq!(desired.last().map(|v| v.timestamp), Some(at(540)));
+        assert_eq!(desired.last().map(|v| v.time_remaining), Some(21));
+        assert_eq!(ranges, vec![(at(0), at(600))]);
+    }
+
+    #[test]
+    fn plan_stops_at_max_gap() {
+        let rows = [row(0, ModeType::Idle, 0)];
+        let real: Vec<&LogRow> = rows.iter().collect();
+        let (desired, _) = plan(&config(), &real, at(10 * 3600));
+        assert_eq!(desired.len(), 360);
+        assert_eq!(desired.last().map(|v| v.timestamp), Some(at(6 * 3600)));
+    }
+}
@@ -0,0 +1,590 @@
+use crate::db::DbType;
+use crate::logic::db::{value_as_bool, value_as_int, value_as_json, value_as_timestamp};
+use crate::models::api::ModeType;
+use crate::models::config::CycleConfig;
+use crate::utils::prelude::*;
+use chrono::{NaiveDateTime, TimeDelta, Utc};
+use color_eyre::eyre::OptionExt;
+use sql_middleware::{MiddlewarePoolConnection, QueryAndParams, RowValues};
+use std::collections::HashMap;
+
+/// Two running rows are the same cycle if the predicted time remaining is within this many minutes
+pub(crate) const SAME_CYCLE_TOLERANCE: i64 = 2;
+/// Polls further apart than this are treated as missed, the end of a cycle is then estimated
+const MISSED_POLL_AFTER: TimeDelta = TimeDelta::minutes(3);
+/// History is read a day at a time when resuming a pep
+const RESUME_CHUNK: TimeDelta = TimeDelta::days(1);
+
+/// A real `LaundryLog` row, only the columns cycle detection needs
+#[derive(Debug, Clone)]
+pub(crate) struct CycleRow {
+    pub(crate) timestamp: NaiveDateTime,
+    pub(crate) time_remaining: i64,
+    pub(crate) door_closed: bool,
+    pub(crate) state: ModeType,
+    pub(crate) machine_settings: Option<serde_json::Value>,
+}
+
+impl CycleRow {
+    /// Time the machine expects to finish, only meaningful while running
+    fn predicted_end(&self) -> NaiveDateTime {
+        self.timestamp + TimeDelta::minutes(self.time_remaining)
+    }
+}
+
+/// A single run of a machine, from the first `running` row until it stops
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Cycle {
+    pub(crate) start: NaiveDateTime,
+    /// Missing while the machine is still running
+    pub(crate) end: Option<NaiveDateTime>,
+    pub(crate) machine_settings: Option<serde_json::Value>,
+    /// Stopped before the time remaining ran out
+    pub(crate) interrupted: bool,
+    /// The end fell between missed polls and was taken from the time remaining
+    pub(crate) estimated_end: bool,
+    /// First time the door was seen open after the cycle ended
+    pub(crate) door_opened: Option<NaiveDateTime>,
+    /// First time the door was seen closed again, or the next cycle started
+    pub(crate) door_closed: Option<NaiveDateTime>,
+    /// Laundry was probably left inside, cleared once the door is opened again or the next cycle
+    /// starts
+    pub(crate) probably_full: bool,
+}
+
+impl Cycle {
+    fn new(row: &CycleRow) -> Self {
+        Self {
+            start: row.timestamp,
+            end: None,
+            machine_settings: row.machine_settings.clone().filter(has_settings),
+            interrupted: false,
+            estimated_end: false,
+            door_opened: None,
+            door_closed: None,
+            probably_full: false,
+        }
+    }
+
+    pub(crate) fn duration(&self) -> Option<TimeDelta> {
+        self.end.map(|end| end - self.start)
+    }
+
+    /// How long the door stayed open after the cycle
+    pub(crate) fn door_open(&self) -> Option<TimeDelta> {
+        Some(self.door_closed? - self.door_opened?)
+    }
+
+    /// Laundry is assumed left inside if the door was never opened after the cycle, or was only
+    /// opened briefly and closed again, for `left_after` minutes.
+    fn is_probably_full(&self, reopened: bool, now: NaiveDateTime, config: &CycleConfig) -> bool {
+        let Some(end) = self.end else { return false };
+        let left_after = TimeDelta::minutes(config.left_after);
+        match (self.door_opened, self.door_closed) {
+            (None, _) => now - end >= left_after,
+            (Some(opened), Some(closed)) if !reopened => {
+                closed - opened <= TimeDelta::seconds(config.brief_open)
+                    && now - closed >= left_after
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Walk state of a single pep
+#[derive(Debug, Default)]
+struct PepCycles {
+    prev: Option<CycleRow>,
+    /// Latest cycle, kept until the next one starts
+    cycle: Option<Cycle>,
+    /// The door was opened again after being closed following the latest cycle
+    reopened: bool,
+}
+
+impl PepCycles {
+    /// Advances the walk by one row, returns every cycle that changed
+    fn step(&mut self, row: CycleRow, config: &CycleConfig) -> Vec<Cycle> {
+        let mut changed = vec![];
+        let running = row.state == ModeType::Running;
+
+        if let Some(prev) = &self.prev
+            && let Some(cycle) = self.cycle.as_mut()
+        {
+            let before = cycle.clone();
+            let missed = row.timestamp - prev.timestamp > MISSED_POLL_AFTER;
+            match cycle.end {
+                // Cycles are only open while the previous row was running
+                None if running => {
+                    let expected =
+                        prev.time_remaining - (row.timestamp - prev.timestamp).num_minutes();
+                    if missed
+                        && row.time_remaining > expected + SAME_CYCLE_TOLERANCE
+                        && row.timestamp > prev.predicted_end()
+                    {
+                        // Finished and started again between polls
+                        cycle.end = Some(prev.predicted_end());
+                        cycle.estimated_end = true;
+                    } else if cycle.machine_settings.is_none() {
+                        cycle.machine_settings = row.machine_settings.clone().filter(has_settings);
+                    }
+                }
+                None => {
+                    let end = match missed {
+                        true => prev.predicted_end().clamp(prev.timestamp, row.timestamp),
+                        false => row.timestamp,
+                    };
+                    cycle.end = Some(end);
+                    cycle.estimated_end = missed;
+                    cycle.interrupted =
+                        prev.predicted_end() - end > TimeDelta::minutes(SAME_CYCLE_TOLERANCE);
+                    if !row.door_closed {
+                        cycle.door_opened = Some(row.timestamp);
+                    }
+                }
+                Some(_) => {
+                    match (cycle.door_opened, cycle.door_closed) {
+                        (None, _) if !row.door_closed => cycle.door_opened = Some(row.timestamp),
+                        // The next cycle starting also means the door was closed again
+                        (Some(_), None) if row.door_closed || running => {
+                            cycle.door_closed = Some(row.timestamp)
+                        }
+                        // Opened again after being closed, assume it was emptied this time
+                        (Some(_), Some(_)) if !row.door_closed => self.reopened = true,
+                        _ => {}
+                    }
+                    cycle.probably_full =
+                        !running && cycle.is_probably_full(self.reopened, row.timestamp, config);
+                }
+            }
+            if *cycle != before {
+                changed.push(cycle.clone());
+            }
+        }
+
+        if running && self.cycle.as_ref().is_none_or(|v| v.end.is_some()) {
+            let cycle = Cycle::new(&row);
+            changed.push(cycle.clone());
+            self.cycle = Some(cycle);
+            self.reopened = false;
+        }
+
+        self.prev = Some(row);
+        changed
+    }
+}
+
+/// Derives `MachineCycles` from `LaundryLog` as polls arrive. The walk state of each pep is kept in
+/// memory and resumed from the database the first time a pep is seen.
+pub(crate) struct CycleTracker {
+    db_type: DbType,
+    config: CycleConfig,
+    peps: HashMap<String, PepCycles>,
+}
+
+impl CycleTracker {
+    pub(crate) fn new(db_type: DbType, config: CycleConfig) -> Self {
+        Self {
+            db_type,
+            config,
+            peps: HashMap::new(),
+        }
+    }
+
+    /// Feeds a freshly inserted row for `pep_id`
+    #[instrument(skip_all, fields(pep_id = pep_id))]
+    pub(crate) async fn observe(
+        &mut self,
+        conn: &mut MiddlewarePoolConnection,
+        pep_id: &str,
+        row: CycleRow,
+    ) -> Result<()> {
+        let result = self.observe_inner(conn, pep_id, row).await;
+        if result.is_err() {
+            // Memory may be ahead of the database, resume on the next poll
+            self.peps.remove(pep_id);
+        }
+        result
+    }
+
+    async fn observe_inner(
+        &mut self,
+        conn: &mut MiddlewarePoolConnection,
+        pep_id: &str,
+        row: CycleRow,
+    ) -> Result<()> {
+        if !self.peps.contains_key(pep_id) {
+            let pep = resume(self.db_type, &self.config, conn, pep_id, row.timestamp).await?;
+            self.peps.insert(pep_id.to_string(), pep);
+        }
+        let pep = self.peps.get_mut(pep_id).ok_or_eyre("Missing pep")?;
+
+        // Rows already walked while resuming
+        if pep
+            .prev
+            .as_ref()
+            .is_some_and(|v| v.timestamp >= row.timestamp)
+        {
+            return Ok(());
+        }
+        for cycle in pep.step(row, &self.config) {
+            upsert_cycle(self.db_type, conn, pep_id, &cycle).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Rebuilds the walk state of a pep by walking its history up to `until`, starting at its latest
+/// cycle. Peps without cycles start at their first `running` row, every cycle found is written.
+/// Runs inline on the first poll of a pep, so at most `resume_lookback` hours are walked.
+async fn resume(
+    db_type: DbType,
+    config: &CycleConfig,
+    conn: &mut MiddlewarePoolConnection,
+    pep_id: &str,
+    until: NaiveDateTime,
+) -> Result<PepCycles> {
+    let mut pep = PepCycles::default();
+    let floor = until - TimeDelta::hours(config.resume_lookback);
+    let from = match first_timestamp(
+        conn,
+        SELECT_LATEST_CYCLE_QUERY,
+        vec![RowValues::Text(pep_id.to_string())],
+    )
+    .await?
+    {
+        Some(v) => Some(v),
+        None => {
+            first_timestamp(
+                conn,
+                SELECT_FIRST_RUNNING_QUERY,
+                vec![
+                    RowValues::Text(pep_id.to_string()),
+                    RowValues::Bool(false),
+                    RowValues::Timestamp(floor),
+                ],
+            )
+            .await?
+        }
+    };
+    // A latest cycle older than the lookback is left as it is, the walk starts without it
+    let Some(mut from) = from.map(|v| v.max(floor)) else {
+        return Ok(pep);
+    };
+    debug!(%from, "Resuming cycle detection");
+
+    let mut written = 0;
+    while from <= until {
+        let to = (from + RESUME_CHUNK).min(until + TimeDelta::seconds(1));
+        for row in load_rows(conn, pep_id, from, to).await? {
+            for cycle in pep.step(row, config) {
+                upsert_cycle(db_type, conn, pep_id, &cycle).await?;
+                written += 1;
+            }
+        }
+        from = to;
+    }
+    debug!(written, "Resumed cycle detection");
+    Ok(pep)
+}
+
+/// Settings are sent as an object of nulls by machines that do not report them
+fn has_settings(value: &serde_json::Value) -> bool {
+    match value {
+        serde_json::Value::Object(v) => v.values().any(|v| !v.is_null()),
+        serde_json::Value::Null => false,
+        _ => true,
+    }
+}
+
+fn optional_timestamp(value: Option<NaiveDateTime>) -> RowValues {
+    match value {
+        Some(v) => RowValues::Timestamp(v),
+        None => RowValues::Null,
+    }
+}
+
+fn optional_seconds(value: Option<TimeDelta>) -> RowValues {
+    match value {
+        Some(v) => RowValues::Int(v.num_seconds()),
+        None => RowValues::Null,
+    }
+}
+
+async fn first_timestamp(
+    conn: &mut MiddlewarePoolConnection,
+    query: &str,
+    params: Vec<RowValues>,
+) -> Result<Option<NaiveDateTime>> {
+    let result = conn.query(query).params(&params).select().await?;
+    Ok(result
+        .results
+        .first()
+        .and_then(|row| row.get_by_index(0))
+        .and_then(value_as_timestamp))
+}
+
+async fn load_rows(
+    conn: &mut MiddlewarePoolConnection,
+    pep_id: &str,
+    from: NaiveDateTime,
+    to: NaiveDateTime,
+) -> Result<Vec<CycleRow>> {
+    let query = QueryAndParams::new(
+        SELECT_ROWS_QUERY,
+        vec![
+            RowValues::Text(pep_id.to_string()),
+            RowValues::Timestamp(from),
+            RowValues::Timestamp(to),
+            RowValues::Bool(false),
+        ],
+    );
+    let result = conn
+        .query(&query.query)
+        .params(&query.params)
+        .select()
+        .await?;
+
+    let mut rows = vec![];
+    for row in result.results.iter() {
+        let column = |index: usize| {
+            row.get_by_index(index)
+                .ok_or_eyre(format!("Failed to get row by index {index}"))
+        };
+        rows.push(CycleRow {
+            timestamp: value_as_timestamp(column(0)?).ok_or_eyre("Invalid timestamp")?,
+            time_remaining: value_as_int(column(1)?).ok_or_eyre("Invalid time_remaining")?,
+            door_closed: value_as_bool(column(2)?).ok_or_eyre("Invalid door_closed")?,
+            state: ModeType::from(
+                column(3)?
+                    .as_text()
+                    .ok_or_eyre("Invalid state")?
+                    .to_string(),
+            ),
+            machine_settings: value_as_json(column(4)?),
+        });
+    }
+    Ok(rows)
+}
+
+async fn upsert_cycle(
+    db_type: DbType,
+    conn: &mut MiddlewarePoolConnection,
+    pep_id: &str,
+    cycle: &Cycle,
+) -> Result<()> {
+    let query = QueryAndParams::new(
+        upsert_cycle_query(db_type),
+        vec![
+            RowValues::Text(pep_id.to_string()), // pep_id
+            RowValues::Timestamp(cycle.start),   // cycle_start
+            optional_timestamp(cycle.end),       // cycle_end
+            optional_seconds(cycle.duration()),  // duration_seconds
+            match &cycle.machine_settings {
+                Some(v) => RowValues::JSON(v.clone()),
+                None => RowValues::Null,
+            }, // machine_settings
+            RowValues::Bool(cycle.interrupted),  // interrupted
+            RowValues::Bool(cycle.estimated_end), // estimated_end
+            optional_timestamp(cycle.door_opened), // door_opened
+            optional_seconds(cycle.door_open()), // door_open_seconds
+            RowValues::Bool(cycle.probably_full), // probably_full
+            RowValues::Timestamp(Utc::now().naive_utc()), // updated_on
+        ],
+    );
+    conn.query(&query.query).params(&query.params).dml().await?;
+    Ok(())
+}
+
+/// `ON CONFLICT` is shared by postgres and sqlite, mssql requires a `MERGE`
+fn upsert_cycle_query(db_type: DbType) -> &'static str {
+    match db_type {
+        DbType::Mssql => MSSQL_UPSERT_CYCLE_QUERY,
+        _ => UPSERT_CYCLE_QUERY,
+    }
+}
+
+const SELECT_LATEST_CYCLE_QUERY: &str =
+    "SELECT MAX(cycle_start) FROM machinecycles WHERE pep_id = $1";
+const SELECT_FIRST_RUNNING_QUERY: &str = "SELECT MIN(\"timestamp\") FROM laundrylog \
+    WHERE pep_id = $1 AND state = 'running' AND backfilled = $2 AND \"timestamp\" >= $3";
+const SELECT_ROWS_QUERY: &str = "SELECT \"timestamp\", time_remaining, door_closed, state, machine_settings \
+    FROM laundrylog WHERE pep_id = $1 AND \"timestamp\" >= $2 AND \"timestamp\" < $3 AND backfilled = $4 \
+    ORDER BY \"timestamp\"";
+
+const UPSERT_CYCLE_QUERY: &str = "INSERT INTO machinecycles(pep_id, cycle_start, cycle_end, duration_seconds, machine_settings, interrupted, estimated_end, door_opened, door_open_seconds, probably_full, updated_on) \
+    VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11) \
+    ON CONFLICT (pep_id, cycle_start) DO UPDATE SET \
+    cycle_end = excluded.cycle_end, duration_seconds = excluded.duration_seconds, \
+    machine_settings = excluded.machine_settings, interrupted = excluded.interrupted, \
+    estimated_end = excluded.estimated_end, door_opened = excluded.door_opened, \
+    door_open_seconds = excluded.door_open_seconds, probably_full = excluded.probably_full, \
+    updated_on = excluded.updated_on";
+const MSSQL_UPSERT_CYCLE_QUERY: &str = "MERGE machinecycles AS target \
+    USING (SELECT $1 AS pep_id, $2 AS cycle_start, $3 AS cycle_end, $4 AS duration_seconds, $5 AS machine_settings, \
+    $6 AS interrupted, $7 AS estimated_end, $8 AS door_opened, $9 AS door_open_seconds, $10 AS probably_full, \
+    $11 AS updated_on) AS source \
+    ON target.pep_id = source.pep_id AND target.cycle_start = source.cycle_start \
+    WHEN MATCHED THEN UPDATE SET \
+    cycle_end = source.cycle_end, duration_seconds = source.duration_seconds, \
+    machine_settings = source.machine_settings, interrupted = source.interrupted, \
+    estimated_end = source.estimated_end, door_opened = source.door_opened, \
+    door_open_seconds = source.door_open_seconds, probably_full = source.probably_full, \
+    updated_on = source.updated_on \
+    WHEN NOT MATCHED THEN INSERT (pep_id, cycle_start, cycle_end, duration_seconds, machine_settings, interrupted, estimated_end, door_opened, door_open_seconds, probably_full, updated_on) \
+    VALUES (source.pep_id, source.cycle_start, source.cycle_end, source.duration_seconds, source.machine_settings, \
+    source.interrupted, source.estimated_end, source.door_opened, source.door_open_seconds, source.probably_full, source.updated_on);";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::at;
+
+    fn row(seconds: i64, state: ModeType, time_remaining: i64, door_closed: bool) -> CycleRow {
+        CycleRow {
+            timestamp: at(seconds),
+            time_remaining,
+            door_closed,
+            state,
+            machine_settings: None,
+        }
+    }
+
+    fn running(seconds: i64, time_remaining: i64) -> CycleRow {
+        row(seconds, ModeType::Running, time_remaining, true)
+    }
+
+    fn idle(seconds: i64, door_closed: bool) -> CycleRow {
+        row(seconds, ModeType::Idle, 0, door_closed)
+    }
+
+    /// Steps through `rows`, returns every cycle that changed
+    fn walk(pep: &mut PepCycles, rows: Vec<CycleRow>) -> Vec<Cycle> {
+        let config = CycleConfig::default();
+        rows.into_iter()
+            .flat_map(|row| pep.step(row, &config))
+            .collect()
+    }
+
+    /// A three minute cycle that ends with the door closed at 180 seconds
+    fn finished() -> PepCycles {
+        let mut pep = PepCycles::default();
+        walk(
+            &mut pep,
+            vec![
+                running(0, 3),
+                running(60, 2),
+                running(120, 1),
+                idle(180, true),
+            ],
+        );
+        pep
+    }
+
+    #[test]
+    fn step_ends_cycle_when_running_stops() {
+        let pep = finished();
+        let cycle = pep.cycle.as_ref();
+        assert_eq!(cycle.map(|v| v.start), Some(at(0)));
+        assert_eq!(cycle.and_then(|v| v.end), Some(at(180)));
+        assert!(cycle.is_some_and(|v| !v.interrupted && !v.estimated_end && !v.probably_full));
+    }
+
+    #[test]
+    fn step_flags_interrupted_cycle() {
+        let mut pep = PepCycles::default();
+        walk(&mut pep, vec![running(0, 30), idle(60, true)]);
+        let cycle = pep.cycle.as_ref();
+        assert_eq!(cycle.and_then(|v| v.end), Some(at(60)));
+        assert!(cycle.is_some_and(|v| v.interrupted));
+    }
+
+    #[test]
+    fn step_estimates_end_between_missed_polls() {
+        let mut pep = PepCycles::default();
+        walk(&mut pep, vec![running(0, 5), idle(600, true)]);
+        let cycle = pep.cycle.as_ref();
+        assert_eq!(cycle.and_then(|v| v.end), Some(at(300)));
+        assert!(cycle.is_some_and(|v| v.estimated_end && !v.interrupted));
+    }
+
+    #[test]
+    fn step_splits_cycles_restarted_between_polls() {
+        let mut pep = PepCycles::default();
+        let changed = walk(&mut pep, vec![running(0, 5), running(1200, 40)]);
+
+        let starts: Vec<_> = changed.iter().map(|v| (v.start, v.end)).collect();
+        assert_eq!(
+            starts,
+            vec![(at(0), None), (at(0), Some(at(300))), (at(1200), None)]
+        );
+        assert!(changed[1].estimated_end);
+    }
+
+    #[test]
+    fn step_measures_door_open() {
+        let mut pep = finished();
+        walk(&mut pep, vec![idle(240, false), idle(300, true)]);
+        let cycle = pep.cycle.as_ref();
+        assert_eq!(cycle.and_then(|v| v.door_opened), Some(at(240)));
+        assert_eq!(
+            cycle.and_then(Cycle::door_open),
+            Some(TimeDelta::seconds(60))
+        );
+    }
+
+    #[test]
+    fn probably_full_when_door_never_opened() {
+        let mut pep = finished();
+        walk(&mut pep, vec![idle(180 + 14 * 60, true)]);
+        assert!(pep.cycle.as_ref().is_some_and(|v| !v.probably_full));
+
+        walk(&mut pep, vec![idle(180 + 15 * 60, true)]);
+        assert!(pep.cycle.as_ref().is_some_and(|v| v.probably_full));
+    }
+
+    #[test]
+    fn probably_full_after_brief_open() {
+        // Closed again after exactly `brief_open` seconds
+        let mut brief = finished();
+        walk(
+            &mut brief,
+            vec![idle(240, false), idle(330, true), idle(330 + 15 * 60, true)],
+        );
+        assert!(brief.cycle.as_ref().is_some_and(|v| v.probably_full));
+
+        let mut emptied = finished();
+        walk(
+            &mut emptied,
+            vec![idle(240, false), idle(331, true), idle(331 + 15 * 60, true)],
+        );
+        assert!(emptied.cycle.as_ref().is_some_and(|v| !v.probably_full));
+    }
+
+    #[test]
+    fn probably_full_cleared_when_reopened() {
+        let mut pep = finished();
+        walk(
+            &mut pep,
+            vec![
+                idle(240, false),
+                idle(270, true),
+                idle(300, false),
+                idle(300 + 15 * 60, true),
+            ],
+        );
+        assert!(pep.reopened);
+        assert!(pep.cycle.as_ref().is_some_and(|v| !v.probably_full));
+    }
+
+    #[test]
+    fn probably_full_cleared_by_next_cycle() {
+        let mut pep = finished();
+        walk(&mut pep, vec![idle(180 + 15 * 60, true)]);
+        let changed = walk(&mut pep, vec![running(180 + 16 * 60, 30)]);
+
+        assert_eq!(changed.len(), 2);
+        assert_eq!(changed[0].start, at(0));
+        assert!(!changed[0].probably_full);
+        assert_eq!(changed[1].start, at(180 + 16 * 60));
+    }
+}
@@ -1,25 +1,56 @@
-use crate::models::api::{ApiLocation, DbLocation, DbRoom};
-use crate::models::config::ApiConfig;
-use crate::types::{Db2HttpMessage, Db2HttpSender, Http2DbMessage, Http2DbReceiver};
+use crate::db::{Connection, DbType, Pool};
+use crate::logic::cycles::{CycleRow, CycleTracker};
+use crate::logic::events::StateFeed;
+use crate::logic::health::{Controller, Health};
+use crate::models::api::{ApiLocation, DbLocation, DbRoom, Machine, decode_machine_list};
+use crate::logic::http::error_kind;
+use crate::logic::metrics::{
+    CHANNEL_DEPTH, DB_WRITE_DURATION, DB_WRITE_FAILURES, KNOWN_MODES, MACHINES, ROOM_LAST_SUCCESS,
+    ROWS_WRITTEN,
+};
+use crate::models::config::{ApiConfig, CycleConfig, PayloadStorage};
+use crate::pep::PhysicalEndpointId;
+use crate::logic::notify::report;
+use crate::types::{
+    ApiRequest, Db2HttpMessage, Db2HttpSender, Http2DbMessage, Http2DbReceiver, NotifyMessage,
+    NotifySender, StateSender,
+};
 use crate::utils::prelude::*;
+use chrono::NaiveDateTime;
+use chrono_tz::Tz;
 use color_eyre::eyre::OptionExt;
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use metrics::{counter, gauge, histogram};
 use sql_middleware::{
-    ConfigAndPool, MiddlewarePoolConnection, QueryAndParams, RowValues, execute_batch,
+    MiddlewarePoolConnection, QueryAndParams, RowValues, execute_batch,
 };
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
 use tokio::sync::oneshot;
 use tokio::sync::oneshot::error::RecvError;
+use tokio::time::Instant;
+use tracing::{Instrument, info_span};
 
 /// Controller for DB related tasks
 #[instrument(skip_all, fields(task_id=%id()))]
 pub(crate) async fn db_controller(
     api_config: ApiConfig,
-    pool: ConfigAndPool,
+    cycle_config: CycleConfig,
+    db_type: DbType,
+    pool: Pool,
     mut http_control_rx: Http2DbReceiver,
     db_control_tx: Db2HttpSender,
+    notify_tx: NotifySender,
+    state_tx: StateSender,
+    health: Health,
     cancel_token: CancellationToken,
 ) -> () {
     info!("Initializing DB Control task");
+    let _running = health.running(Controller::Db);
+    let payloads = api_config.payloads;
+    let mut cycles = CycleTracker::new(db_type, cycle_config);
+    let mut events = StateFeed::new(state_tx);
 
     db_precheck(
         api_config,
@@ -42,20 +73,384 @@ pub(crate) async fn db_controller(
                 }
             },
         };
+        gauge!(CHANNEL_DEPTH, "channel" => "http2db").set(http_control_rx.len() as f64);
 
-        match msg {
-            Http2DbMessage::ApiResponse(_res) => {}
-            Http2DbMessage::ApiError(_err) => unimplemented!(),
+        let mut conn = match pool.get_connection().await {
+            Ok(v) => v,
+            Err(e) => {
+                error!("Failed to get db connection: {:?}", e);
+                counter!(DB_WRITE_FAILURES, "context" => "connection").increment(1);
+                report(
+                    &notify_tx,
+                    NotifyMessage::DbWriteFailed {
+                        context: "connection",
+                        error: e.to_string(),
+                    },
+                );
+                continue;
+            }
         };
+
+        // Stored under the span of the request, see `ApiRequest::span`
+        let span = info_span!(parent: &msg.request().span, "store");
+        async {
+            let (request, outcome) = match msg {
+                Http2DbMessage::ApiResponse(request, res) => {
+                    let status = Some(res.status().as_u16());
+                    let outcome = match res.bytes().await {
+                        Ok(body) => {
+                            let start = Instant::now();
+                            let inserted = db_insert(
+                                db_type,
+                                &mut conn,
+                                &mut cycles,
+                                &mut events,
+                                &request,
+                                &body,
+                            )
+                            .await;
+                            histogram!(DB_WRITE_DURATION, "table" => "laundrylog")
+                                .record(start.elapsed().as_secs_f64());
+                            // A room where every machine failed was not stored
+                            if inserted.as_ref().is_ok_and(|(written, _)| *written > 0) {
+                                health.polled(request.timestamp);
+                                let location_id = request.location_id.clone().unwrap_or_default();
+                                let room_id = request.room_id.clone().unwrap_or_default();
+                                gauge!(
+                                    ROOM_LAST_SUCCESS,
+                                    "location_id" => location_id,
+                                    "room_id" => room_id
+                                )
+                                .set(request.timestamp.and_utc().timestamp() as f64);
+                            }
+
+                            let error = match inserted {
+                                Ok((_, 0)) => None,
+                                Ok((_, failed)) => {
+                                    counter!(DB_WRITE_FAILURES, "context" => "laundry_log")
+                                        .increment(failed as u64);
+                                    report(
+                                        &notify_tx,
+                                        NotifyMessage::DbWriteFailed {
+                                            context: "laundry_log",
+                                            error: format!("{failed} machines failed to insert"),
+                                        },
+                                    );
+                                    None
+                                }
+                                Err(e) => {
+                                    error!("Failed to insert api response: {:?}", e);
+                                    Some(("decode", e.to_string()))
+                                }
+                            };
+                            ApiOutcome {
+                                status,
+                                error,
+                                body: Some(body.to_vec()),
+                            }
+                        }
+                        Err(e) => ApiOutcome::from_error(status, &e),
+                    };
+                    (request, outcome)
+                }
+                Http2DbMessage::ApiError(request, err) => {
+                    let status = err.status().map(|v| v.as_u16());
+                    (request, ApiOutcome::from_error(status, &err))
+                }
+                Http2DbMessage::ApiRecord {
+                    request,
+                    status,
+                    error,
+                } => {
+                    let outcome = match error {
+                        Some(e) => ApiOutcome::from_error(status, &e),
+                        None => ApiOutcome {
+                            status,
+                            error: None,
+                            body: None,
+                        },
+                    };
+                    (request, outcome)
+                }
+            };
+
+            let start = Instant::now();
+            let inserted = insert_api_log(&mut conn, &request, outcome, payloads).await;
+            histogram!(DB_WRITE_DURATION, "table" => "apilog")
+                .record(start.elapsed().as_secs_f64());
+            if let Err(e) = inserted {
+                error!("Failed to insert api log: {:?}", e);
+                counter!(DB_WRITE_FAILURES, "context" => "api_log").increment(1);
+                report(
+                    &notify_tx,
+                    NotifyMessage::DbWriteFailed {
+                        context: "api_log",
+                        error: e.to_string(),
+                    },
+                );
+            }
+        }
+        .instrument(span)
+        .await;
     }
 
     // cleanup
 }
 
-// Attempt to insert into db, if a part doesn't exist yield and message parent.
-// Wait for resume to re-attempt insert
+/// Result of a single api request, written into `ApiLog`
+struct ApiOutcome {
+    status: Option<u16>,
+    /// Error kind and message
+    error: Option<(&'static str, String)>,
+    body: Option<Vec<u8>>,
+}
+
+impl ApiOutcome {
+    fn from_error(status: Option<u16>, err: &reqwest::Error) -> Self {
+        Self {
+            status,
+            error: Some((error_kind(err), err.to_string())),
+            body: None,
+        }
+    }
+}
+
 #[instrument(skip_all)]
-async fn db_insert(conn: MiddlewarePoolConnection, control_tx: Db2HttpSender) -> () {}
+async fn insert_api_log(
+    conn: &mut MiddlewarePoolConnection,
+    request: &ApiRequest,
+    outcome: ApiOutcome,
+    payloads: PayloadStorage,
+) -> Result<()> {
+    let (body, body_encoding) = match (outcome.body, payloads) {
+        (None, _) | (Some(_), PayloadStorage::Off) => (RowValues::Null, RowValues::Null),
+        (Some(body), PayloadStorage::Raw) => {
+            (RowValues::Blob(body), RowValues::Text("identity".to_string()))
+        }
+        (Some(body), PayloadStorage::Gzip) => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&body)?;
+            (
+                RowValues::Blob(encoder.finish()?),
+                RowValues::Text("gzip".to_string()),
+            )
+        }
+    };
+    let (error_kind, error) = match outcome.error {
+        Some((kind, message)) => (RowValues::Text(kind.to_string()), RowValues::Text(message)),
+        None => (RowValues::Null, RowValues::Null),
+    };
+
+    let query = QueryAndParams::new(
+        INSERT_API_LOG_QUERY,
+        vec![
+            RowValues::Timestamp(request.timestamp),                       // timestamp
+            RowValues::Text(request.url.clone()),                          // url
+            optional_text(&request.location_id),                           // location_id
+            optional_text(&request.room_id),                               // room_id
+            match outcome.status {
+                Some(v) => RowValues::Int(v.into()),
+                None => RowValues::Null,
+            }, // status
+            RowValues::Int(i64::try_from(request.latency.as_millis())?), // latency_ms
+            error_kind,                                                    // error_kind
+            error,                                                         // error
+            body,                                                          // body
+            body_encoding,                                                 // body_encoding
+        ],
+    );
+    conn.query(&query.query).params(&query.params).dml().await?;
+    counter!(ROWS_WRITTEN, "table" => "apilog").increment(1);
+    Ok(())
+}
+
+/// Decodes a machines response and writes each machine into `Machines`, `PhysicalEndpoint` and
+/// `LaundryLog`, then advances cycle detection and publishes state changes. Every row shares the
+/// request timestamp with its `ApiLog` row. Returns the number of rows written and of machines that
+/// failed to insert.
+#[instrument(skip_all)]
+async fn db_insert(
+    db_type: DbType,
+    conn: &mut MiddlewarePoolConnection,
+    cycles: &mut CycleTracker,
+    events: &mut StateFeed,
+    request: &ApiRequest,
+    body: &[u8],
+) -> Result<(usize, usize)> {
+    let machines = decode_machine_list(serde_json::from_slice(body)?);
+    let timestamp = request.timestamp;
+    debug!("Inserting {} machines", machines.len());
+
+    let mut written = 0;
+    let mut failed = 0;
+    let mut modes: HashMap<&str, u64> = KNOWN_MODES.iter().map(|v| (*v, 0)).collect();
+    for machine in machines.iter() {
+        *modes.entry(machine.mode.as_str()).or_default() += 1;
+        // A single bad machine should not drop the rest of the room
+        let pep_id = match insert_machine(db_type, conn, machine, timestamp).await {
+            Ok(v) => v,
+            Err(e) => {
+                error!(machine_id = %machine.opaque_id, "failed to insert machine: {:?}", e);
+                failed += 1;
+                continue;
+            }
+        };
+        counter!(ROWS_WRITTEN, "table" => "laundrylog").increment(1);
+        written += 1;
+        events.observe(&pep_id, machine, timestamp);
+
+        let row = CycleRow {
+            timestamp,
+            time_remaining: machine.time_remaining.unwrap_or(0).into(),
+            door_closed: machine.door_closed,
+            state: machine.mode.clone(),
+            machine_settings: serde_json::to_value(&machine.settings).ok(),
+        };
+        if let Err(e) = cycles.observe(conn, &pep_id, row).await {
+            // The log row is stored, cycles are resumed from it on the next poll
+            error!(pep_id, "failed to update cycles: {:?}", e);
+        }
+    }
+
+    let location_id = request.location_id.clone().unwrap_or_default();
+    let room_id = request.room_id.clone().unwrap_or_default();
+    for (mode, count) in modes {
+        gauge!(
+            MACHINES,
+            "location_id" => location_id.clone(),
+            "room_id" => room_id.clone(),
+            "mode" => mode.to_string()
+        )
+        .set(count as f64);
+    }
+    Ok((written, failed))
+}
+
+#[instrument(skip_all, fields(machine_id = %machine.opaque_id))]
+async fn insert_machine(
+    db_type: DbType,
+    conn: &mut MiddlewarePoolConnection,
+    machine: &Machine,
+    timestamp: NaiveDateTime,
+) -> Result<String> {
+    let pep_id = PhysicalEndpointId::try_from(machine)?.calculate_pep()?;
+    let machine_id = machine.opaque_id.to_string();
+
+    let upsert_machine = QueryAndParams::new(
+        upsert_machine_query(db_type),
+        vec![
+            RowValues::Text(machine_id.clone()),                   // machine_id
+            RowValues::Text(machine.qr_code_id.clone()),           // qr_code_id
+            RowValues::Text(machine.nfc_id.to_string()),           // nfc_id
+            optional_text(&machine.controller_type),               // controller_type
+            RowValues::Text(machine.r#type.as_str().to_string()), // type
+            RowValues::Text(machine.license_plate.clone()),        // license_plate
+            optional_json(&machine.capabilities),                  // capabilities
+            optional_json(&machine.pricing),                       // pricing
+            optional_bool(machine.in_service),                     // in_service
+            optional_bool(machine.free_play),                      // free_play
+            RowValues::Timestamp(timestamp),                       // first_seen
+            RowValues::Timestamp(timestamp),                       // last_seen
+        ],
+    );
+    conn.query(&upsert_machine.query)
+        .params(&upsert_machine.params)
+        .dml()
+        .await?;
+
+    let insert_pep = QueryAndParams::new(
+        insert_pep_query(db_type),
+        vec![
+            RowValues::Text(pep_id.clone()),                  // pep_id
+            RowValues::Text(machine.room_id.clone()),         // room_id
+            RowValues::Text(machine.location_id.to_string()), // location_id
+            RowValues::Text(machine_id),                      // machine_id
+            RowValues::Int(machine.sticker_number.into()),    // sticker_number
+        ],
+    );
+    conn.query(&insert_pep.query)
+        .params(&insert_pep.params)
+        .dml()
+        .await?;
+
+    let insert_log = QueryAndParams::new(
+        INSERT_LAUNDRY_LOG_QUERY,
+        vec![
+            RowValues::Text(pep_id.clone()),                            // pep_id
+            RowValues::Timestamp(timestamp),                            // timestamp
+            RowValues::Int(machine.time_remaining.unwrap_or(0).into()), // time_remaining
+            optional_text(&machine.not_available_reason),               // not_available_reason
+            RowValues::Bool(machine.door_closed),                       // door_closed
+            RowValues::Text(machine.mode.as_str().to_string()),         // state
+            RowValues::JSON(serde_json::to_value(&machine.settings)?),  // machine_settings
+            optional_bool(machine.available),                           // available
+            RowValues::Bool(machine.time_remaining.is_some()),          // time_remaining_reported
+        ],
+    );
+    conn.query(&insert_log.query)
+        .params(&insert_log.params)
+        .dml()
+        .await?;
+
+    Ok(pep_id)
+}
+
+/// Reads a timestamp column. Sqlite stores timestamps as text.
+pub(crate) fn value_as_timestamp(value: &RowValues) -> Option<NaiveDateTime> {
+    match value {
+        RowValues::Timestamp(v) => Some(*v),
+        RowValues::Text(v) => NaiveDateTime::parse_from_str(v, "%Y-%m-%d %H:%M:%S%.f")
+            .or_else(|_| NaiveDateTime::parse_from_str(v, "%Y-%m-%dT%H:%M:%S%.f"))
+            .ok(),
+        _ => None,
+    }
+}
+
+pub(crate) fn value_as_int(value: &RowValues) -> Option<i64> {
+    match value {
+        RowValues::Int(v) => Some(*v),
+        _ => None,
+    }
+}
+
+/// Reads a boolean column. Sqlite stores booleans as integers.
+pub(crate) fn value_as_bool(value: &RowValues) -> Option<bool> {
+    match value {
+        RowValues::Bool(v) => Some(*v),
+        RowValues::Int(v) => Some(*v != 0),
+        _ => None,
+    }
+}
+
+/// Reads a json column. Sqlite and mssql store json as text.
+pub(crate) fn value_as_json(value: &RowValues) -> Option<serde_json::Value> {
+    match value {
+        RowValues::JSON(v) => Some(v.clone()),
+        RowValues::Text(v) => serde_json::from_str(v).ok(),
+        _ => None,
+    }
+}
+
+pub(crate) fn optional_text(value: &Option<String>) -> RowValues {
+    match value {
+        Some(v) => RowValues::Text(v.clone()),
+        None => RowValues::Null,
+    }
+}
+
+fn optional_json(value: &Option<serde_json::Value>) -> RowValues {
+    match value {
+        Some(v) => RowValues::JSON(v.clone()),
+        None => RowValues::Null,
+    }
+}
+
+fn optional_bool(value: Option<bool>) -> RowValues {
+    match value {
+        Some(v) => RowValues::Bool(v),
+        None => RowValues::Null,
+    }
+}
 
 #[instrument(skip_all)]
 async fn db_task(conn: MiddlewarePoolConnection, control_tx: Db2HttpSender) -> () {}
@@ -63,7 +458,7 @@ async fn db_task(conn: MiddlewarePoolConnection, control_tx: Db2HttpSender) -> (
 #[instrument(skip_all)]
 async fn db_precheck(
     endpoints: ApiConfig,
-    mut conn: MiddlewarePoolConnection,
+    mut conn: Connection,
     control_tx: Db2HttpSender,
 ) -> Result<()> {
     // locations and rooms found in config
@@ -71,12 +466,18 @@ async fn db_precheck(
         let mut locs = HashSet::new();
         let mut rooms = HashSet::new();
 
-        for endpoint in endpoints.endpoints {
-            locs.insert(endpoint.location_id);
-            rooms.insert(endpoint.room_id);
+        for endpoint in endpoints.endpoints.iter() {
+            locs.insert(endpoint.location_id.clone());
+            rooms.insert(endpoint.room_id.clone());
         }
         (locs, rooms)
     };
+    // timezones found in config, endpoints of a location agree on it
+    let config_timezones: HashMap<String, String> = endpoints
+        .endpoints
+        .into_iter()
+        .filter_map(|v| Some((v.location_id, v.timezone?)))
+        .collect();
 
     // locations and rooms found in database
     let db_locations_set = get_query_as_hashset(&mut conn, MISSING_LOCATIONS_QUERY).await?;
@@ -124,13 +525,33 @@ async fn db_precheck(
             }
         };
 
+        let timezone = location_timezone(
+            &location,
+            config_timezones.get(&location),
+            recv.timezone.as_deref(),
+        );
+
         // If the location was missing from db, add to the set to insert into db
         if missing_locations.contains(&recv.location_id.to_string()) {
             found_locations.insert(DbLocation {
                 location_id: recv.location_id.to_string(),
                 description: recv.description, // TODO
                 label: recv.label,
+                timezone: timezone.unwrap_or_else(|| {
+                    warn!("No timezone for location {}, using UTC", location);
+                    "UTC".to_string()
+                }),
             });
+        } else if let Some(timezone) = timezone {
+            // Rows written before timezones were known are corrected
+            let succ = conn
+                .query(UPDATE_LOCATION_TIMEZONE_QUERY)
+                .params(&[RowValues::Text(timezone), RowValues::Text(location.clone())])
+                .dml()
+                .await;
+            if let Err(e) = succ {
+                error!("failed to update location timezone: {:?}", e)
+            }
         }
 
         let filtered_rooms = recv
@@ -156,6 +577,7 @@ async fn db_precheck(
                 RowValues::Text(loc.location_id), // location_id
                 RowValues::Null,                  // TODO: Description
                 RowValues::Text(loc.label),       // label
+                RowValues::Text(loc.timezone),    // timezone
             ],
         );
         let succ = conn.query(&query.query).params(&query.params).dml().await;
@@ -182,6 +604,28 @@ async fn db_precheck(
     Ok(())
 }
 
+/// Config wins over CSC, a timezone missing from the IANA database is never stored
+fn location_timezone(
+    location_id: &str,
+    config: Option<&String>,
+    csc: Option<&str>,
+) -> Option<String> {
+    if let Some(timezone) = config {
+        return Some(timezone.clone());
+    }
+    let timezone = csc?;
+    match timezone.parse::<Tz>() {
+        Ok(_) => Some(timezone.to_string()),
+        Err(_) => {
+            warn!(
+                "CSC sent unknown timezone {:?} for location {}",
+                timezone, location_id
+            );
+            None
+        }
+    }
+}
+
 async fn get_query_as_hashset(
     conn: &mut MiddlewarePoolConnection,
     query: &str,
@@ -209,4 +653,54 @@ const MISSING_LOCATIONS_QUERY: &str = "SELECT location_id FROM locations";
 const INSERT_ROOM_QUERY: &str =
     "INSERT INTO rooms(room_id, description, label) VALUES ($1, $2, $3)";
 const INSERT_LOCATION_QUERY: &str =
-    "INSERT INTO locations(location_id, description, label, timezone) VALUES ($1, $2, $3, 'UTC')";
+    "INSERT INTO locations(location_id, description, label, timezone) VALUES ($1, $2, $3, $4)";
+const UPDATE_LOCATION_TIMEZONE_QUERY: &str =
+    "UPDATE locations SET timezone = $1 WHERE location_id = $2 AND timezone <> $1";
+
+/// `ON CONFLICT` is shared by postgres and sqlite, mssql requires a `MERGE`
+fn upsert_machine_query(db_type: DbType) -> &'static str {
+    match db_type {
+        DbType::Mssql => MSSQL_UPSERT_MACHINE_QUERY,
+        _ => UPSERT_MACHINE_QUERY,
+    }
+}
+
+fn insert_pep_query(db_type: DbType) -> &'static str {
+    match db_type {
+        DbType::Mssql => MSSQL_INSERT_PEP_QUERY,
+        _ => INSERT_PEP_QUERY,
+    }
+}
+
+// first_seen is only written on insert, every other column follows the latest poll
+const UPSERT_MACHINE_QUERY: &str = "INSERT INTO machines(machine_id, qr_code_id, nfc_id, controller_type, type, license_plate, capabilities, pricing, in_service, free_play, first_seen, last_seen) \
+    VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12) \
+    ON CONFLICT (machine_id) DO UPDATE SET \
+    qr_code_id = excluded.qr_code_id, nfc_id = excluded.nfc_id, controller_type = excluded.controller_type, \
+    type = excluded.type, license_plate = excluded.license_plate, capabilities = excluded.capabilities, \
+    pricing = excluded.pricing, in_service = excluded.in_service, free_play = excluded.free_play, \
+    last_seen = excluded.last_seen";
+const MSSQL_UPSERT_MACHINE_QUERY: &str = "MERGE machines AS target \
+    USING (SELECT $1 AS machine_id, $2 AS qr_code_id, $3 AS nfc_id, $4 AS controller_type, $5 AS type, $6 AS license_plate, \
+    $7 AS capabilities, $8 AS pricing, $9 AS in_service, $10 AS free_play, $11 AS first_seen, $12 AS last_seen) AS source \
+    ON target.machine_id = source.machine_id \
+    WHEN MATCHED THEN UPDATE SET \
+    qr_code_id = source.qr_code_id, nfc_id = source.nfc_id, controller_type = source.controller_type, \
+    type = source.type, license_plate = source.license_plate, capabilities = source.capabilities, \
+    pricing = source.pricing, in_service = source.in_service, free_play = source.free_play, \
+    last_seen = source.last_seen \
+    WHEN NOT MATCHED THEN INSERT (machine_id, qr_code_id, nfc_id, controller_type, type, license_plate, capabilities, pricing, in_service, free_play, first_seen, last_seen) \
+    VALUES (source.machine_id, source.qr_code_id, source.nfc_id, source.controller_type, source.type, source.license_plate, \
+    source.capabilities, source.pricing, source.in_service, source.free_play, source.first_seen, source.last_seen);";
+
+const INSERT_PEP_QUERY: &str = "INSERT INTO physicalendpoint(pep_id, room_id, location_id, machine_id, sticker_number) \
+    VALUES ($1, $2, $3, $4, $5) ON CONFLICT DO NOTHING";
+const MSSQL_INSERT_PEP_QUERY: &str = "IF NOT EXISTS (SELECT 1 FROM physicalendpoint WHERE pep_id = $1) \
+    INSERT INTO physicalendpoint(pep_id, room_id, location_id, machine_id, sticker_number) \
+    VALUES ($1, $2, $3, $4, $5)";
+
+const INSERT_API_LOG_QUERY: &str = "INSERT INTO apilog(\"timestamp\", url, location_id, room_id, status, latency_ms, error_kind, error, body, body_encoding) \
+    VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)";
+
+const INSERT_LAUNDRY_LOG_QUERY: &str = "INSERT INTO laundrylog(pep_id, \"timestamp\", time_remaining, not_available_reason, door_closed, state, machine_settings, available, time_remaining_reported) \
+    VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)";
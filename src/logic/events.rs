@@ -0,0 +1,44 @@
+use crate::models::api::{Machine, ModeType};
+use crate::types::{StateChange, StateSender};
+use chrono::NaiveDateTime;
+use std::collections::HashMap;
+
+/// Publishes a `StateChange` whenever a polled machine differs from its previous poll.
+/// Nothing is published the first time a pep is seen after startup.
+pub(crate) struct StateFeed {
+    state_tx: StateSender,
+    /// Mode and door of every pep at its last poll
+    last: HashMap<String, (ModeType, bool)>,
+}
+
+impl StateFeed {
+    pub(crate) fn new(state_tx: StateSender) -> Self {
+        Self {
+            state_tx,
+            last: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn observe(&mut self, pep_id: &str, machine: &Machine, timestamp: NaiveDateTime) {
+        let current = (machine.mode.clone(), machine.door_closed);
+        let Some(previous) = self.last.insert(pep_id.to_string(), current.clone()) else {
+            return;
+        };
+        if previous == current {
+            return;
+        }
+
+        // Only fails without subscribers
+        let _ = self.state_tx.send(StateChange {
+            pep_id: pep_id.to_string(),
+            location_id: machine.location_id.to_string(),
+            room_id: machine.room_id.clone(),
+            sticker_number: machine.sticker_number,
+            timestamp,
+            old_mode: previous.0,
+            new_mode: current.0,
+            time_remaining: machine.time_remaining,
+            door_closed: machine.door_closed,
+        });
+    }
+}
@@ -0,0 +1,75 @@
+use chrono::{NaiveDateTime, Utc};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+
+/// Liveness of the long-lived controllers and the last successful poll, shared with the server.
+#[derive(Clone, Default)]
+pub(crate) struct Health {
+    inner: Arc<HealthInner>,
+}
+
+#[derive(Default)]
+struct HealthInner {
+    db_controller: AtomicBool,
+    http_controller: AtomicBool,
+    /// Unix seconds, 0 before the first poll
+    last_poll: AtomicI64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Controller {
+    Db,
+    Http,
+}
+
+/// Marks a controller as running until dropped, including when the task panics
+pub(crate) struct RunningGuard {
+    health: Health,
+    controller: Controller,
+}
+
+impl Drop for RunningGuard {
+    fn drop(&mut self) {
+        self.health
+            .flag(self.controller)
+            .store(false, Ordering::Relaxed);
+    }
+}
+
+impl Health {
+    fn flag(&self, controller: Controller) -> &AtomicBool {
+        match controller {
+            Controller::Db => &self.inner.db_controller,
+            Controller::Http => &self.inner.http_controller,
+        }
+    }
+
+    pub(crate) fn running(&self, controller: Controller) -> RunningGuard {
+        self.flag(controller).store(true, Ordering::Relaxed);
+        RunningGuard {
+            health: self.clone(),
+            controller,
+        }
+    }
+
+    #[cfg_attr(not(feature = "server"), allow(dead_code))]
+    pub(crate) fn is_running(&self, controller: Controller) -> bool {
+        self.flag(controller).load(Ordering::Relaxed)
+    }
+
+    /// Records a poll that was stored successfully
+    pub(crate) fn polled(&self, timestamp: NaiveDateTime) {
+        self.inner
+            .last_poll
+            .fetch_max(timestamp.and_utc().timestamp(), Ordering::Relaxed);
+    }
+
+    /// Seconds since the last successful poll, `None` before the first
+    #[cfg_attr(not(feature = "server"), allow(dead_code))]
+    pub(crate) fn poll_age(&self) -> Option<i64> {
+        match self.inner.last_poll.load(Ordering::Relaxed) {
+            0 => None,
+            v => Some((Utc::now().timestamp() - v).max(0)),
+        }
+    }
+}
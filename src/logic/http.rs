@@ -1,24 +1,32 @@
+use crate::logic::health::{Controller, Health};
+use crate::logic::metrics::{CHANNEL_DEPTH, CSC_REQUEST_DURATION, CSC_REQUESTS, POLL_RETRIES};
 use crate::models::api::ApiLocation;
 use crate::models::config::ApiConfig;
 use crate::types::{
-    Db2HttpMessage, Db2HttpReceiver, Http2DbMessage, Http2DbSender, RoomMachinesEndpoint,
-    TrackerWithToken,
+    ApiRequest, Db2HttpMessage, Db2HttpReceiver, Http2DbMessage, Http2DbSender,
+    RoomMachinesEndpoint, TrackerWithToken,
 };
 use crate::utils::prelude::*;
 use crate::utils::url;
-use reqwest::{Client, header};
+use chrono::Utc;
+use metrics::{counter, gauge, histogram};
+use reqwest::{Client, Response, header};
 use tokio::sync::oneshot;
-use tokio::time::{Duration, sleep};
+use tokio::time::{Duration, Instant, sleep};
+use tracing::{Instrument, Span, info_span};
 
 // Long-lived controller task. Handles control messages from the database
 #[instrument(skip_all, fields(task_id=%id()))]
 pub(crate) async fn http_controller(
     mut control_rx: Db2HttpReceiver,
+    record_tx: Http2DbSender,
     api_config: ApiConfig,
     client: Client,
+    health: Health,
     cancel_token: CancellationToken,
 ) -> () {
     info!("Initializing HTTP Control task");
+    let _running = health.running(Controller::Http);
 
     loop {
         let msg = tokio::select! {
@@ -33,6 +41,7 @@ pub(crate) async fn http_controller(
                 }
             },
         };
+        gauge!(CHANNEL_DEPTH, "channel" => "db2http").set(control_rx.len() as f64);
 
         match msg {
             Db2HttpMessage::Noop => info!("DB NOOP"),
@@ -42,11 +51,40 @@ pub(crate) async fn http_controller(
                 return_channel,
             } => {
                 let url = url::location(&api_config, &location_id);
-                let body = get_locations_rooms_endpoint(url, client.clone())
-                    .await
-                    .unwrap(); // TODO
+                // Each lookup is its own trace like a poll, not part of the controller's span
+                let lookup = info_span!(parent: None, "location", location_id);
+                let (request, res) = timed_get(&client, &url, Some(&location_id), None)
+                    .instrument(lookup)
+                    .await;
 
-                return_channel.send(body).unwrap();
+                let (status, error, body) = match res {
+                    Ok(res) => (Some(res.status().as_u16()), None, Some(res)),
+                    Err(e) => {
+                        error!("Failed to get location {}: {:?}", location_id, e);
+                        (e.status().map(|v| v.as_u16()), Some(e), None)
+                    }
+                };
+
+                // The db task may be waiting on the return channel, never block on a full channel
+                let record = Http2DbMessage::ApiRecord {
+                    request,
+                    status,
+                    error,
+                };
+                if let Err(e) = record_tx.try_send(record) {
+                    warn!("Dropped api record for location {}: {}", location_id, e);
+                }
+
+                // Dropping the return channel tells the db task the location could not be found
+                let Some(body) = body else { continue };
+                match body.json::<ApiLocation>().await {
+                    Ok(v) => {
+                        if return_channel.send(v).is_err() {
+                            error!("Location return channel closed");
+                        }
+                    }
+                    Err(e) => error!("Failed to decode location {}: {:?}", location_id, e),
+                }
             }
         };
     }
@@ -88,6 +126,8 @@ async fn scrape_task(
     control_tx: Http2DbSender,
 ) -> () {
     info!("Initializing http task");
+    // Requests are not retried on their own, the next poll repeats a failed one
+    let mut failed = false;
 
     loop {
         trace!("Running http task");
@@ -100,29 +140,110 @@ async fn scrape_task(
             _ = sleep(dur) => {}
         }
 
-        let req = client.get(&url).send();
-        match req.await {
-            Ok(res) => {
-                let send_result = match res.error_for_status() {
-                    Ok(v) => control_tx.send(Http2DbMessage::ApiResponse(v)).await,
-                    Err(e) => control_tx.send(Http2DbMessage::ApiError(e)).await,
-                };
+        if failed {
+            counter!(
+                POLL_RETRIES,
+                "location_id" => endpoint.location_id.clone(),
+                "room_id" => endpoint.room_id.clone()
+            )
+            .increment(1);
+        }
 
-                if let Err(err) = send_result {
-                    error!("Http2Db channel is closed. {:?}", err)
-                }
-            }
-            Err(err) => {
-                error!("{:?}", err); // TODO: better api error handling
+        // Each poll is its own trace, this task lives as long as the process
+        let poll = info_span!(
+            parent: None,
+            "poll",
+            location_id = endpoint.location_id,
+            room_id = endpoint.room_id
+        );
+        let (request, res) = timed_get(
+            &client,
+            &url,
+            Some(&endpoint.location_id),
+            Some(&endpoint.room_id),
+        )
+        .instrument(poll)
+        .await;
+
+        failed = res.is_err();
+        let msg = match res {
+            Ok(v) => Http2DbMessage::ApiResponse(request, v),
+            Err(e) => {
+                warn!("Api request failed: {:?}", e);
+                Http2DbMessage::ApiError(request, e)
             }
+        };
+
+        if let Err(err) = control_tx.send(msg).await {
+            error!("Http2Db channel is closed. {:?}", err)
         }
     }
 }
 
-#[instrument(skip_all)]
-async fn get_locations_rooms_endpoint(url: String, client: Client) -> Result<ApiLocation> {
-    let res = client.get(url).send().await?.json::<ApiLocation>().await?;
-    Ok(res)
+/// Sends a GET request and records the metadata needed for `ApiLog`, and request metrics.
+/// Non-success statuses are returned as errors. Callers instrument it with a root span, which is
+/// kept on the request so the db task stores it under the same trace.
+async fn timed_get(
+    client: &Client,
+    url: &str,
+    location_id: Option<&str>,
+    room_id: Option<&str>,
+) -> (ApiRequest, reqwest::Result<Response>) {
+    let timestamp = Utc::now().naive_utc();
+    let start = Instant::now();
+    let res = client
+        .get(url)
+        .send()
+        .await
+        .and_then(Response::error_for_status);
+    let latency = start.elapsed();
+
+    // Only machine requests are scoped to a room
+    let endpoint = if room_id.is_some() {
+        "machines"
+    } else {
+        "location"
+    };
+    let status = match &res {
+        Ok(v) => v.status().as_u16().to_string(),
+        Err(e) => match e.status() {
+            Some(v) => v.as_u16().to_string(),
+            None => error_kind(e).to_string(),
+        },
+    };
+    counter!(CSC_REQUESTS, "endpoint" => endpoint, "status" => status).increment(1);
+    histogram!(CSC_REQUEST_DURATION, "endpoint" => endpoint).record(latency.as_secs_f64());
+
+    let request = ApiRequest {
+        url: url.to_string(),
+        location_id: location_id.map(str::to_string),
+        room_id: room_id.map(str::to_string),
+        timestamp,
+        latency,
+        span: Span::current(),
+    };
+    (request, res)
+}
+
+/// Short classification of a request error. Stored in `ApiLog.error_kind`
+pub(crate) fn error_kind(err: &reqwest::Error) -> &'static str {
+    if err.is_timeout() {
+        "timeout"
+    } else if err.is_connect() {
+        "connect"
+    } else if err.is_status() {
+        "status"
+    } else if err.is_body() {
+        "body"
+    } else if err.is_decode() {
+        "decode"
+    } else if err.is_redirect() {
+        "redirect"
+    } else if err.is_request() {
+        "request"
+    } else {
+        "other"
+    }
 }
 
 #[instrument(skip_all)]
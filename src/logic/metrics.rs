@@ -0,0 +1,53 @@
+use metrics::{Unit, describe_counter, describe_gauge, describe_histogram};
+
+// Recorded through the `metrics` facade, every recording is a no-op until a recorder is installed
+
+/// Labels: `endpoint`, `status`. Status is the http code or the error kind
+pub(crate) const CSC_REQUESTS: &str = "laundry_csc_requests_total";
+/// Labels: `endpoint`
+pub(crate) const CSC_REQUEST_DURATION: &str = "laundry_csc_request_duration_seconds";
+/// Labels: `location_id`, `room_id`. Polls sent after the previous poll of the room failed
+pub(crate) const POLL_RETRIES: &str = "laundry_poll_retries_total";
+/// Labels: `channel`. Messages still queued after a receive
+pub(crate) const CHANNEL_DEPTH: &str = "laundry_channel_depth";
+/// Labels: `table`
+pub(crate) const DB_WRITE_DURATION: &str = "laundry_db_write_duration_seconds";
+/// Labels: `context`, same as `NotifyMessage::DbWriteFailed`
+pub(crate) const DB_WRITE_FAILURES: &str = "laundry_db_write_failures_total";
+/// Labels: `table`
+pub(crate) const ROWS_WRITTEN: &str = "laundry_rows_written_total";
+/// Labels: `location_id`, `room_id`
+pub(crate) const ROOM_LAST_SUCCESS: &str = "laundry_room_last_success_timestamp_seconds";
+/// Labels: `location_id`, `room_id`, `mode`
+pub(crate) const MACHINES: &str = "laundry_machines";
+
+/// Modes always reported for a room, so a mode dropping to zero machines is exported as zero
+pub(crate) const KNOWN_MODES: [&str; 4] = ["pressStart", "running", "idle", "unknown"];
+
+/// Sets help text and units. Must run after the recorder is installed
+pub(crate) fn describe() {
+    describe_counter!(CSC_REQUESTS, "Requests sent to CSC");
+    describe_histogram!(
+        CSC_REQUEST_DURATION,
+        Unit::Seconds,
+        "Latency of requests sent to CSC"
+    );
+    describe_counter!(
+        POLL_RETRIES,
+        "Polls repeating a failed poll of the same room"
+    );
+    describe_gauge!(CHANNEL_DEPTH, "Messages queued in an internal channel");
+    describe_histogram!(
+        DB_WRITE_DURATION,
+        Unit::Seconds,
+        "Time spent writing a response to the database"
+    );
+    describe_counter!(DB_WRITE_FAILURES, "Failed database writes");
+    describe_counter!(ROWS_WRITTEN, "Rows written to the database");
+    describe_gauge!(
+        ROOM_LAST_SUCCESS,
+        Unit::Seconds,
+        "Unix time of the last successfully stored poll of a room"
+    );
+    describe_gauge!(MACHINES, "Machines in a room by mode at the last poll");
+}
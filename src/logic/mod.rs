@@ -1,2 +1,10 @@
+pub(crate) mod backfill;
+pub(crate) mod cycles;
 pub(crate) mod db;
+pub(crate) mod events;
+pub(crate) mod health;
 pub(crate) mod http;
+pub(crate) mod metrics;
+pub(crate) mod notify;
+pub(crate) mod retention;
+pub(crate) mod sinks;
@@ -0,0 +1,408 @@
+use crate::db::Pool;
+use crate::logic::db::{value_as_int, value_as_timestamp};
+use crate::logic::sinks::Sink;
+use crate::models::config::NotifyConfig;
+use crate::types::{NotifyMessage, NotifyReceiver, NotifySender, RoomMachinesEndpoint};
+use crate::utils::prelude::*;
+use chrono::{NaiveDateTime, TimeDelta, Utc};
+use color_eyre::eyre::OptionExt;
+use reqwest::Client;
+use serde::Serialize;
+use sql_middleware::{MiddlewarePoolConnection, QueryAndParams, RowValues};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use tokio::task::JoinSet;
+use tokio::time::{Duration, interval};
+use tracing::Instrument;
+
+/// Only cycles that ended this recently are checked for left laundry, a machine that stopped
+/// reporting would otherwise stay full forever
+const LEFT_LAUNDRY_LOOKBACK: TimeDelta = TimeDelta::hours(24);
+
+#[derive(Debug, Clone, Copy, Serialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum AlertStatus {
+    Firing,
+    Resolved,
+}
+
+impl AlertStatus {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            AlertStatus::Firing => "firing",
+            AlertStatus::Resolved => "resolved",
+        }
+    }
+}
+
+/// An alert delivered to every sink. Serialized as the webhook and command payload.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct Alert {
+    /// Stable key for the condition, used for deduplication
+    pub(crate) key: String,
+    pub(crate) status: AlertStatus,
+    pub(crate) summary: String,
+    pub(crate) location_id: Option<String>,
+    pub(crate) room_id: Option<String>,
+    pub(crate) timestamp: NaiveDateTime,
+}
+
+impl Alert {
+    pub(crate) fn subject(&self) -> String {
+        format!("[laundry-data] {}: {}", self.status.as_str(), self.summary)
+    }
+
+    pub(crate) fn body(&self) -> String {
+        format!(
+            "{summary}\n\nstatus: {status}\nkey: {key}\nlocation: {location}\nroom: {room}\ntime (UTC): {timestamp}\n",
+            summary = self.summary,
+            status = self.status.as_str(),
+            key = self.key,
+            location = self.location_id.as_deref().unwrap_or("-"),
+            room = self.room_id.as_deref().unwrap_or("-"),
+            timestamp = self.timestamp,
+        )
+    }
+}
+
+/// A failure condition that is currently active
+struct Condition {
+    key: String,
+    summary: String,
+    location_id: Option<String>,
+    room_id: Option<String>,
+}
+
+/// Tracks active alerts to deduplicate and rate limit what is sent
+struct AlertState {
+    repeat_after: TimeDelta,
+    max_per_hour: usize,
+    /// Active conditions and when they were last sent. `None` if rate limited before ever sending
+    active: HashMap<String, (Condition, Option<NaiveDateTime>)>,
+    sent: VecDeque<NaiveDateTime>,
+}
+
+impl AlertState {
+    fn new(config: &NotifyConfig) -> Self {
+        Self {
+            repeat_after: TimeDelta::minutes(config.repeat_after.into()),
+            max_per_hour: config.max_per_hour as usize,
+            active: HashMap::new(),
+            sent: VecDeque::new(),
+        }
+    }
+
+    /// Returns true if another alert may be sent, and records it
+    fn take_slot(&mut self, now: NaiveDateTime) -> bool {
+        while let Some(oldest) = self.sent.front()
+            && now - *oldest >= TimeDelta::hours(1)
+        {
+            self.sent.pop_front();
+        }
+        if self.sent.len() >= self.max_per_hour {
+            return false;
+        }
+        self.sent.push_back(now);
+        true
+    }
+
+    /// Updates the state with every currently active condition and returns the alerts to send
+    fn update(&mut self, conditions: Vec<Condition>, now: NaiveDateTime) -> Vec<Alert> {
+        let mut alerts = vec![];
+        let mut still_active: HashMap<String, (Condition, Option<NaiveDateTime>)> = HashMap::new();
+
+        for condition in conditions {
+            let last_sent = self.active.remove(&condition.key).and_then(|v| v.1);
+            let due = match last_sent {
+                Some(v) => now - v >= self.repeat_after,
+                None => true,
+            };
+
+            let last_sent = if due && self.take_slot(now) {
+                alerts.push(new_alert(&condition, AlertStatus::Firing, now));
+                Some(now)
+            } else {
+                if due {
+                    warn!(key = condition.key, "Alert rate limited");
+                }
+                last_sent
+            };
+            still_active.insert(condition.key.clone(), (condition, last_sent));
+        }
+
+        // Anything left was active last check and has cleared
+        for (_, (condition, last_sent)) in self.active.drain() {
+            // Never told anyone it was firing, no need to resolve
+            if last_sent.is_some() && self.take_slot(now) {
+                alerts.push(new_alert(&condition, AlertStatus::Resolved, now));
+            }
+        }
+
+        self.active = still_active;
+        alerts
+    }
+}
+
+fn new_alert(condition: &Condition, status: AlertStatus, now: NaiveDateTime) -> Alert {
+    Alert {
+        key: condition.key.clone(),
+        status,
+        summary: condition.summary.clone(),
+        location_id: condition.location_id.clone(),
+        room_id: condition.room_id.clone(),
+        timestamp: now,
+    }
+}
+
+/// Reports a failure to the notify task without blocking the caller.
+/// Failures are dropped if notifications are disabled or the channel is full.
+pub(crate) fn report(notify_tx: &NotifySender, msg: NotifyMessage) {
+    let _ = notify_tx.try_send(msg);
+}
+
+/// Long-lived task that checks failure conditions and sends alerts to every sink
+#[instrument(skip_all, fields(task_id=%id()))]
+pub(crate) async fn notify_task(
+    config: NotifyConfig,
+    endpoints: Vec<RoomMachinesEndpoint>,
+    pool: Pool,
+    client: Client,
+    mut notify_rx: NotifyReceiver,
+    cancel_token: CancellationToken,
+) -> () {
+    info!("Initializing notify task");
+
+    let mut sinks = vec![];
+    for sink in config.sinks.clone() {
+        match Sink::new(sink, client.clone()) {
+            Ok(v) => sinks.push(Arc::new(v)),
+            Err(e) => error!("Failed to create notification sink: {:?}", e),
+        }
+    }
+    if sinks.is_empty() {
+        warn!("No notification sinks, alerts are only logged");
+    }
+
+    let started = Utc::now().naive_utc();
+    let mut state = AlertState::new(&config);
+    let mut db_failures: Vec<String> = vec![];
+    // Consecutive checks that saw a database failure, and the failures over them
+    let mut db_failing_checks = 0u32;
+    let mut db_failing_total = 0usize;
+    let mut ticker = interval(Duration::from_secs(config.check_interval));
+    let mut rx_open = true;
+    // Alerts in flight, sent in the background so a slow sink does not hold up checks
+    let mut sends = JoinSet::new();
+
+    loop {
+        tokio::select! {
+            _ = cancel_token.cancelled() => {debug!("Got cancel");break},
+            Some(result) = sends.join_next(), if !sends.is_empty() => {
+                if let Err(e) = result {
+                    error!("Alert send task failed: {:?}", e);
+                }
+            },
+            value = notify_rx.recv(), if rx_open => {
+                match value {
+                    Some(NotifyMessage::DbWriteFailed { context, error }) => {
+                        db_failures.push(format!("{context}: {error}"));
+                    }
+                    None => rx_open = false,
+                }
+            },
+            _ = ticker.tick() => {
+                let now = Utc::now().naive_utc();
+                let mut conditions = vec![];
+
+                match pool.get_connection().await {
+                    Ok(mut conn) => {
+                        for endpoint in endpoints.iter() {
+                            match check_endpoint(&mut conn, &config, endpoint, started, now).await {
+                                Ok(v) => conditions.extend(v),
+                                Err(e) => error!("Failed to check api log: {:?}", e),
+                            }
+                        }
+                        if config.left_laundry {
+                            match check_left_laundry(&mut conn, now).await {
+                                Ok(v) => conditions.extend(v),
+                                Err(e) => error!("Failed to check machine cycles: {:?}", e),
+                            }
+                        }
+                    }
+                    Err(e) => db_failures.push(format!("connection: {e}")),
+                }
+
+                if db_failures.is_empty() {
+                    db_failing_checks = 0;
+                    db_failing_total = 0;
+                } else {
+                    db_failing_checks += 1;
+                    db_failing_total += db_failures.len();
+                    // Only sustained failures alert, a single failed write is usually transient
+                    if db_failing_checks >= config.db_failure_checks {
+                        conditions.push(Condition {
+                            key: "db_write".to_string(),
+                            summary: format!(
+                                "{db_failing_total} database failures over {db_failing_checks} checks, latest: {}",
+                                db_failures.last().map(String::as_str).unwrap_or_default(),
+                            ),
+                            location_id: None,
+                            room_id: None,
+                        });
+                    }
+                    db_failures.clear();
+                }
+
+                for alert in state.update(conditions, now) {
+                    warn!(key = alert.key, status = alert.status.as_str(), "{}", alert.summary);
+                    for sink in sinks.iter() {
+                        let (sink, alert) = (sink.clone(), alert.clone());
+                        sends.spawn(
+                            async move {
+                                if let Err(e) = sink.send(&alert).await {
+                                    error!("Failed to send alert: {:?}", e);
+                                }
+                            }
+                            .in_current_span(),
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    // Every sink times out, so this only waits a bounded time
+    while sends.join_next().await.is_some() {}
+}
+
+/// Checks `ApiLog` for consecutive errors and stale polls for a single room
+#[instrument(skip_all, fields(location_id = endpoint.location_id, room_id = endpoint.room_id))]
+async fn check_endpoint(
+    conn: &mut MiddlewarePoolConnection,
+    config: &NotifyConfig,
+    endpoint: &RoomMachinesEndpoint,
+    started: NaiveDateTime,
+    now: NaiveDateTime,
+) -> Result<Vec<Condition>> {
+    let mut conditions = vec![];
+
+    let query = QueryAndParams::new(
+        LAST_SUCCESS_QUERY,
+        vec![
+            RowValues::Text(endpoint.location_id.clone()),
+            RowValues::Text(endpoint.room_id.clone()),
+        ],
+    );
+    let result = conn.query(&query.query).params(&query.params).select().await?;
+    let last_success = result
+        .results
+        .first()
+        .and_then(|row| row.get_by_index(0))
+        .and_then(value_as_timestamp);
+
+    // Count every error after the last success, or every error if the room never succeeded
+    let query = match last_success {
+        Some(v) => QueryAndParams::new(
+            COUNT_ERRORS_SINCE_QUERY,
+            vec![
+                RowValues::Text(endpoint.location_id.clone()),
+                RowValues::Text(endpoint.room_id.clone()),
+                RowValues::Timestamp(v),
+            ],
+        ),
+        None => QueryAndParams::new(
+            COUNT_ERRORS_QUERY,
+            vec![
+                RowValues::Text(endpoint.location_id.clone()),
+                RowValues::Text(endpoint.room_id.clone()),
+            ],
+        ),
+    };
+    let result = conn.query(&query.query).params(&query.params).select().await?;
+    let errors = result
+        .results
+        .first()
+        .and_then(|row| row.get_by_index(0))
+        .and_then(value_as_int)
+        .unwrap_or(0);
+
+    if errors >= i64::from(config.consecutive_errors) {
+        conditions.push(Condition {
+            key: format!("api_errors:{}/{}", endpoint.location_id, endpoint.room_id),
+            summary: format!(
+                "{errors} consecutive api errors for room {}",
+                endpoint.room_id
+            ),
+            location_id: Some(endpoint.location_id.clone()),
+            room_id: Some(endpoint.room_id.clone()),
+        });
+    }
+
+    // Do not alert for rooms that have not had a chance to poll since startup
+    let reference = last_success.unwrap_or(started).max(started);
+    if now - reference >= TimeDelta::minutes(config.stale_after.into()) {
+        conditions.push(Condition {
+            key: format!("api_stale:{}/{}", endpoint.location_id, endpoint.room_id),
+            summary: format!(
+                "No successful poll for room {} in {} minutes",
+                endpoint.room_id,
+                (now - reference).num_minutes()
+            ),
+            location_id: Some(endpoint.location_id.clone()),
+            room_id: Some(endpoint.room_id.clone()),
+        });
+    }
+
+    Ok(conditions)
+}
+
+/// Every machine with laundry probably left inside, see `logic::cycles`
+#[instrument(skip_all)]
+async fn check_left_laundry(
+    conn: &mut MiddlewarePoolConnection,
+    now: NaiveDateTime,
+) -> Result<Vec<Condition>> {
+    let query = QueryAndParams::new(
+        PROBABLY_FULL_QUERY,
+        vec![
+            RowValues::Bool(true),
+            RowValues::Timestamp(now - LEFT_LAUNDRY_LOOKBACK),
+        ],
+    );
+    let result = conn.query(&query.query).params(&query.params).select().await?;
+
+    let mut conditions = vec![];
+    for row in result.results.iter() {
+        let column = |index: usize| {
+            row.get_by_index(index)
+                .ok_or_eyre(format!("Failed to get row by index {index}"))
+        };
+        let pep_id = column(0)?.as_text().ok_or_eyre("Invalid pep_id")?;
+        let cycle_end = value_as_timestamp(column(1)?).ok_or_eyre("Invalid cycle_end")?;
+        let location_id = column(2)?.as_text().ok_or_eyre("Invalid location_id")?;
+        let room_id = column(3)?.as_text().ok_or_eyre("Invalid room_id")?;
+        let sticker_number = value_as_int(column(4)?).ok_or_eyre("Invalid sticker_number")?;
+
+        conditions.push(Condition {
+            key: format!("left_laundry:{pep_id}"),
+            summary: format!(
+                "Laundry probably left in machine #{sticker_number} in room {room_id} for {} minutes",
+                (now - cycle_end).num_minutes()
+            ),
+            location_id: Some(location_id.to_string()),
+            room_id: Some(room_id.to_string()),
+        });
+    }
+    Ok(conditions)
+}
+
+const LAST_SUCCESS_QUERY: &str = "SELECT MAX(\"timestamp\") FROM apilog \
+    WHERE location_id = $1 AND room_id = $2 AND error_kind IS NULL";
+const COUNT_ERRORS_SINCE_QUERY: &str = "SELECT COUNT(*) FROM apilog \
+    WHERE location_id = $1 AND room_id = $2 AND error_kind IS NOT NULL AND \"timestamp\" > $3";
+const COUNT_ERRORS_QUERY: &str = "SELECT COUNT(*) FROM apilog \
+    WHERE location_id = $1 AND room_id = $2 AND error_kind IS NOT NULL";
+
+const PROBABLY_FULL_QUERY: &str = "SELECT c.pep_id, c.cycle_end, p.location_id, p.room_id, p.sticker_number \
+    FROM machinecycles c JOIN physicalendpoint p ON c.pep_id = p.pep_id \
+    WHERE c.probably_full = $1 AND c.cycle_end >= $2";
@@ -0,0 +1,180 @@
+use crate::db::{DbType, Pool};
+use crate::logic::db::value_as_timestamp;
+use crate::models::config::RetentionConfig;
+use crate::utils::prelude::*;
+use chrono::{DurationRound, NaiveDateTime, TimeDelta, Utc};
+use sql_middleware::{MiddlewarePoolConnection, RowValues};
+use tokio::time::{Duration, interval};
+
+/// Long-lived task that rolls `LaundryLog` rows older than `raw_days` into `LaundryLogHourly` and
+/// deletes them. On postgres Timescale policies do the work, they are set once and the task ends.
+#[instrument(skip_all, fields(task_id=%id()))]
+pub(crate) async fn retention_task(
+    config: RetentionConfig,
+    db_type: DbType,
+    pool: Pool,
+    cancel_token: CancellationToken,
+) -> () {
+    info!("Initializing retention task");
+    if db_type == DbType::Postgres {
+        match timescale_policies(&pool, &config).await {
+            Ok(()) => info!(
+                "Timescale policies set, raw rows are kept {} days",
+                config.raw_days
+            ),
+            Err(e) => error!("Failed to set Timescale policies: {:?}", e),
+        }
+        return;
+    }
+
+    let mut ticker = interval(Duration::from_secs(config.interval));
+    loop {
+        tokio::select! {
+            _ = cancel_token.cancelled() => {debug!("Got cancel");break},
+            _ = ticker.tick() => {}
+        }
+
+        let mut conn = match pool.get_connection().await {
+            Ok(v) => v,
+            Err(e) => {
+                error!("Failed to get db connection: {:?}", e);
+                continue;
+            }
+        };
+        match rollup(db_type, &mut conn, &config).await {
+            Ok(Some(until)) => info!("Rolled up LaundryLog before {}", until),
+            Ok(None) => debug!("Nothing to roll up"),
+            Err(e) => error!("Retention failed: {:?}", e),
+        }
+    }
+}
+
+/// Removes the Timescale policies of a run with `[retention]`, raw rows are kept again.
+/// Hours already in `LaundryLogHourly` stay, but are no longer refreshed.
+#[instrument(skip_all, fields(task_id=%id()))]
+pub(crate) async fn remove_policies_task(pool: Pool) -> () {
+    let remove = async {
+        let mut conn = pool.get_connection().await?;
+        conn.query(REMOVE_AGGREGATE_POLICY_QUERY).select().await?;
+        conn.query(REMOVE_RETENTION_POLICY_QUERY).select().await?;
+        Ok::<_, Report>(())
+    };
+    match remove.await {
+        Ok(()) => debug!("Timescale policies removed"),
+        Err(e) => error!("Failed to remove Timescale policies: {:?}", e),
+    }
+}
+
+/// Replaces the policies on every start so a changed `raw_days` applies.
+/// The aggregate is refreshed in full once, before the retention policy drops any chunk.
+#[instrument(skip_all)]
+async fn timescale_policies(pool: &Pool, config: &RetentionConfig) -> Result<()> {
+    let mut conn = pool.get_connection().await?;
+    let days = config.raw_days;
+
+    let materialized = conn.query(ANY_HOURLY_QUERY).select().await?.results;
+    if materialized.is_empty() {
+        info!("Materializing LaundryLogHourly from existing history");
+        conn.query(REFRESH_HOURLY_QUERY).dml().await?;
+    }
+
+    conn.query(REMOVE_AGGREGATE_POLICY_QUERY).select().await?;
+    // Refreshing a range whose raw chunks were dropped would empty it, stay inside `raw_days`
+    conn.query(&format!(
+        "SELECT add_continuous_aggregate_policy('laundryloghourly', \
+        start_offset => INTERVAL '{days} days', end_offset => INTERVAL '1 hour', \
+        schedule_interval => INTERVAL '1 hour')"
+    ))
+    .select()
+    .await?;
+    conn.query(REMOVE_RETENTION_POLICY_QUERY).select().await?;
+    conn.query(&format!(
+        "SELECT add_retention_policy('laundrylog', drop_after => INTERVAL '{days} days')"
+    ))
+    .select()
+    .await?;
+    Ok(())
+}
+
+/// Rolls whole hours before the cutoff a day at a time, returns where it stopped.
+/// Hours are replaced rather than added to, so a run that failed before deleting is repeated safely.
+#[instrument(skip_all)]
+async fn rollup(
+    db_type: DbType,
+    conn: &mut MiddlewarePoolConnection,
+    config: &RetentionConfig,
+) -> Result<Option<NaiveDateTime>> {
+    let hour = TimeDelta::hours(1);
+    let cutoff =
+        (Utc::now().naive_utc() - TimeDelta::days(config.raw_days)).duration_trunc(hour)?;
+    let mut until = None;
+
+    loop {
+        let rows = conn.query(OLDEST_ROW_QUERY).select().await?.results;
+        let Some(oldest) = rows
+            .first()
+            .and_then(|row| row.get_by_index(0))
+            .and_then(value_as_timestamp)
+            .filter(|v| *v < cutoff)
+        else {
+            break;
+        };
+
+        let start = oldest.duration_trunc(hour)?;
+        let end = (start + TimeDelta::days(1)).min(cutoff);
+        let params = [RowValues::Timestamp(start), RowValues::Timestamp(end)];
+        let query = match db_type {
+            DbType::Mssql => MSSQL_ROLLUP_QUERY,
+            _ => ROLLUP_QUERY,
+        };
+        conn.query(query).params(&params).dml().await?;
+        conn.query(DELETE_ROLLED_UP_QUERY)
+            .params(&params)
+            .dml()
+            .await?;
+        debug!("Rolled up {} to {}", start, end);
+        until = Some(end);
+    }
+    Ok(until)
+}
+
+const ANY_HOURLY_QUERY: &str = "SELECT 1 FROM laundryloghourly LIMIT 1";
+
+const REFRESH_HOURLY_QUERY: &str = "CALL refresh_continuous_aggregate('laundryloghourly', NULL, \
+    (now() AT TIME ZONE 'UTC') - INTERVAL '1 hour')";
+
+const REMOVE_AGGREGATE_POLICY_QUERY: &str =
+    "SELECT remove_continuous_aggregate_policy('laundryloghourly', if_exists => true)";
+
+const REMOVE_RETENTION_POLICY_QUERY: &str =
+    "SELECT remove_retention_policy('laundrylog', if_exists => true)";
+
+const OLDEST_ROW_QUERY: &str = "SELECT MIN(\"timestamp\") FROM laundrylog";
+
+/// Sqlite, timestamps are stored as text
+const ROLLUP_QUERY: &str = "INSERT OR REPLACE INTO laundryloghourly(pep_id, hour, samples, press_start, running, idle, unknown, other, availability) \
+    SELECT pep_id, strftime('%Y-%m-%d %H:00:00', \"timestamp\") AS hour, COUNT(*), \
+    SUM(CASE WHEN state = 'pressStart' THEN 1 ELSE 0 END), SUM(CASE WHEN state = 'running' THEN 1 ELSE 0 END), \
+    SUM(CASE WHEN state = 'idle' THEN 1 ELSE 0 END), SUM(CASE WHEN state = 'unknown' THEN 1 ELSE 0 END), \
+    SUM(CASE WHEN state NOT IN ('pressStart', 'running', 'idle', 'unknown') THEN 1 ELSE 0 END), \
+    AVG(CASE WHEN state = 'idle' AND not_available_reason IS NULL THEN 1.0 ELSE 0.0 END) \
+    FROM laundrylog WHERE \"timestamp\" >= $1 AND \"timestamp\" < $2 \
+    GROUP BY pep_id, strftime('%Y-%m-%d %H:00:00', \"timestamp\")";
+
+const MSSQL_ROLLUP_QUERY: &str = "MERGE laundryloghourly AS target \
+    USING (SELECT pep_id, DATEADD(hour, DATEDIFF(hour, 0, \"timestamp\"), 0) AS hour, COUNT_BIG(*) AS samples, \
+    SUM(CASE WHEN state = 'pressStart' THEN 1 ELSE 0 END) AS press_start, SUM(CASE WHEN state = 'running' THEN 1 ELSE 0 END) AS running, \
+    SUM(CASE WHEN state = 'idle' THEN 1 ELSE 0 END) AS idle, SUM(CASE WHEN state = 'unknown' THEN 1 ELSE 0 END) AS unknown, \
+    SUM(CASE WHEN state NOT IN ('pressStart', 'running', 'idle', 'unknown') THEN 1 ELSE 0 END) AS other, \
+    AVG(CASE WHEN state = 'idle' AND not_available_reason IS NULL THEN 1.0 ELSE 0.0 END) AS availability \
+    FROM laundrylog WHERE \"timestamp\" >= $1 AND \"timestamp\" < $2 \
+    GROUP BY pep_id, DATEADD(hour, DATEDIFF(hour, 0, \"timestamp\"), 0)) AS source \
+    ON target.pep_id = source.pep_id AND target.hour = source.hour \
+    WHEN MATCHED THEN UPDATE SET samples = source.samples, press_start = source.press_start, running = source.running, \
+    idle = source.idle, unknown = source.unknown, other = source.other, availability = source.availability \
+    WHEN NOT MATCHED THEN INSERT (pep_id, hour, samples, press_start, running, idle, unknown, other, availability) \
+    VALUES (source.pep_id, source.hour, source.samples, source.press_start, source.running, source.idle, \
+    source.unknown, source.other, source.availability);";
+
+const DELETE_ROLLED_UP_QUERY: &str =
+    "DELETE FROM laundrylog WHERE \"timestamp\" >= $1 AND \"timestamp\" < $2";
@@ -0,0 +1,265 @@
+use crate::logic::notify::Alert;
+use crate::models::config::SinkConfig;
+use crate::utils::prelude::*;
+use reqwest::Client;
+use std::process::Stdio;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::time::{Duration, timeout};
+
+#[cfg(feature = "smtp")]
+use lettre::{
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor, message::Mailbox,
+    message::header::ContentType, transport::smtp::authentication::Credentials,
+};
+
+/// A destination for alerts
+pub(crate) enum Sink {
+    Webhook {
+        client: Client,
+        url: String,
+        timeout: Duration,
+    },
+    #[cfg(feature = "smtp")]
+    Smtp {
+        transport: AsyncSmtpTransport<Tokio1Executor>,
+        from: Mailbox,
+        to: Vec<Mailbox>,
+    },
+    Command {
+        program: String,
+        args: Vec<String>,
+        timeout: Duration,
+    },
+}
+
+impl Sink {
+    pub(crate) fn new(config: SinkConfig, client: Client) -> Result<Self> {
+        match config {
+            SinkConfig::Webhook { url, timeout } => Ok(Sink::Webhook {
+                client,
+                url,
+                timeout: Duration::from_secs(timeout),
+            }),
+            #[cfg(feature = "smtp")]
+            SinkConfig::Smtp(smtp) => {
+                use crate::models::config::SmtpTls;
+
+                let mut builder = match smtp.tls {
+                    SmtpTls::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(
+                        smtp.host.as_str(),
+                    ),
+                    SmtpTls::Starttls => {
+                        AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&smtp.host)?
+                    }
+                    SmtpTls::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&smtp.host)?,
+                };
+                if let Some(port) = smtp.port {
+                    builder = builder.port(port);
+                }
+                builder = builder.timeout(Some(Duration::from_secs(smtp.timeout)));
+                if let (Some(user), Some(pass)) = (smtp.user_name, smtp.password) {
+                    builder = builder.credentials(Credentials::new(user, pass));
+                }
+
+                Ok(Sink::Smtp {
+                    transport: builder.build(),
+                    from: smtp.from.parse()?,
+                    to: smtp
+                        .to
+                        .iter()
+                        .map(|v| v.parse())
+                        .collect::<std::result::Result<_, _>>()?,
+                })
+            }
+            #[cfg(not(feature = "smtp"))]
+            SinkConfig::Smtp(_) => Err(Report::msg(
+                "smtp sink requires the 'smtp' feature to be enabled",
+            )),
+            SinkConfig::Command {
+                program,
+                args,
+                timeout,
+            } => Ok(Sink::Command {
+                program,
+                args,
+                timeout: Duration::from_secs(timeout),
+            }),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Sink::Webhook { .. } => "webhook",
+            #[cfg(feature = "smtp")]
+            Sink::Smtp { .. } => "smtp",
+            Sink::Command { .. } => "command",
+        }
+    }
+
+    #[instrument(skip_all, fields(sink = self.name(), key = alert.key))]
+    pub(crate) async fn send(&self, alert: &Alert) -> Result<()> {
+        match self {
+            Sink::Webhook {
+                client,
+                url,
+                timeout,
+            } => {
+                client
+                    .post(url)
+                    .timeout(*timeout)
+                    .json(alert)
+                    .send()
+                    .await?
+                    .error_for_status()?;
+            }
+            #[cfg(feature = "smtp")]
+            Sink::Smtp {
+                transport,
+                from,
+                to,
+            } => {
+                let mut builder = Message::builder()
+                    .from(from.clone())
+                    .subject(alert.subject())
+                    .header(ContentType::TEXT_PLAIN);
+                for mailbox in to {
+                    builder = builder.to(mailbox.clone());
+                }
+                transport.send(builder.body(alert.body())?).await?;
+            }
+            Sink::Command {
+                program,
+                args,
+                timeout: limit,
+            } => {
+                // Alert is passed as JSON on stdin, common fields are also set in the environment
+                let mut child = Command::new(program)
+                    .args(args)
+                    .env("ALERT_KEY", &alert.key)
+                    .env("ALERT_STATUS", alert.status.as_str())
+                    .env("ALERT_SUMMARY", &alert.summary)
+                    .stdin(Stdio::piped())
+                    .kill_on_drop(true)
+                    .spawn()?;
+
+                let payload = serde_json::to_vec(alert)?;
+                let run = async {
+                    if let Some(mut stdin) = child.stdin.take() {
+                        stdin.write_all(&payload).await?;
+                    }
+                    Ok::<_, Report>(child.wait().await?)
+                };
+                let status = match timeout(*limit, run).await {
+                    Ok(v) => v?,
+                    Err(_) => {
+                        child.kill().await?;
+                        return Err(Report::msg(format!(
+                            "{program} killed after {}s",
+                            limit.as_secs()
+                        )));
+                    }
+                };
+                if !status.success() {
+                    return Err(Report::msg(format!("{program} exited with {status}")));
+                }
+            }
+        }
+        debug!("Alert sent");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logic::notify::AlertStatus;
+    use crate::testing::serve_once;
+    use chrono::Utc;
+
+    fn alert() -> Alert {
+        Alert {
+            key: "room_stale:loc:room".to_string(),
+            status: AlertStatus::Firing,
+            summary: "No data for room".to_string(),
+            location_id: Some("loc".to_string()),
+            room_id: Some("room".to_string()),
+            timestamp: Utc::now().naive_utc(),
+        }
+    }
+
+    #[tokio::test]
+    async fn webhook_posts_alert_as_json() -> Result<()> {
+        let (address, server) = serve_once("200 OK").await?;
+        let alert = alert();
+        let url = format!("http://{address}/hook");
+        Sink::new(SinkConfig::Webhook { url, timeout: 5 }, Client::new())?
+            .send(&alert)
+            .await?;
+
+        let request = server.await??;
+        assert!(request.head.starts_with("post /hook http/1.1\r\n"));
+        assert!(request.head.contains("content-type: application/json"));
+        let body: serde_json::Value = serde_json::from_slice(&request.body)?;
+        assert_eq!(body, serde_json::to_value(&alert)?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn webhook_error_status_fails() -> Result<()> {
+        let (address, server) = serve_once("500 Internal Server Error").await?;
+        let url = format!("http://{address}/hook");
+        let sink = Sink::new(SinkConfig::Webhook { url, timeout: 5 }, Client::new())?;
+        assert!(sink.send(&alert()).await.is_err());
+        server.await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn webhook_times_out() -> Result<()> {
+        // Connections queue on the listener but are never answered
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}/hook", listener.local_addr()?);
+        let sink = Sink::new(SinkConfig::Webhook { url, timeout: 1 }, Client::new())?;
+        let error = sink.send(&alert()).await.err();
+        assert!(
+            error
+                .and_then(|v| v.downcast::<reqwest::Error>().ok())
+                .is_some_and(|v| v.is_timeout())
+        );
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn command_reads_alert() -> Result<()> {
+        let sink = Sink::new(
+            SinkConfig::Command {
+                program: "sh".to_string(),
+                args: vec![
+                    "-c".to_string(),
+                    r#"test "$ALERT_STATUS" = firing && grep -q room_stale"#.to_string(),
+                ],
+                timeout: 5,
+            },
+            Client::new(),
+        )?;
+        sink.send(&alert()).await
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn command_killed_after_timeout() -> Result<()> {
+        let sink = Sink::new(
+            SinkConfig::Command {
+                program: "sleep".to_string(),
+                args: vec!["10".to_string()],
+                timeout: 1,
+            },
+            Client::new(),
+        )?;
+        let error = sink.send(&alert()).await.err().map(|v| v.to_string());
+        assert_eq!(error.as_deref(), Some("sleep killed after 1s"));
+        Ok(())
+    }
+}
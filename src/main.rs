@@ -1,49 +1,108 @@
+mod commands;
 mod db;
+mod logging;
 mod logic;
 mod models;
 mod types;
 mod utils;
 mod pep;
+#[cfg(feature = "server")]
+mod server;
+#[cfg(test)]
+mod testing;
 
+use clap::{Parser, Subcommand};
+use color_eyre::eyre::{OptionExt, WrapErr};
 use config::Config;
+use serde::de::DeserializeOwned;
 use tokio::signal::ctrl_c;
 use tokio_util::sync::CancellationToken;
 use tokio_util::task::TaskTracker;
 
-use crate::models::config::AppConfig;
+use crate::logging::Console;
+use crate::models::config::{AppConfig, CommandConfig};
 use crate::utils::prelude::*;
-use tracing_subscriber::layer::SubscriberExt;
-use tracing_subscriber::util::SubscriberInitExt;
-use tracing_subscriber::{fmt, EnvFilter};
+
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Runs the scrapers and every configured task. Default when no command is given
+    Run,
+    /// Streams LaundryLog history to CSV or JSON Lines
+    Export(commands::export::ExportArgs),
+    /// Copies every table from the `[source]` database into `[db]`, resuming where it stopped
+    Copy(commands::copy::CopyArgs),
+    /// Writes LaundryLog and MachineCycles to Parquet, partitioned by date and location
+    #[cfg(feature = "parquet")]
+    Parquet(commands::parquet::ParquetArgs),
+}
 
 fn main() -> Result<()> {
     color_eyre::install()?;
-    tracing_subscriber::registry()
-        .with(fmt::layer())
-        .with(EnvFilter::from_default_env())
-        .init();
+    let cli = Cli::parse();
 
-    let app_config = Config::builder()
+    let config = Config::builder()
         .add_source(
             config::Environment::default()
                 .separator("_")
                 .ignore_empty(true),
         )
         .add_source(config::File::with_name("config"))
-        .build()?
-        .try_deserialize()?;
-
-    tokio::runtime::Builder::new_multi_thread()
+        .build()?;
+    let runtime = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
-        .build()?
-        .block_on(async_main(app_config))
+        .build()?;
+
+    match cli.command.unwrap_or(Command::Run) {
+        Command::Run => {
+            let app_config: AppConfig = load(config)?;
+            app_config.validate()?;
+            // Flushes the log file and spans on exit
+            let _log_guard = logging::init(&app_config.log, Console::Stdout)?;
+            runtime.block_on(async_main(app_config))
+        }
+        Command::Export(args) => {
+            let config: CommandConfig = load(config)?;
+            config.validate()?;
+            let _log_guard = logging::init(&config.log, Console::Stderr)?;
+            runtime.block_on(commands::export::export(config.db, args))
+        }
+        Command::Copy(args) => {
+            let config: CommandConfig = load(config)?;
+            config.validate()?;
+            let _log_guard = logging::init(&config.log, Console::Stderr)?;
+            let source = config
+                .source
+                .ok_or_eyre("Missing [source] database to copy from")?;
+            runtime.block_on(commands::copy::copy(source, config.db, args))
+        }
+        #[cfg(feature = "parquet")]
+        Command::Parquet(args) => {
+            let config: CommandConfig = load(config)?;
+            config.validate()?;
+            let _log_guard = logging::init(&config.log, Console::Stderr)?;
+            runtime.block_on(commands::parquet::export_parquet(config.db, args))
+        }
+    }
+}
+
+/// Serde errors name the key but not where it came from
+fn load<T: DeserializeOwned>(config: Config) -> Result<T> {
+    config
+        .try_deserialize()
+        .wrap_err("Invalid config, check config.toml and the environment")
 }
 
 #[instrument(skip_all)]
 async fn async_main(config: AppConfig) -> Result<()> {
     info!("Beginning startup");
-    let pool = db::new_pool(config.db.clone()).await?;
-    // TODO: Check for database connectivity
+    let pool = db::connect(&config.db).await?;
 
     info!("Applying migrations");
     let report = db::embedded::run_async(config.db.clone()).await?;
@@ -52,6 +111,13 @@ async fn async_main(config: AppConfig) -> Result<()> {
         report.applied_migrations().len()
     );
 
+    // Installed before any task records, a recorder that can not be installed stops startup
+    #[cfg(feature = "server")]
+    let metrics = match &config.server {
+        Some(server_config) if server_config.metrics => Some(server::install_metrics()?),
+        _ => None,
+    };
+
     // Cancel token for all sub-tasks
     let cancel_token = CancellationToken::new();
     let tracker:TaskTracker = TaskTracker::new();
@@ -61,6 +127,10 @@ async fn async_main(config: AppConfig) -> Result<()> {
 
     let (http_tx, http_rx) = tokio::sync::mpsc::channel(32);
     let (db_tx, db_rx) = tokio::sync::mpsc::channel(32);
+    let (notify_tx, notify_rx) = tokio::sync::mpsc::channel(32);
+    // Receivers are created per subscriber, sends without one are dropped
+    let (state_tx, _) = tokio::sync::broadcast::channel(256);
+    let health = logic::health::Health::default();
 
     // Http tasks
     let http_client = logic::http::build_client()?;
@@ -69,12 +139,14 @@ async fn async_main(config: AppConfig) -> Result<()> {
         tracker_with_token,
         config.api.clone(),
         http_client.clone(),
-        http_tx,
+        http_tx.clone(),
     )?;
     tracker.spawn(logic::http::http_controller(
         db_rx,
+        http_tx,
         config.api.clone(),
-        http_client,
+        http_client.clone(),
+        health.clone(),
         cancel_token.clone(),
     ));
 
@@ -82,12 +154,79 @@ async fn async_main(config: AppConfig) -> Result<()> {
 
     tracker.spawn(logic::db::db_controller(
         config.api.clone(),
-        pool,
+        config.cycles.clone(),
+        config.db.r#type,
+        pool.clone(),
         http_rx,
         db_tx,
+        notify_tx,
+        state_tx.clone(),
+        health.clone(),
         cancel_token.clone(),
     ));
 
+    if let Some(backfill_config) = config.backfill.clone() {
+        tracker.spawn(logic::backfill::backfill_task(
+            backfill_config,
+            pool.clone(),
+            cancel_token.clone(),
+        ));
+    } else {
+        info!("Backfilling disabled");
+    }
+
+    if let Some(retention_config) = config.retention.clone() {
+        tracker.spawn(logic::retention::retention_task(
+            retention_config,
+            config.db.r#type,
+            pool.clone(),
+            cancel_token.clone(),
+        ));
+    } else {
+        info!("Retention disabled");
+        // Policies set by an earlier run would keep dropping raw rows
+        if config.db.r#type == db::DbType::Postgres {
+            tracker.spawn(logic::retention::remove_policies_task(pool.clone()));
+        }
+    }
+
+    // server tasks
+    #[cfg(feature = "server")]
+    if let Some(server_config) = config.server.clone() {
+        tracker.spawn(server::server_task(
+            server_config,
+            server::AppState {
+                db_type: config.db.r#type,
+                pool: pool.clone(),
+                state_tx,
+                health,
+                cancel_token: cancel_token.clone(),
+            },
+            metrics,
+            cancel_token.clone(),
+        ));
+    } else {
+        info!("Server disabled");
+    }
+    #[cfg(not(feature = "server"))]
+    if config.server.is_some() {
+        warn!("Server configured but the server feature is not enabled");
+    }
+
+    // notify tasks
+    if let Some(notify_config) = config.notify.clone() {
+        tracker.spawn(logic::notify::notify_task(
+            notify_config,
+            config.api.endpoints.clone(),
+            pool,
+            http_client,
+            notify_rx,
+            cancel_token.clone(),
+        ));
+    } else {
+        info!("Notifications disabled");
+    }
+
     tracker.close();
 
     tokio::select! {
@@ -1,26 +1,106 @@
-use serde::Deserialize;
+use crate::utils::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::sync::{LazyLock, Mutex};
 use uuid::Uuid;
 
-#[derive(Deserialize, Debug)]
+/// Unknown values seen from the api. Used to only log a new value once.
+static UNKNOWN_VARIANTS: LazyLock<Mutex<HashSet<String>>> =
+    LazyLock::new(|| Mutex::new(HashSet::new()));
+
+fn warn_unknown_variant(kind: &str, value: &str) {
+    if let Ok(mut seen) = UNKNOWN_VARIANTS.lock()
+        && seen.insert(format!("{kind}:{value}"))
+    {
+        warn!("Unknown {kind} value from api: {value:?}");
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Eq, PartialEq)]
+#[serde(from = "String", into = "String")]
 pub enum MachineType {
-    #[serde(rename = "washer")]
     Washer,
-    #[serde(rename = "dryer")]
     Dryer,
+    /// Any type not known yet. e.g. `combo`
+    Other(String),
 }
 
-#[derive(Deserialize, Debug)]
+impl From<String> for MachineType {
+    fn from(value: String) -> Self {
+        match value.as_str() {
+            "washer" => MachineType::Washer,
+            "dryer" => MachineType::Dryer,
+            _ => {
+                warn_unknown_variant("MachineType", &value);
+                MachineType::Other(value)
+            }
+        }
+    }
+}
+
+impl From<MachineType> for String {
+    fn from(value: MachineType) -> Self {
+        value.as_str().to_string()
+    }
+}
+
+impl MachineType {
+    /// Value stored in the `type` column
+    pub fn as_str(&self) -> &str {
+        match self {
+            MachineType::Washer => "washer",
+            MachineType::Dryer => "dryer",
+            MachineType::Other(v) => v,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Eq, PartialEq)]
+#[serde(from = "String", into = "String")]
 pub enum ModeType {
-    #[serde(rename = "pressStart")]
     PressStart,
-    #[serde(rename = "running")]
     Running,
-    #[serde(rename = "idle")]
     Idle,
-    #[serde(rename = "unknown")]
     Unknown,
+    /// Any mode not known yet. e.g. `outOfOrder`
+    Other(String),
 }
-#[derive(Deserialize, Debug)]
+
+impl From<String> for ModeType {
+    fn from(value: String) -> Self {
+        match value.as_str() {
+            "pressStart" => ModeType::PressStart,
+            "running" => ModeType::Running,
+            "idle" => ModeType::Idle,
+            "unknown" => ModeType::Unknown,
+            _ => {
+                warn_unknown_variant("ModeType", &value);
+                ModeType::Other(value)
+            }
+        }
+    }
+}
+
+impl From<ModeType> for String {
+    fn from(value: ModeType) -> Self {
+        value.as_str().to_string()
+    }
+}
+
+impl ModeType {
+    /// Value stored in the `state` column
+    pub fn as_str(&self) -> &str {
+        match self {
+            ModeType::PressStart => "pressStart",
+            ModeType::Running => "running",
+            ModeType::Idle => "idle",
+            ModeType::Unknown => "unknown",
+            ModeType::Other(v) => v,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct Machine {
     pub opaque_id: Uuid,
@@ -33,16 +113,25 @@ pub struct Machine {
     #[serde(rename = "type")]
     pub r#type: MachineType,
     pub door_closed: bool,
-    pub available: bool,
+    /// Always sent by CSC, left out by the CSC compatible api for rows that did not store it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub available: Option<bool>,
     pub not_available_reason: Option<String>,
     pub mode: ModeType,
     pub time_remaining: Option<i16>,
+    pub controller_type: Option<String>,
+    pub in_service: Option<bool>,
+    pub free_play: Option<bool>,
+    /// Raw capability flags, shape differs between controller types
+    pub capabilities: Option<serde_json::Value>,
+    /// Raw pricing, only present on paid machines
+    pub pricing: Option<serde_json::Value>,
 
     #[serde(flatten)]
     pub settings: MachineSettings,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct MachineSettings {
     pub soil: Option<String>,
@@ -53,6 +142,24 @@ pub struct MachineSettings {
 
 pub(crate) type MachineList = Vec<Machine>;
 
+/// Decodes each machine on its own so one unexpected machine does not drop the entire room.
+/// Machines that fail to decode are logged and skipped.
+pub(crate) fn decode_machine_list(values: Vec<serde_json::Value>) -> MachineList {
+    let mut machines = Vec::with_capacity(values.len());
+    for value in values {
+        let opaque_id = value
+            .get("opaqueId")
+            .and_then(|v| v.as_str())
+            .unwrap_or("<missing>")
+            .to_string();
+        match serde_json::from_value::<Machine>(value) {
+            Ok(machine) => machines.push(machine),
+            Err(e) => error!(opaque_id, "Failed to decode machine: {}", e),
+        }
+    }
+    machines
+}
+
 // TODO: Description might be null?
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
@@ -60,6 +167,9 @@ pub struct ApiLocation {
     pub location_id: Uuid,
     pub description: String,
     pub label: String,
+    /// Not sent for every location
+    #[serde(default)]
+    pub timezone: Option<String>,
     pub rooms: Vec<ApiRoom>,
 }
 #[derive(Deserialize, Debug)]
@@ -77,6 +187,7 @@ pub struct DbLocation {
     pub location_id: String,
     pub description: String,
     pub label: String,
+    pub timezone: String,
 }
 
 /// Struct for inserting into the database
@@ -1,9 +1,155 @@
+use crate::utils::prelude::*;
+use chrono_tz::Tz;
+use color_eyre::eyre::bail;
 use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use uuid::Uuid;
 
 #[derive(Debug, Deserialize, Clone)]
 pub(crate) struct AppConfig {
     pub(crate) db: crate::db::DbConfig,
     pub(crate) api: ApiConfig,
+    /// Notifications are disabled when missing
+    pub(crate) notify: Option<NotifyConfig>,
+    /// Backfilling is disabled when missing
+    pub(crate) backfill: Option<BackfillConfig>,
+    /// Raw history is kept forever when missing
+    pub(crate) retention: Option<RetentionConfig>,
+    /// Http server is disabled when missing, requires the `server` feature
+    pub(crate) server: Option<ServerConfig>,
+    #[serde(default)]
+    pub(crate) cycles: CycleConfig,
+    #[serde(default)]
+    pub(crate) log: LogConfig,
+}
+
+impl AppConfig {
+    /// Checks what serde can not before anything connects, reporting every problem at once
+    pub(crate) fn validate(&self) -> Result<()> {
+        let mut problems = self.db.problems("db");
+
+        if self.api.endpoints.is_empty() {
+            problems.push("api.endpoints: at least one endpoint is required".to_string());
+        }
+        let mut seen = HashMap::new();
+        let mut timezones = HashMap::new();
+        for (index, endpoint) in self.api.endpoints.iter().enumerate() {
+            if Uuid::parse_str(&endpoint.location_id).is_err() {
+                problems.push(format!(
+                    "api.endpoints[{index}].location_id: {:?} is not a UUID",
+                    endpoint.location_id
+                ));
+            }
+            if let Some(timezone) = &endpoint.timezone {
+                if timezone.parse::<Tz>().is_err() {
+                    problems.push(format!(
+                        "api.endpoints[{index}].timezone: {timezone:?} is not an IANA timezone"
+                    ));
+                }
+                // Rooms of a location share its timezone
+                let (first, first_timezone) = *timezones
+                    .entry(&endpoint.location_id)
+                    .or_insert((index, timezone));
+                if first_timezone != timezone {
+                    problems.push(format!(
+                        "api.endpoints[{index}].timezone: differs from api.endpoints[{first}] of the same location"
+                    ));
+                }
+            }
+            let first = *seen
+                .entry((&endpoint.location_id, &endpoint.room_id))
+                .or_insert(index);
+            if first != index {
+                problems.push(format!(
+                    "api.endpoints[{index}]: duplicate of api.endpoints[{first}]"
+                ));
+            }
+        }
+
+        if self.cycles.resume_lookback < 1 {
+            problems.push("cycles.resume_lookback: must be at least 1".to_string());
+        }
+
+        if let Some(backfill) = &self.backfill {
+            if backfill.interval == 0 {
+                problems.push("backfill.interval: must be greater than 0".to_string());
+            }
+            // A step of zero or less never moves past the start of a gap
+            if backfill.step <= 0 {
+                problems.push("backfill.step: must be greater than 0".to_string());
+            }
+        }
+
+        if let Some(notify) = &self.notify {
+            if notify.check_interval == 0 {
+                problems.push("notify.check_interval: must be greater than 0".to_string());
+            }
+            if notify.db_failure_checks == 0 {
+                problems.push("notify.db_failure_checks: must be at least 1".to_string());
+            }
+            for (index, sink) in notify.sinks.iter().enumerate() {
+                let timeout = match sink {
+                    SinkConfig::Webhook { timeout, .. } | SinkConfig::Command { timeout, .. } => {
+                        *timeout
+                    }
+                    SinkConfig::Smtp(smtp) => smtp.timeout,
+                };
+                if timeout == 0 {
+                    problems.push(format!(
+                        "notify.sinks[{index}].timeout: must be greater than 0"
+                    ));
+                }
+            }
+        }
+
+        if let Some(retention) = &self.retention {
+            if retention.raw_days < 1 {
+                problems.push("retention.raw_days: must be at least 1".to_string());
+            }
+            if retention.interval == 0 {
+                problems.push("retention.interval: must be greater than 0".to_string());
+            }
+            // Backfilled rows older than the cutoff would replace a finished hour
+            if let Some(backfill) = &self.backfill
+                && retention.raw_days * 24 <= backfill.lookback
+            {
+                problems.push(format!(
+                    "retention.raw_days: must cover backfill.lookback, {} hours",
+                    backfill.lookback
+                ));
+            }
+        }
+
+        report(problems)
+    }
+}
+
+/// Part of `AppConfig` read by commands that only work on the database
+#[derive(Debug, Deserialize, Clone)]
+pub(crate) struct CommandConfig {
+    pub(crate) db: crate::db::DbConfig,
+    /// Database `copy` reads from, `db` is written to
+    pub(crate) source: Option<crate::db::DbConfig>,
+    #[serde(default)]
+    pub(crate) log: LogConfig,
+}
+
+impl CommandConfig {
+    pub(crate) fn validate(&self) -> Result<()> {
+        let mut problems = self.db.problems("db");
+        if let Some(source) = &self.source {
+            problems.extend(source.problems("source"));
+        }
+        report(problems)
+    }
+}
+
+fn report(problems: Vec<String>) -> Result<()> {
+    if problems.is_empty() {
+        return Ok(());
+    }
+    bail!("Invalid config:\n  {}", problems.join("\n  "))
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -15,6 +161,20 @@ pub(crate) struct ApiConfig {
     pub(crate) host: String,
     #[serde(default = "ApiConfig::default_api_port")]
     pub(crate) port: u16,
+    /// How raw response bodies are kept in `ApiLog`
+    #[serde(default = "ApiConfig::default_api_payloads")]
+    pub(crate) payloads: PayloadStorage,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum PayloadStorage {
+    /// Bodies are not stored
+    Off,
+    /// Bodies are stored as received
+    Raw,
+    /// Bodies are gzip compressed before storing
+    Gzip,
 }
 
 impl ApiConfig {
@@ -27,4 +187,360 @@ impl ApiConfig {
     fn default_api_port() -> u16 {
         443
     }
+    fn default_api_payloads() -> PayloadStorage {
+        PayloadStorage::Gzip
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub(crate) struct ServerConfig {
+    /// Address the http server listens on
+    #[serde(default = "ServerConfig::default_bind")]
+    pub(crate) bind: String,
+    /// Serves CSC's machines path from the latest stored rows
+    #[serde(default)]
+    pub(crate) csc_proxy: bool,
+    /// Exports prometheus metrics on `/metrics`
+    #[serde(default)]
+    pub(crate) metrics: bool,
+    /// Minutes without a successful poll before `/readyz` fails
+    #[serde(default = "ServerConfig::default_stale_after")]
+    pub(crate) stale_after: u32,
+}
+
+impl ServerConfig {
+    fn default_bind() -> String {
+        "0.0.0.0:8000".to_string()
+    }
+    fn default_stale_after() -> u32 {
+        5
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub(crate) struct LogConfig {
+    #[serde(default = "LogConfig::default_format")]
+    pub(crate) format: LogFormat,
+    /// Timezone of record timestamps
+    #[serde(default = "LogConfig::default_time")]
+    pub(crate) time: LogTime,
+    /// Default level, ignored when `RUST_LOG` is set
+    #[serde(default = "LogConfig::default_level")]
+    pub(crate) level: String,
+    /// Level per module path, e.g. `laundry_data::logic::http = "debug"`
+    #[serde(default)]
+    pub(crate) modules: HashMap<String, String>,
+    /// Also writes records to a file when set
+    pub(crate) file: Option<LogFileConfig>,
+    /// Exports spans over OTLP when set, requires the `otel` feature
+    pub(crate) otlp: Option<OtlpConfig>,
+}
+
+impl LogConfig {
+    fn default_format() -> LogFormat {
+        LogFormat::Text
+    }
+    fn default_time() -> LogTime {
+        LogTime::Utc
+    }
+    fn default_level() -> String {
+        "info".to_string()
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            format: Self::default_format(),
+            time: Self::default_time(),
+            level: Self::default_level(),
+            modules: HashMap::new(),
+            file: None,
+            otlp: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum LogFormat {
+    Text,
+    /// One JSON object per record, including the fields of every open span
+    Json,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum LogTime {
+    Local,
+    Utc,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub(crate) struct LogFileConfig {
+    pub(crate) directory: String,
+    /// File name before the rotation date suffix
+    #[serde(default = "LogFileConfig::default_prefix")]
+    pub(crate) prefix: String,
+    #[serde(default = "LogFileConfig::default_rotation")]
+    pub(crate) rotation: LogRotation,
+    /// Rotated files kept, older files are deleted. Keeps every file when missing
+    pub(crate) keep: Option<usize>,
+}
+
+impl LogFileConfig {
+    fn default_prefix() -> String {
+        "laundry-data.log".to_string()
+    }
+    fn default_rotation() -> LogRotation {
+        LogRotation::Daily
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum LogRotation {
+    Minutely,
+    Hourly,
+    Daily,
+    Never,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[cfg_attr(not(feature = "otel"), allow(dead_code))]
+pub(crate) struct OtlpConfig {
+    /// OTLP over http, the full traces url of a collector
+    #[serde(default = "OtlpConfig::default_endpoint")]
+    pub(crate) endpoint: String,
+    #[serde(default = "OtlpConfig::default_service_name")]
+    pub(crate) service_name: String,
+}
+
+impl OtlpConfig {
+    fn default_endpoint() -> String {
+        "http://localhost:4318/v1/traces".to_string()
+    }
+    fn default_service_name() -> String {
+        env!("CARGO_PKG_NAME").to_string()
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub(crate) struct CycleConfig {
+    /// Minutes after a cycle before laundry is assumed left inside
+    #[serde(default = "CycleConfig::default_left_after")]
+    pub(crate) left_after: i64,
+    /// Seconds, a door open this short does not count as emptying the machine
+    #[serde(default = "CycleConfig::default_brief_open")]
+    pub(crate) brief_open: i64,
+    /// Hours of history walked when a pep is first seen, older history is left as it is
+    #[serde(default = "CycleConfig::default_resume_lookback")]
+    pub(crate) resume_lookback: i64,
+}
+
+impl CycleConfig {
+    fn default_left_after() -> i64 {
+        15
+    }
+    fn default_brief_open() -> i64 {
+        90
+    }
+    fn default_resume_lookback() -> i64 {
+        24
+    }
+}
+
+impl Default for CycleConfig {
+    fn default() -> Self {
+        Self {
+            left_after: Self::default_left_after(),
+            brief_open: Self::default_brief_open(),
+            resume_lookback: Self::default_resume_lookback(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub(crate) struct BackfillConfig {
+    /// Seconds between backfill runs
+    #[serde(default = "BackfillConfig::default_interval")]
+    pub(crate) interval: u64,
+    /// Seconds between synthesized rows
+    #[serde(default = "BackfillConfig::default_step")]
+    pub(crate) step: i64,
+    /// Seconds without a row before a gap is filled
+    #[serde(default = "BackfillConfig::default_gap_after")]
+    pub(crate) gap_after: i64,
+    /// Minutes, longer gaps are left empty. Stops a removed machine from being backfilled forever.
+    #[serde(default = "BackfillConfig::default_max_gap")]
+    pub(crate) max_gap: i64,
+    /// Hours of history checked on each run
+    #[serde(default = "BackfillConfig::default_lookback")]
+    pub(crate) lookback: i64,
+}
+
+impl BackfillConfig {
+    fn default_interval() -> u64 {
+        300
+    }
+    fn default_step() -> i64 {
+        60
+    }
+    fn default_gap_after() -> i64 {
+        180
+    }
+    fn default_max_gap() -> i64 {
+        360
+    }
+    fn default_lookback() -> i64 {
+        24
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub(crate) struct RetentionConfig {
+    /// Days of raw `LaundryLog` kept, older rows are rolled into `LaundryLogHourly` and deleted
+    #[serde(default = "RetentionConfig::default_raw_days")]
+    pub(crate) raw_days: i64,
+    /// Seconds between runs on sqlite and mssql. Timescale schedules its own jobs
+    #[serde(default = "RetentionConfig::default_interval")]
+    pub(crate) interval: u64,
+}
+
+impl RetentionConfig {
+    fn default_raw_days() -> i64 {
+        30
+    }
+    fn default_interval() -> u64 {
+        3600
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub(crate) struct NotifyConfig {
+    /// Consecutive failed requests for a room before alerting
+    #[serde(default = "NotifyConfig::default_consecutive_errors")]
+    pub(crate) consecutive_errors: u32,
+    /// Minutes without a successful poll for a room before alerting
+    #[serde(default = "NotifyConfig::default_stale_after")]
+    pub(crate) stale_after: u32,
+    /// Seconds between condition checks
+    #[serde(default = "NotifyConfig::default_check_interval")]
+    pub(crate) check_interval: u64,
+    /// Minutes before an alert that is still firing is sent again
+    #[serde(default = "NotifyConfig::default_repeat_after")]
+    pub(crate) repeat_after: u32,
+    /// Maximum alerts sent per hour across all conditions
+    #[serde(default = "NotifyConfig::default_max_per_hour")]
+    pub(crate) max_per_hour: u32,
+    /// Consecutive checks with a database failure before alerting, single failures are usually
+    /// transient
+    #[serde(default = "NotifyConfig::default_db_failure_checks")]
+    pub(crate) db_failure_checks: u32,
+    /// Alert for machines with laundry probably left inside
+    #[serde(default)]
+    pub(crate) left_laundry: bool,
+    pub(crate) sinks: Vec<SinkConfig>,
+}
+
+impl NotifyConfig {
+    fn default_consecutive_errors() -> u32 {
+        5
+    }
+    fn default_stale_after() -> u32 {
+        15
+    }
+    fn default_check_interval() -> u64 {
+        60
+    }
+    fn default_repeat_after() -> u32 {
+        60
+    }
+    fn default_max_per_hour() -> u32 {
+        10
+    }
+    fn default_db_failure_checks() -> u32 {
+        3
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub(crate) enum SinkConfig {
+    /// POSTs the alert as JSON
+    Webhook {
+        url: String,
+        /// Seconds before the request is abandoned
+        #[serde(default = "SinkConfig::default_webhook_timeout")]
+        timeout: u64,
+    },
+    /// Sends the alert as a plain text email
+    Smtp(SmtpConfig),
+    /// Runs a program with the alert as JSON on stdin
+    Command {
+        program: String,
+        #[serde(default)]
+        args: Vec<String>,
+        /// Seconds before the program is killed
+        #[serde(default = "SinkConfig::default_command_timeout")]
+        timeout: u64,
+    },
+}
+
+impl SinkConfig {
+    fn default_webhook_timeout() -> u64 {
+        10
+    }
+    fn default_command_timeout() -> u64 {
+        30
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub(crate) struct SmtpConfig {
+    pub(crate) host: String,
+    pub(crate) port: Option<u16>,
+    #[serde(default = "SmtpConfig::default_tls")]
+    pub(crate) tls: SmtpTls,
+    #[serde(default, deserialize_with = "crate::models::secret::optional")]
+    pub(crate) user_name: Option<String>,
+    #[serde(default, deserialize_with = "crate::models::secret::optional")]
+    pub(crate) password: Option<String>,
+    pub(crate) from: String,
+    pub(crate) to: Vec<String>,
+    /// Seconds before connecting or sending is abandoned
+    #[serde(default = "SmtpConfig::default_timeout")]
+    pub(crate) timeout: u64,
+}
+
+impl SmtpConfig {
+    fn default_tls() -> SmtpTls {
+        SmtpTls::Starttls
+    }
+    fn default_timeout() -> u64 {
+        30
+    }
+}
+
+// Hide user name and password from debug
+impl Debug for SmtpConfig {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SmtpConfig")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("tls", &self.tls)
+            .field("from", &self.from)
+            .field("to", &self.to)
+            .field("timeout", &self.timeout)
+            .finish()
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum SmtpTls {
+    /// Plain text, only for local relays and testing
+    None,
+    Starttls,
+    Tls,
 }
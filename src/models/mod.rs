@@ -1,2 +1,3 @@
 pub(crate) mod api;
-pub(crate) mod config;
\ No newline at end of file
+pub(crate) mod config;
+pub(crate) mod secret;
\ No newline at end of file
@@ -0,0 +1,26 @@
+use serde::de::Error;
+use serde::{Deserialize, Deserializer};
+use std::path::PathBuf;
+
+/// A sensitive value written inline, or read from a mounted secret with `{ file = "..." }`.
+/// With the `_` env separator, `DB_PASS_FILE` becomes `db.pass.file`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Secret {
+    Value(String),
+    File { file: PathBuf },
+}
+
+/// For optional fields, use with `#[serde(default)]`.
+/// Files are read once while the config is loaded, surrounding whitespace is trimmed.
+pub(crate) fn optional<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<String>, D::Error> {
+    match Option::<Secret>::deserialize(deserializer)? {
+        Some(Secret::Value(v)) => Ok(Some(v)),
+        Some(Secret::File { file }) => std::fs::read_to_string(&file)
+            .map(|v| Some(v.trim().to_string()))
+            .map_err(|e| D::Error::custom(format!("Failed to read {}: {e}", file.display()))),
+        None => Ok(None),
+    }
+}
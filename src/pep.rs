@@ -1,3 +1,4 @@
+use crate::models::api::Machine;
 use crate::utils::prelude::*;
 use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
 use uuid::Uuid;
@@ -30,3 +31,16 @@ impl PhysicalEndpointId {
         Ok(URL_SAFE_NO_PAD.encode(hash.to_le_bytes()))
     }
 }
+
+impl TryFrom<&Machine> for PhysicalEndpointId {
+    type Error = Report;
+
+    fn try_from(machine: &Machine) -> Result<Self> {
+        Ok(Self {
+            sticker_number: u32::try_from(machine.sticker_number)?,
+            machine_id: machine.opaque_id,
+            room_id: machine.room_id.clone(),
+            location_id: machine.location_id,
+        })
+    }
+}
@@ -0,0 +1,575 @@
+use crate::db::DbType;
+use crate::logic::db::{value_as_int, value_as_timestamp};
+use crate::models::api::{MachineType, ModeType};
+use crate::server::{ApiError, AppState, first_value};
+use crate::utils::prelude::*;
+use axum::extract::{Path, State};
+use axum::http::HeaderMap;
+use axum::http::header::AUTHORIZATION;
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use chrono::{NaiveDateTime, TimeDelta, Utc};
+use color_eyre::eyre::OptionExt;
+use serde::{Deserialize, Serialize};
+use sql_middleware::{MiddlewarePoolConnection, QueryAndParams, RowValues};
+
+/// A machine can only be claimed if its latest poll is newer than this
+const MAX_STATUS_AGE_MINUTES: i64 = 5;
+
+pub(super) fn router() -> Router<AppState> {
+    Router::new()
+        .route("/api/v1/users", post(create_user))
+        .route("/api/v1/users/{user_id}/loads", get(list_loads))
+        .route("/api/v1/users/{user_id}/claims", post(claim_cycle))
+}
+
+#[derive(Deserialize)]
+struct NewUser {
+    name: String,
+}
+
+#[derive(Serialize)]
+struct User {
+    user_id: i64,
+    name: String,
+    created_on: NaiveDateTime,
+    /// Sent as `Authorization: Bearer <token>`, only returned here
+    token: String,
+}
+
+/// A machine by pep, or by its sticker number in a room
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum MachineRef {
+    Pep {
+        pep_id: String,
+    },
+    Sticker {
+        location_id: String,
+        room_id: String,
+        sticker_number: i64,
+    },
+}
+
+#[derive(Deserialize)]
+struct NewClaim {
+    #[serde(flatten)]
+    machine: MachineRef,
+    /// Adds the cycle to an existing load, e.g. a dryer cycle after its washer cycle
+    load_id: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct ClaimedCycle {
+    pep_id: String,
+    cycle_start: NaiveDateTime,
+}
+
+/// Rows written for a claim, in one transaction
+struct Claim {
+    user_id: i64,
+    /// A new load is created when missing
+    load_id: Option<i64>,
+    cycle_table: &'static str,
+    insert_cycle: &'static str,
+    pep_id: String,
+    cycle_start: NaiveDateTime,
+    /// The log row seen when claiming
+    timestamp: NaiveDateTime,
+    claimed_on: NaiveDateTime,
+}
+
+#[derive(Serialize)]
+struct LaundryLoad {
+    load_id: i64,
+    created_on: NaiveDateTime,
+    washer_cycles: Vec<ClaimedCycle>,
+    dryer_cycles: Vec<ClaimedCycle>,
+}
+
+#[instrument(skip_all)]
+async fn create_user(
+    State(state): State<AppState>,
+    Json(body): Json<NewUser>,
+) -> Result<Json<User>, ApiError> {
+    let name = body.name.trim().to_string();
+    if name.is_empty() {
+        return Err(ApiError::BadRequest("name must not be empty".to_string()));
+    }
+
+    let mut conn = state.connection().await?;
+    let existing = first_value(
+        &mut conn,
+        SELECT_USER_BY_NAME_QUERY,
+        vec![RowValues::Text(name.clone())],
+    )
+    .await?;
+    if existing.is_some() {
+        return Err(ApiError::Conflict(format!("user {name} already exists")));
+    }
+
+    let created_on = Utc::now().naive_utc();
+    let token = URL_SAFE_NO_PAD.encode(rand::random::<[u8; 32]>());
+    let user_id = first_value(
+        &mut conn,
+        insert_user_query(state.db_type),
+        vec![
+            RowValues::Text(name.clone()),    // name
+            RowValues::Timestamp(created_on), // created_on
+            RowValues::Text(token.clone()),   // token
+        ],
+    )
+    .await
+    .map_err(ApiError::unique_conflict(format!(
+        "user {name} already exists"
+    )))?
+    .as_ref()
+    .and_then(value_as_int)
+    .ok_or_eyre("Insert did not return user_id")?;
+
+    Ok(Json(User {
+        user_id,
+        name,
+        created_on,
+        token,
+    }))
+}
+
+#[instrument(skip_all)]
+async fn list_loads(
+    State(state): State<AppState>,
+    Path(user_id): Path<i64>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<LaundryLoad>>, ApiError> {
+    let mut conn = state.connection().await?;
+    authorize(&mut conn, &headers, user_id).await?;
+    Ok(Json(user_loads(&mut conn, user_id).await?))
+}
+
+/// Claims the cycle currently running on a machine. Creates a new load unless `load_id` is given.
+#[instrument(skip_all)]
+async fn claim_cycle(
+    State(state): State<AppState>,
+    Path(user_id): Path<i64>,
+    headers: HeaderMap,
+    Json(body): Json<NewClaim>,
+) -> Result<Json<LaundryLoad>, ApiError> {
+    let mut conn = state.connection().await?;
+    authorize(&mut conn, &headers, user_id).await?;
+
+    let pep_id = resolve_pep(&mut conn, body.machine).await?;
+    let machine_type = first_value(
+        &mut conn,
+        SELECT_MACHINE_TYPE_QUERY,
+        vec![RowValues::Text(pep_id.clone())],
+    )
+    .await?
+    .and_then(|v| v.as_text().map(str::to_string))
+    .ok_or_else(|| ApiError::NotFound(format!("machine {pep_id} not found")))?;
+    let (select_cycle, insert_cycle, cycle_table) = match MachineType::from(machine_type) {
+        MachineType::Washer => (
+            SELECT_WASHER_CYCLE_QUERY,
+            INSERT_WASHER_CYCLE_QUERY,
+            "washerlaundryloads",
+        ),
+        MachineType::Dryer => (
+            SELECT_DRYER_CYCLE_QUERY,
+            INSERT_DRYER_CYCLE_QUERY,
+            "dryerlaundryload",
+        ),
+        MachineType::Other(v) => {
+            return Err(ApiError::BadRequest(format!(
+                "{v} machines can not be claimed"
+            )));
+        }
+    };
+
+    // Only the latest real poll counts, backfilled rows are estimates
+    let now = Utc::now().naive_utc();
+    let (timestamp, state_value) = latest_status(&mut conn, &pep_id)
+        .await?
+        .ok_or_else(|| ApiError::NotFound(format!("no status for machine {pep_id}")))?;
+    if now - timestamp > TimeDelta::minutes(MAX_STATUS_AGE_MINUTES) {
+        return Err(ApiError::Conflict(format!(
+            "status for machine {pep_id} is out of date"
+        )));
+    }
+    if ModeType::from(state_value) != ModeType::Running {
+        return Err(ApiError::Conflict(format!(
+            "machine {pep_id} is not running"
+        )));
+    }
+
+    // Cycles are detected as polls are written, see `logic::cycles`
+    let cycle_start = first_value(
+        &mut conn,
+        SELECT_OPEN_CYCLE_QUERY,
+        vec![RowValues::Text(pep_id.clone())],
+    )
+    .await?
+    .as_ref()
+    .and_then(value_as_timestamp)
+    .ok_or_else(|| ApiError::Conflict(format!("no running cycle on machine {pep_id}")))?;
+    let claimed = first_value(
+        &mut conn,
+        select_cycle,
+        vec![
+            RowValues::Text(pep_id.clone()),
+            RowValues::Timestamp(cycle_start),
+        ],
+    )
+    .await?;
+    if claimed.is_some() {
+        return Err(ApiError::Conflict(format!(
+            "cycle on machine {pep_id} is already claimed"
+        )));
+    }
+
+    if let Some(load_id) = body.load_id {
+        let owner = first_value(
+            &mut conn,
+            SELECT_LOAD_OWNER_QUERY,
+            vec![RowValues::Int(load_id)],
+        )
+        .await?
+        .as_ref()
+        .and_then(value_as_int);
+        // Another user's load is reported as missing
+        if owner != Some(user_id) {
+            return Err(ApiError::NotFound(format!("load {load_id} not found")));
+        }
+    }
+
+    let claim = Claim {
+        user_id,
+        load_id: body.load_id,
+        cycle_table,
+        insert_cycle,
+        pep_id: pep_id.clone(),
+        cycle_start,
+        timestamp,
+        claimed_on: now,
+    };
+    // Spawned so a dropped request can not leave a transaction open on a pooled connection
+    let db_type = state.db_type;
+    let (mut conn, load_id) = tokio::spawn(async move {
+        let load_id = insert_claim(&mut conn, db_type, &claim).await;
+        (conn, load_id)
+    })
+    .await?;
+    // Lost a race with another claim of the same cycle
+    let load_id = load_id.map_err(ApiError::unique_conflict(format!(
+        "cycle on machine {pep_id} is already claimed"
+    )))?;
+    info!(%pep_id, load_id, "Cycle claimed");
+
+    user_loads(&mut conn, user_id)
+        .await?
+        .into_iter()
+        .find(|v| v.load_id == load_id)
+        .map(Json)
+        .ok_or_else(|| ApiError::NotFound(format!("load {load_id} not found")))
+}
+
+/// Checks `Authorization: Bearer <token>` against the user's token. An unknown user and a wrong
+/// token are not told apart.
+async fn authorize(
+    conn: &mut MiddlewarePoolConnection,
+    headers: &HeaderMap,
+    user_id: i64,
+) -> Result<(), ApiError> {
+    let token = headers
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .ok_or_else(|| ApiError::Unauthorized("missing bearer token".to_string()))?;
+    let params = vec![
+        RowValues::Int(user_id),
+        RowValues::Text(token.trim().to_string()),
+    ];
+    match first_value(conn, SELECT_USER_TOKEN_QUERY, params).await? {
+        Some(_) => Ok(()),
+        None => Err(ApiError::Unauthorized(format!(
+            "invalid token for user {user_id}"
+        ))),
+    }
+}
+
+async fn resolve_pep(
+    conn: &mut MiddlewarePoolConnection,
+    machine: MachineRef,
+) -> Result<String, ApiError> {
+    let (query, params, name) = match machine {
+        MachineRef::Pep { pep_id } => (
+            SELECT_PEP_QUERY,
+            vec![RowValues::Text(pep_id.clone())],
+            pep_id,
+        ),
+        // A replaced machine keeps its sticker, the newest pep wins
+        MachineRef::Sticker {
+            location_id,
+            room_id,
+            sticker_number,
+        } => (
+            SELECT_PEP_BY_STICKER_QUERY,
+            vec![
+                RowValues::Text(location_id),
+                RowValues::Text(room_id.clone()),
+                RowValues::Int(sticker_number),
+            ],
+            format!("{room_id} #{sticker_number}"),
+        ),
+    };
+
+    first_value(conn, query, params)
+        .await?
+        .and_then(|v| v.as_text().map(str::to_string))
+        .ok_or_else(|| ApiError::NotFound(format!("machine {name} not found")))
+}
+
+/// Timestamp and state of the latest real poll
+async fn latest_status(
+    conn: &mut MiddlewarePoolConnection,
+    pep_id: &str,
+) -> Result<Option<(NaiveDateTime, String)>> {
+    let query = QueryAndParams::new(
+        SELECT_LATEST_STATUS_QUERY,
+        vec![
+            RowValues::Text(pep_id.to_string()),
+            RowValues::Text(pep_id.to_string()),
+            RowValues::Bool(false),
+        ],
+    );
+    let result = conn
+        .query(&query.query)
+        .params(&query.params)
+        .select()
+        .await?;
+    let Some(row) = result.results.first() else {
+        return Ok(None);
+    };
+
+    let timestamp = row
+        .get_by_index(0)
+        .and_then(value_as_timestamp)
+        .ok_or_eyre("Invalid timestamp")?;
+    let state = row
+        .get_by_index(1)
+        .and_then(|v| v.as_text())
+        .ok_or_eyre("Invalid state")?
+        .to_string();
+    Ok(Some((timestamp, state)))
+}
+
+/// Writes the load, the cycle and the log claim in one transaction and returns the load id
+async fn insert_claim(
+    conn: &mut MiddlewarePoolConnection,
+    db_type: DbType,
+    claim: &Claim,
+) -> Result<i64> {
+    if db_type == DbType::Mssql {
+        return first_value(
+            conn,
+            &mssql_claim_query(claim.cycle_table),
+            vec![
+                claim.load_id.map_or(RowValues::Null, RowValues::Int), // load_id
+                RowValues::Int(claim.user_id),                         // user_id
+                RowValues::Timestamp(claim.claimed_on),                // created_on, claimed_on
+                RowValues::Text(claim.pep_id.clone()),                 // pep_id
+                RowValues::Timestamp(claim.cycle_start),               // cycle_start
+                RowValues::Timestamp(claim.timestamp),                 // timestamp
+            ],
+        )
+        .await?
+        .as_ref()
+        .and_then(value_as_int)
+        .ok_or_eyre("Claim did not return load_id");
+    }
+
+    execute(conn, "BEGIN", vec![]).await?;
+    match insert_claim_rows(conn, claim).await {
+        Ok(load_id) => {
+            execute(conn, "COMMIT", vec![]).await?;
+            Ok(load_id)
+        }
+        Err(e) => {
+            if let Err(rollback) = execute(conn, "ROLLBACK", vec![]).await {
+                warn!("Failed to roll back claim: {:?}", rollback);
+            }
+            Err(e)
+        }
+    }
+}
+
+async fn insert_claim_rows(conn: &mut MiddlewarePoolConnection, claim: &Claim) -> Result<i64> {
+    let load_id = match claim.load_id {
+        Some(v) => v,
+        None => first_value(
+            conn,
+            INSERT_LOAD_QUERY,
+            vec![
+                RowValues::Int(claim.user_id),          // user_id
+                RowValues::Timestamp(claim.claimed_on), // created_on
+            ],
+        )
+        .await?
+        .as_ref()
+        .and_then(value_as_int)
+        .ok_or_eyre("Insert did not return load_id")?,
+    };
+
+    execute(
+        conn,
+        claim.insert_cycle,
+        vec![
+            RowValues::Int(load_id),                 // load_id
+            RowValues::Text(claim.pep_id.clone()),   // pep_id
+            RowValues::Timestamp(claim.cycle_start), // cycle_start
+        ],
+    )
+    .await?;
+    execute(
+        conn,
+        INSERT_LOG_CLAIM_QUERY,
+        vec![
+            RowValues::Int(claim.user_id),          // user_id
+            RowValues::Text(claim.pep_id.clone()),  // pep_id
+            RowValues::Timestamp(claim.timestamp),  // timestamp
+            RowValues::Timestamp(claim.claimed_on), // claimed_on
+        ],
+    )
+    .await?;
+    Ok(load_id)
+}
+
+async fn user_loads(conn: &mut MiddlewarePoolConnection, user_id: i64) -> Result<Vec<LaundryLoad>> {
+    let result = conn
+        .query(SELECT_LOADS_QUERY)
+        .params(&[RowValues::Int(user_id)])
+        .select()
+        .await?;
+    let mut loads = vec![];
+    for row in result.results.iter() {
+        loads.push(LaundryLoad {
+            load_id: row
+                .get_by_index(0)
+                .and_then(value_as_int)
+                .ok_or_eyre("Invalid load_id")?,
+            created_on: row
+                .get_by_index(1)
+                .and_then(value_as_timestamp)
+                .ok_or_eyre("Invalid created_on")?,
+            washer_cycles: vec![],
+            dryer_cycles: vec![],
+        });
+    }
+
+    for (query, is_washer) in [
+        (SELECT_USER_WASHER_CYCLES_QUERY, true),
+        (SELECT_USER_DRYER_CYCLES_QUERY, false),
+    ] {
+        let result = conn
+            .query(query)
+            .params(&[RowValues::Int(user_id)])
+            .select()
+            .await?;
+        for row in result.results.iter() {
+            let load_id = row
+                .get_by_index(0)
+                .and_then(value_as_int)
+                .ok_or_eyre("Invalid load_id")?;
+            let cycle = ClaimedCycle {
+                pep_id: row
+                    .get_by_index(1)
+                    .and_then(|v| v.as_text())
+                    .ok_or_eyre("Invalid pep_id")?
+                    .to_string(),
+                cycle_start: row
+                    .get_by_index(2)
+                    .and_then(value_as_timestamp)
+                    .ok_or_eyre("Invalid cycle_start")?,
+            };
+            if let Some(load) = loads.iter_mut().find(|v| v.load_id == load_id) {
+                match is_washer {
+                    true => load.washer_cycles.push(cycle),
+                    false => load.dryer_cycles.push(cycle),
+                }
+            }
+        }
+    }
+
+    Ok(loads)
+}
+
+async fn execute(
+    conn: &mut MiddlewarePoolConnection,
+    query: &str,
+    params: Vec<RowValues>,
+) -> Result<()> {
+    conn.query(query).params(&params).dml().await?;
+    Ok(())
+}
+
+/// Postgres and sqlite use `RETURNING`, mssql requires `OUTPUT`
+fn insert_user_query(db_type: DbType) -> &'static str {
+    match db_type {
+        DbType::Mssql => MSSQL_INSERT_USER_QUERY,
+        _ => INSERT_USER_QUERY,
+    }
+}
+
+/// Mssql runs every parameterized query on its own, so the claim is a single batch that rolls
+/// back on any error. `$1` is the load id, null for a new load.
+fn mssql_claim_query(cycle_table: &str) -> String {
+    format!(
+        "SET NOCOUNT ON; SET XACT_ABORT ON; BEGIN TRANSACTION; \
+        DECLARE @load_id BIGINT = $1; \
+        IF @load_id IS NULL BEGIN \
+        INSERT INTO laundryload(user_id, created_on) VALUES ($2, $3); \
+        SET @load_id = SCOPE_IDENTITY(); END; \
+        INSERT INTO {cycle_table}(load_id, pep_id, cycle_start) VALUES (@load_id, $4, $5); \
+        INSERT INTO userlogclaim(user_id, pep_id, \"timestamp\", claimed_on) VALUES ($2, $4, $6, $3); \
+        COMMIT TRANSACTION; SELECT @load_id"
+    )
+}
+
+const SELECT_USER_TOKEN_QUERY: &str = "SELECT user_id FROM users WHERE user_id = $1 AND token = $2";
+const SELECT_USER_BY_NAME_QUERY: &str = "SELECT user_id FROM users WHERE name = $1";
+const INSERT_USER_QUERY: &str =
+    "INSERT INTO users(name, created_on, token) VALUES ($1, $2, $3) RETURNING user_id";
+const MSSQL_INSERT_USER_QUERY: &str =
+    "INSERT INTO users(name, created_on, token) OUTPUT INSERTED.user_id VALUES ($1, $2, $3)";
+
+const SELECT_PEP_QUERY: &str = "SELECT pep_id FROM physicalendpoint WHERE pep_id = $1";
+const SELECT_PEP_BY_STICKER_QUERY: &str = "SELECT pep_id FROM physicalendpoint \
+    WHERE location_id = $1 AND room_id = $2 AND sticker_number = $3 ORDER BY added_on DESC";
+const SELECT_MACHINE_TYPE_QUERY: &str = "SELECT m.type FROM physicalendpoint p \
+    JOIN machines m ON p.machine_id = m.machine_id WHERE p.pep_id = $1";
+
+const SELECT_LATEST_STATUS_QUERY: &str = "SELECT \"timestamp\", state FROM laundrylog \
+    WHERE pep_id = $1 AND \"timestamp\" = (SELECT MAX(\"timestamp\") FROM laundrylog WHERE pep_id = $2 AND backfilled = $3)";
+const SELECT_OPEN_CYCLE_QUERY: &str =
+    "SELECT MAX(cycle_start) FROM machinecycles WHERE pep_id = $1 AND cycle_end IS NULL";
+
+const SELECT_LOAD_OWNER_QUERY: &str = "SELECT user_id FROM laundryload WHERE load_id = $1";
+const INSERT_LOAD_QUERY: &str =
+    "INSERT INTO laundryload(user_id, created_on) VALUES ($1, $2) RETURNING load_id";
+const SELECT_LOADS_QUERY: &str =
+    "SELECT load_id, created_on FROM laundryload WHERE user_id = $1 ORDER BY created_on DESC";
+
+const SELECT_WASHER_CYCLE_QUERY: &str =
+    "SELECT load_id FROM washerlaundryloads WHERE pep_id = $1 AND cycle_start = $2";
+const SELECT_DRYER_CYCLE_QUERY: &str =
+    "SELECT load_id FROM dryerlaundryload WHERE pep_id = $1 AND cycle_start = $2";
+const INSERT_WASHER_CYCLE_QUERY: &str =
+    "INSERT INTO washerlaundryloads(load_id, pep_id, cycle_start) VALUES ($1, $2, $3)";
+const INSERT_DRYER_CYCLE_QUERY: &str =
+    "INSERT INTO dryerlaundryload(load_id, pep_id, cycle_start) VALUES ($1, $2, $3)";
+const SELECT_USER_WASHER_CYCLES_QUERY: &str = "SELECT w.load_id, w.pep_id, w.cycle_start FROM washerlaundryloads w \
+    JOIN laundryload l ON w.load_id = l.load_id WHERE l.user_id = $1 ORDER BY w.cycle_start";
+const SELECT_USER_DRYER_CYCLES_QUERY: &str = "SELECT d.load_id, d.pep_id, d.cycle_start FROM dryerlaundryload d \
+    JOIN laundryload l ON d.load_id = l.load_id WHERE l.user_id = $1 ORDER BY d.cycle_start";
+
+const INSERT_LOG_CLAIM_QUERY: &str = "INSERT INTO userlogclaim(user_id, pep_id, \"timestamp\", claimed_on) \
+    VALUES ($1, $2, $3, $4)";
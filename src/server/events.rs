@@ -0,0 +1,72 @@
+use crate::server::AppState;
+use crate::types::{StateChange, StateReceiver};
+use crate::utils::prelude::*;
+use axum::Router;
+use axum::extract::{Query, State};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::routing::get;
+use futures_util::Stream;
+use futures_util::stream::unfold;
+use serde::Deserialize;
+use tokio::sync::broadcast::error::RecvError;
+
+pub(super) fn router() -> Router<AppState> {
+    Router::new().route("/api/v1/events", get(events))
+}
+
+/// Optional filters, a missing filter matches everything
+#[derive(Deserialize)]
+struct EventFilter {
+    location_id: Option<String>,
+    room_id: Option<String>,
+}
+
+impl EventFilter {
+    fn matches(&self, change: &StateChange) -> bool {
+        self.location_id
+            .as_ref()
+            .is_none_or(|v| *v == change.location_id)
+            && self.room_id.as_ref().is_none_or(|v| *v == change.room_id)
+    }
+}
+
+/// Streams state changes as they are ingested. Nothing is replayed on connect.
+#[instrument(skip_all)]
+async fn events(
+    State(state): State<AppState>,
+    Query(filter): Query<EventFilter>,
+) -> Sse<impl Stream<Item = Result<Event, axum::Error>>> {
+    let rx = state.state_tx.subscribe();
+    Sse::new(event_stream(rx, state.cancel_token, filter)).keep_alive(KeepAlive::default())
+}
+
+fn event_stream(
+    rx: StateReceiver,
+    cancel_token: CancellationToken,
+    filter: EventFilter,
+) -> impl Stream<Item = Result<Event, axum::Error>> {
+    unfold(
+        (rx, cancel_token, filter),
+        |(mut rx, cancel_token, filter)| async move {
+            loop {
+                let received = tokio::select! {
+                    _ = cancel_token.cancelled() => return None,
+                    v = rx.recv() => v,
+                };
+                let event = match received {
+                    Ok(change) if filter.matches(&change) => {
+                        Event::default().event("state").json_data(&change)
+                    }
+                    Ok(_) => continue,
+                    // Slow client, tell it how many changes were dropped and keep going
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("Event stream lagged, skipped {} changes", skipped);
+                        Ok(Event::default().event("lagged").data(skipped.to_string()))
+                    }
+                    Err(RecvError::Closed) => return None,
+                };
+                return Some((event, (rx, cancel_token, filter)));
+            }
+        },
+    )
+}
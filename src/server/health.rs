@@ -0,0 +1,99 @@
+use crate::db::ping;
+use crate::logic::health::Controller;
+use crate::models::config::ServerConfig;
+use crate::server::AppState;
+use crate::utils::prelude::*;
+use axum::Router;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::Json;
+use axum::routing::get;
+use serde::Serialize;
+use std::time::Duration;
+
+/// A database that does not answer in time is treated as unreachable
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub(super) fn router(config: &ServerConfig) -> Router<AppState> {
+    let stale_after = i64::from(config.stale_after) * 60;
+    Router::new().route("/healthz", get(healthz)).route(
+        "/readyz",
+        get(move |state: State<AppState>| readyz(state, stale_after)),
+    )
+}
+
+#[derive(Serialize)]
+struct HealthBody {
+    status: &'static str,
+    checks: Vec<Check>,
+}
+
+#[derive(Serialize)]
+struct Check {
+    name: &'static str,
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+}
+
+impl Check {
+    fn new(name: &'static str, ok: bool, detail: Option<String>) -> Self {
+        Self { name, ok, detail }
+    }
+}
+
+/// 200 when every check passed, otherwise 503
+fn respond(checks: Vec<Check>) -> (StatusCode, Json<HealthBody>) {
+    let (status, body) = match checks.iter().all(|v| v.ok) {
+        true => (StatusCode::OK, "ok"),
+        false => (StatusCode::SERVICE_UNAVAILABLE, "unavailable"),
+    };
+    (
+        status,
+        Json(HealthBody {
+            status: body,
+            checks,
+        }),
+    )
+}
+
+/// The process is up and both controllers are still running
+async fn healthz(State(state): State<AppState>) -> (StatusCode, Json<HealthBody>) {
+    respond(vec![
+        Check::new(
+            "db_controller",
+            state.health.is_running(Controller::Db),
+            None,
+        ),
+        Check::new(
+            "http_controller",
+            state.health.is_running(Controller::Http),
+            None,
+        ),
+    ])
+}
+
+/// The database is reachable and a poll was stored recently.
+/// Migrations are applied before the server starts.
+#[instrument(skip_all)]
+async fn readyz(State(state): State<AppState>, stale_after: i64) -> (StatusCode, Json<HealthBody>) {
+    let database = match tokio::time::timeout(PING_TIMEOUT, ping(&state.pool)).await {
+        Ok(Ok(())) => Check::new("database", true, None),
+        Ok(Err(e)) => {
+            warn!("Database ping failed: {:?}", e);
+            Check::new("database", false, Some(e.to_string()))
+        }
+        Err(_) => Check::new("database", false, Some("timed out".to_string())),
+    };
+
+    let poll = match state.health.poll_age() {
+        Some(age) => Check::new(
+            "poll",
+            age <= stale_after,
+            Some(format!("last successful poll {age} seconds ago")),
+        ),
+        None => Check::new("poll", false, Some("no successful poll yet".to_string())),
+    };
+
+    respond(vec![database, poll])
+}
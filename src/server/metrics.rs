@@ -0,0 +1,37 @@
+use crate::logic::metrics::describe;
+use crate::server::AppState;
+use crate::utils::prelude::*;
+use axum::Router;
+use axum::routing::get;
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+
+/// Seconds, covers both fast db writes and slow CSC requests
+const DURATION_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+/// Installs the global prometheus recorder. Metrics recorded before this are lost.
+pub(crate) fn install() -> Result<PrometheusHandle> {
+    let handle = PrometheusBuilder::new()
+        .set_buckets_for_metric(
+            Matcher::Suffix("duration_seconds".to_string()),
+            DURATION_BUCKETS,
+        )?
+        .install_recorder()?;
+    describe();
+    Ok(handle)
+}
+
+pub(super) fn router(handle: PrometheusHandle) -> Router<AppState> {
+    Router::new().route(
+        "/metrics",
+        get(move || {
+            let handle = handle.clone();
+            async move {
+                // Nothing else drains histogram buckets without the exporter's own listener
+                handle.run_upkeep();
+                handle.render()
+            }
+        }),
+    )
+}
@@ -0,0 +1,160 @@
+mod claims;
+mod events;
+mod health;
+mod metrics;
+mod proxy;
+mod query;
+
+use crate::db::{Connection, DbType, Pool};
+use crate::logic::health::Health;
+use crate::models::config::ServerConfig;
+use crate::types::StateSender;
+use crate::utils::prelude::*;
+use axum::Json;
+use axum::Router;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use metrics_exporter_prometheus::PrometheusHandle;
+use serde::Serialize;
+use sql_middleware::{MiddlewarePoolConnection, RowValues};
+use tokio::net::TcpListener;
+
+pub(crate) use self::metrics::install as install_metrics;
+
+/// Shared state for every handler
+#[derive(Clone)]
+pub(crate) struct AppState {
+    pub(crate) db_type: DbType,
+    pub(crate) pool: Pool,
+    /// Subscribed to by every event stream
+    pub(crate) state_tx: StateSender,
+    pub(crate) health: Health,
+    /// Ends event streams on shutdown, graceful shutdown waits for open connections
+    pub(crate) cancel_token: CancellationToken,
+}
+
+impl AppState {
+    async fn connection(&self) -> Result<Connection, ApiError> {
+        Ok(self.pool.get_connection().await?)
+    }
+}
+
+/// Error returned by handlers, rendered as `{"error": "..."}`
+pub(crate) enum ApiError {
+    BadRequest(String),
+    Unauthorized(String),
+    NotFound(String),
+    Conflict(String),
+    Internal(Report),
+}
+
+impl ApiError {
+    /// A raced insert fails on a unique or primary key, that is a conflict rather than a failure
+    fn unique_conflict(message: String) -> impl FnOnce(Report) -> ApiError {
+        move |e| match is_unique_violation(&e) {
+            true => ApiError::Conflict(message),
+            false => ApiError::Internal(e),
+        }
+    }
+}
+
+impl<E: Into<Report>> From<E> for ApiError {
+    fn from(value: E) -> Self {
+        ApiError::Internal(value.into())
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: String,
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let (status, error) = match self {
+            ApiError::BadRequest(v) => (StatusCode::BAD_REQUEST, v),
+            ApiError::Unauthorized(v) => (StatusCode::UNAUTHORIZED, v),
+            ApiError::NotFound(v) => (StatusCode::NOT_FOUND, v),
+            ApiError::Conflict(v) => (StatusCode::CONFLICT, v),
+            ApiError::Internal(e) => {
+                error!("Request failed: {:?}", e);
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Internal server error".to_string(),
+                )
+            }
+        };
+        (status, Json(ErrorBody { error })).into_response()
+    }
+}
+
+/// First column of the first row, if any
+async fn first_value(
+    conn: &mut MiddlewarePoolConnection,
+    query: &str,
+    params: Vec<RowValues>,
+) -> Result<Option<RowValues>> {
+    let result = conn.query(query).params(&params).select().await?;
+    Ok(result
+        .results
+        .first()
+        .and_then(|row| row.get_by_index(0))
+        .cloned())
+}
+
+/// The drivers only expose unique violations in their messages, some only in `Debug`
+fn is_unique_violation(error: &Report) -> bool {
+    error.chain().any(|e| {
+        let message = format!("{e} {e:?}");
+        UNIQUE_VIOLATIONS.iter().any(|v| message.contains(v))
+    })
+}
+
+fn router(config: &ServerConfig, state: AppState, metrics: Option<PrometheusHandle>) -> Router {
+    let mut router = Router::new()
+        .merge(claims::router())
+        .merge(query::router())
+        .merge(events::router())
+        .merge(health::router(config));
+    if config.csc_proxy {
+        router = router.merge(proxy::router());
+    }
+    if let Some(handle) = metrics {
+        router = router.merge(metrics::router(handle));
+    }
+    router.with_state(state)
+}
+
+/// Long-lived task serving the http api. `metrics` is the recorder installed at startup, if enabled.
+#[instrument(skip_all, fields(task_id=%id(), bind = config.bind))]
+pub(crate) async fn server_task(
+    config: ServerConfig,
+    state: AppState,
+    metrics: Option<PrometheusHandle>,
+    cancel_token: CancellationToken,
+) -> () {
+    info!("Initializing server task");
+
+    let listener = match TcpListener::bind(&config.bind).await {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Failed to bind {}: {:?}", config.bind, e);
+            return;
+        }
+    };
+
+    let serve = axum::serve(listener, router(&config, state, metrics))
+        .with_graceful_shutdown(cancel_token.cancelled_owned());
+    if let Err(e) = serve.await {
+        error!("Server error: {:?}", e);
+    }
+}
+
+/// Postgres, sqlite, then mssql primary key and unique index messages
+const UNIQUE_VIOLATIONS: &[&str] = &[
+    "duplicate key value violates unique constraint",
+    "UNIQUE constraint failed",
+    "Violation of PRIMARY KEY constraint",
+    "Violation of UNIQUE KEY constraint",
+    "Cannot insert duplicate key",
+];
@@ -0,0 +1,139 @@
+use crate::logic::db::{value_as_bool, value_as_int, value_as_json, value_as_timestamp};
+use crate::models::api::{Machine, MachineSettings, MachineType, ModeType};
+use crate::server::query::{REMOVED_AFTER, room_newest};
+use crate::server::{ApiError, AppState, first_value};
+use crate::utils::prelude::*;
+use axum::Router;
+use axum::extract::{Path, State};
+use axum::http::HeaderName;
+use axum::response::{IntoResponse, Json};
+use axum::routing::get;
+use chrono::Utc;
+use color_eyre::eyre::OptionExt;
+use sql_middleware::{CustomDbRow, RowValues};
+use uuid::Uuid;
+
+/// Seconds since the snapshot was polled from CSC
+const SNAPSHOT_AGE: HeaderName = HeaderName::from_static("x-snapshot-age");
+/// Time the snapshot was polled from CSC, UTC
+const SNAPSHOT_TIME: HeaderName = HeaderName::from_static("x-snapshot-time");
+
+/// Same paths and shape as CSC, served from the latest stored rows
+pub(super) fn router() -> Router<AppState> {
+    Router::new().route(
+        "/api/v1/location/{location_id}/room/{room_id}/machines",
+        get(room_machines),
+    )
+}
+
+#[instrument(skip_all)]
+async fn room_machines(
+    State(state): State<AppState>,
+    Path((location_id, room_id)): Path<(String, String)>,
+) -> Result<impl IntoResponse, ApiError> {
+    let mut conn = state.connection().await?;
+    let newest = room_newest(&mut conn, &location_id, &room_id).await?;
+    // Backfilled rows are estimates, staleness is measured from the last real poll
+    let last_poll = first_value(
+        &mut conn,
+        SELECT_LAST_POLL_QUERY,
+        vec![
+            RowValues::Text(location_id.clone()),
+            RowValues::Text(room_id.clone()),
+            RowValues::Bool(false),
+        ],
+    )
+    .await?
+    .as_ref()
+    .and_then(value_as_timestamp)
+    .unwrap_or(newest);
+
+    let result = conn
+        .query(SELECT_MACHINES_QUERY)
+        .params(&[
+            RowValues::Text(location_id),
+            RowValues::Text(room_id),
+            RowValues::Timestamp(newest - REMOVED_AFTER),
+        ])
+        .select()
+        .await?;
+
+    let mut machines = vec![];
+    for row in result.results.iter() {
+        // One bad row should not fail the whole room
+        match machine_from_row(row) {
+            Ok(v) => machines.push(v),
+            Err(e) => warn!("Skipping machine row: {:?}", e),
+        }
+    }
+
+    let age = (Utc::now().naive_utc() - last_poll).num_seconds().max(0);
+    Ok((
+        [
+            (SNAPSHOT_AGE, age.to_string()),
+            (SNAPSHOT_TIME, last_poll.and_utc().to_rfc3339()),
+        ],
+        Json(machines),
+    ))
+}
+
+/// Rebuilds a CSC machine from its latest row. Fields that were not stored are left out.
+fn machine_from_row(row: &CustomDbRow) -> Result<Machine> {
+    let column = |index: usize| {
+        row.get_by_index(index)
+            .ok_or_eyre(format!("Failed to get row by index {index}"))
+    };
+    let text = |index: usize| -> Result<String> {
+        Ok(column(index)?
+            .as_text()
+            .ok_or_eyre(format!("Invalid text at index {index}"))?
+            .to_string())
+    };
+
+    let settings = match value_as_json(column(17)?) {
+        Some(v) => serde_json::from_value(v)?,
+        None => MachineSettings::default(),
+    };
+    // Stored as 0 when CSC sent none, older rows did not record it
+    let time_remaining = match value_as_bool(column(19)?) {
+        Some(false) => None,
+        _ => Some(i16::try_from(
+            value_as_int(column(11)?).ok_or_eyre("Invalid time_remaining")?,
+        )?),
+    };
+
+    Ok(Machine {
+        opaque_id: Uuid::parse_str(&text(0)?)?,
+        location_id: Uuid::parse_str(&text(1)?)?,
+        room_id: text(2)?,
+        nfc_id: Uuid::parse_str(&text(3)?)?,
+        qr_code_id: text(4)?,
+        license_plate: text(5)?,
+        sticker_number: i16::try_from(
+            value_as_int(column(6)?).ok_or_eyre("Invalid sticker_number")?,
+        )?,
+        r#type: MachineType::from(text(7)?),
+        door_closed: value_as_bool(column(8)?).ok_or_eyre("Invalid door_closed")?,
+        available: value_as_bool(column(18)?),
+        not_available_reason: column(9)?.as_text().map(str::to_string),
+        mode: ModeType::from(text(10)?),
+        time_remaining,
+        controller_type: column(12)?.as_text().map(str::to_string),
+        in_service: value_as_bool(column(13)?),
+        free_play: value_as_bool(column(14)?),
+        capabilities: value_as_json(column(15)?),
+        pricing: value_as_json(column(16)?),
+        settings,
+    })
+}
+
+const SELECT_MACHINES_QUERY: &str = "SELECT m.machine_id, p.location_id, p.room_id, m.nfc_id, m.qr_code_id, \
+    m.license_plate, p.sticker_number, m.type, l.door_closed, l.not_available_reason, l.state, l.time_remaining, \
+    m.controller_type, m.in_service, m.free_play, m.capabilities, m.pricing, l.machine_settings, \
+    l.available, l.time_remaining_reported \
+    FROM physicalendpoint p JOIN machines m ON p.machine_id = m.machine_id JOIN laundrylog l ON l.pep_id = p.pep_id \
+    WHERE p.location_id = $1 AND p.room_id = $2 AND l.\"timestamp\" >= $3 \
+    AND l.\"timestamp\" = (SELECT MAX(\"timestamp\") FROM laundrylog WHERE pep_id = p.pep_id) \
+    ORDER BY p.sticker_number";
+const SELECT_LAST_POLL_QUERY: &str = "SELECT MAX(l.\"timestamp\") FROM laundrylog l \
+    JOIN physicalendpoint p ON l.pep_id = p.pep_id WHERE p.location_id = $1 AND p.room_id = $2 AND l.backfilled = $3";
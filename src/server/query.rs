@@ -0,0 +1,852 @@
+use crate::db::DbType;
+use crate::logic::db::{value_as_bool, value_as_int, value_as_json, value_as_timestamp};
+use crate::server::{ApiError, AppState, first_value};
+use crate::utils::prelude::*;
+use axum::extract::{Path, Query, State};
+use axum::routing::get;
+use axum::{Json, Router};
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime, TimeDelta, TimeZone, Timelike, Utc};
+use chrono_tz::Tz;
+use color_eyre::eyre::{OptionExt, eyre};
+use serde::{Deserialize, Serialize};
+use sql_middleware::{MiddlewarePoolConnection, RowValues};
+use std::collections::{BTreeMap, HashSet};
+
+/// Range used when a request has no `from`
+const DEFAULT_RANGE: TimeDelta = TimeDelta::days(1);
+const DEFAULT_LIMIT: i64 = 100;
+const MAX_LIMIT: i64 = 1000;
+/// Machines not seen this long before the newest poll of their room were removed or replaced
+pub(super) const REMOVED_AFTER: TimeDelta = TimeDelta::days(1);
+
+pub(super) fn router() -> Router<AppState> {
+    Router::new()
+        .route("/api/v1/locations", get(list_locations))
+        .route(
+            "/api/v1/locations/{location_id}/rooms/{room_id}/status",
+            get(room_status),
+        )
+        .route(
+            "/api/v1/locations/{location_id}/rooms/{room_id}/utilization",
+            get(room_utilization),
+        )
+        .route("/api/v1/peps/{pep_id}/history", get(pep_history))
+        .route("/api/v1/peps/{pep_id}/cycles", get(pep_cycles))
+}
+
+/// Time filter and keyset pagination shared by every range endpoint
+#[derive(Deserialize)]
+struct RangeParams {
+    from: Option<NaiveDateTime>,
+    to: Option<NaiveDateTime>,
+    limit: Option<i64>,
+    /// `next` of the previous page
+    after: Option<NaiveDateTime>,
+}
+
+impl RangeParams {
+    fn range(&self) -> Result<(NaiveDateTime, NaiveDateTime), ApiError> {
+        range(self.from, self.to)
+    }
+
+    fn limit(&self) -> Result<i64, ApiError> {
+        match self.limit.unwrap_or(DEFAULT_LIMIT) {
+            v @ 1..=MAX_LIMIT => Ok(v),
+            _ => Err(ApiError::BadRequest(format!(
+                "limit must be between 1 and {MAX_LIMIT}"
+            ))),
+        }
+    }
+
+    /// Exclusive lower bound, anything before `from` when not paging
+    fn after(&self, from: NaiveDateTime) -> NaiveDateTime {
+        self.after.unwrap_or(from - TimeDelta::seconds(1))
+    }
+}
+
+/// Range of a utilization report
+#[derive(Deserialize)]
+struct UtilizationParams {
+    from: Option<NaiveDateTime>,
+    to: Option<NaiveDateTime>,
+    /// A whole day in the location's timezone, instead of `from` and `to`
+    date: Option<NaiveDate>,
+}
+
+impl UtilizationParams {
+    fn range(&self, timezone: Tz) -> Result<(NaiveDateTime, NaiveDateTime), ApiError> {
+        let Some(date) = self.date else {
+            return range(self.from, self.to);
+        };
+        if self.from.is_some() || self.to.is_some() {
+            return Err(ApiError::BadRequest(
+                "date can not be combined with from or to".to_string(),
+            ));
+        }
+        let next = date
+            .succ_opt()
+            .ok_or_else(|| ApiError::BadRequest("date is out of range".to_string()))?;
+        Ok((day_start(timezone, date), day_start(timezone, next)))
+    }
+}
+
+fn range(
+    from: Option<NaiveDateTime>,
+    to: Option<NaiveDateTime>,
+) -> Result<(NaiveDateTime, NaiveDateTime), ApiError> {
+    let to = to.unwrap_or_else(|| Utc::now().naive_utc());
+    let from = from.unwrap_or(to - DEFAULT_RANGE);
+    if from >= to {
+        return Err(ApiError::BadRequest("from must be before to".to_string()));
+    }
+    Ok((from, to))
+}
+
+/// Start of a local day in UTC. Where DST skips midnight the day starts at the first local time
+fn day_start(timezone: Tz, date: NaiveDate) -> NaiveDateTime {
+    let midnight = date.and_time(NaiveTime::MIN);
+    (0..24)
+        .find_map(|hour| {
+            timezone
+                .from_local_datetime(&(midnight + TimeDelta::hours(hour)))
+                .earliest()
+        })
+        .map(|v| v.naive_utc())
+        .unwrap_or(midnight)
+}
+
+/// Calls `f` with the local hour of day and seconds of every local hour `from..to` overlaps
+fn split_hours(
+    timezone: Tz,
+    from: NaiveDateTime,
+    to: NaiveDateTime,
+    mut f: impl FnMut(usize, i64),
+) {
+    let mut cursor = from;
+    while cursor < to {
+        let local = timezone.from_utc_datetime(&cursor);
+        // Offsets are not always whole hours, the hour ends where the local one does
+        let into_hour = TimeDelta::seconds((local.minute() * 60 + local.second()) as i64)
+            + TimeDelta::nanoseconds(local.nanosecond() as i64);
+        let next = (cursor - into_hour + TimeDelta::hours(1)).min(to);
+        f(local.hour() as usize, (next - cursor).num_seconds());
+        cursor = next;
+    }
+}
+
+/// Running time of every local hour of day `from..to` overlaps, shared by `machines`
+fn hour_utilization(
+    timezone: Tz,
+    from: NaiveDateTime,
+    to: NaiveDateTime,
+    running: &[i64; 24],
+    machines: usize,
+) -> Vec<HourUtilization> {
+    let mut covered = [0; 24];
+    split_hours(timezone, from, to, |hour, seconds| covered[hour] += seconds);
+    (0..24)
+        .filter(|hour| covered[*hour] > 0)
+        .map(|hour| HourUtilization {
+            hour,
+            running_seconds: running[hour],
+            utilization: match machines {
+                0 => 0.0,
+                count => running[hour] as f64 / (covered[hour] * count as i64) as f64,
+            },
+        })
+        .collect()
+}
+
+#[derive(Serialize)]
+struct Page<T> {
+    items: Vec<T>,
+    /// Pass as `after` to get the next page, missing on the last page
+    next: Option<NaiveDateTime>,
+}
+
+impl<T> Page<T> {
+    /// Rows are fetched with one extra to know if another page exists
+    fn new(mut items: Vec<T>, limit: i64, key: impl Fn(&T) -> NaiveDateTime) -> Self {
+        let more = items.len() as i64 > limit;
+        items.truncate(limit as usize);
+        let next = match more {
+            true => items.last().map(key),
+            false => None,
+        };
+        Self { items, next }
+    }
+}
+
+#[derive(Serialize)]
+struct Location {
+    location_id: String,
+    label: String,
+    description: Option<String>,
+    timezone: String,
+    rooms: Vec<Room>,
+}
+
+#[derive(Serialize)]
+struct Room {
+    room_id: String,
+    label: String,
+    description: Option<String>,
+}
+
+#[derive(Serialize)]
+struct MachineStatus {
+    pep_id: String,
+    sticker_number: i64,
+    r#type: String,
+    timestamp: NaiveDateTime,
+    state: String,
+    time_remaining: i64,
+    door_closed: bool,
+    not_available_reason: Option<String>,
+    backfilled: bool,
+    /// Laundry was probably left inside after the last cycle
+    probably_full: bool,
+}
+
+#[derive(Serialize)]
+struct HistoryRow {
+    timestamp: NaiveDateTime,
+    state: String,
+    time_remaining: i64,
+    door_closed: bool,
+    not_available_reason: Option<String>,
+    machine_settings: Option<serde_json::Value>,
+    backfilled: bool,
+}
+
+#[derive(Serialize)]
+struct CycleRow {
+    cycle_start: NaiveDateTime,
+    cycle_end: Option<NaiveDateTime>,
+    duration_seconds: Option<i64>,
+    machine_settings: Option<serde_json::Value>,
+    interrupted: bool,
+    estimated_end: bool,
+    door_opened: Option<NaiveDateTime>,
+    door_open_seconds: Option<i64>,
+    probably_full: bool,
+}
+
+#[derive(Serialize)]
+struct Utilization {
+    from: NaiveDateTime,
+    to: NaiveDateTime,
+    /// Timezone of the location, `hours` are local to it
+    timezone: String,
+    machines: Vec<MachineUtilization>,
+    /// Running time of the whole room by hour of day, the busiest are the peak hours
+    hours: Vec<HourUtilization>,
+}
+
+#[derive(Serialize)]
+struct HourUtilization {
+    /// Hour of day in the location's timezone, 0 to 23
+    hour: usize,
+    running_seconds: i64,
+    /// Share of the machines' time in this hour spent running, from 0 to 1
+    utilization: f64,
+}
+
+#[derive(Serialize)]
+struct MachineUtilization {
+    pep_id: String,
+    sticker_number: i64,
+    r#type: String,
+    /// Cycles overlapping the range
+    cycles: i64,
+    running_seconds: i64,
+    /// Share of the range spent running, from 0 to 1
+    utilization: f64,
+}
+
+#[instrument(skip_all)]
+async fn list_locations(State(state): State<AppState>) -> Result<Json<Vec<Location>>, ApiError> {
+    let mut conn = state.connection().await?;
+
+    let result = conn.query(SELECT_LOCATIONS_QUERY).select().await?;
+    let mut locations = vec![];
+    for row in result.results.iter() {
+        locations.push(Location {
+            location_id: text(row.get_by_index(0))?,
+            label: text(row.get_by_index(1))?,
+            description: row
+                .get_by_index(2)
+                .and_then(|v| v.as_text())
+                .map(str::to_string),
+            timezone: text(row.get_by_index(3))?,
+            rooms: vec![],
+        });
+    }
+
+    let result = conn.query(SELECT_ROOMS_QUERY).select().await?;
+    for row in result.results.iter() {
+        let location_id = text(row.get_by_index(0))?;
+        let room = Room {
+            room_id: text(row.get_by_index(1))?,
+            label: text(row.get_by_index(2))?,
+            description: row
+                .get_by_index(3)
+                .and_then(|v| v.as_text())
+                .map(str::to_string),
+        };
+        if let Some(location) = locations.iter_mut().find(|v| v.location_id == location_id) {
+            location.rooms.push(room);
+        }
+    }
+
+    Ok(Json(locations))
+}
+
+/// Latest row of every machine in a room
+#[instrument(skip_all)]
+async fn room_status(
+    State(state): State<AppState>,
+    Path((location_id, room_id)): Path<(String, String)>,
+) -> Result<Json<Vec<MachineStatus>>, ApiError> {
+    let mut conn = state.connection().await?;
+    let newest = room_newest(&mut conn, &location_id, &room_id).await?;
+    Ok(Json(
+        room_machines(&mut conn, &location_id, &room_id, newest).await?,
+    ))
+}
+
+#[instrument(skip_all)]
+async fn pep_history(
+    State(state): State<AppState>,
+    Path(pep_id): Path<String>,
+    Query(params): Query<RangeParams>,
+) -> Result<Json<Page<HistoryRow>>, ApiError> {
+    let (from, to) = params.range()?;
+    let limit = params.limit()?;
+    let mut conn = state.connection().await?;
+    ensure_pep(&mut conn, &pep_id).await?;
+
+    let query = match state.db_type {
+        DbType::Mssql => MSSQL_SELECT_HISTORY_QUERY,
+        _ => SELECT_HISTORY_QUERY,
+    };
+    let result = conn
+        .query(query)
+        .params(&[
+            RowValues::Text(pep_id),
+            RowValues::Timestamp(from),
+            RowValues::Timestamp(params.after(from)),
+            RowValues::Timestamp(to),
+            RowValues::Int(limit + 1),
+        ])
+        .select()
+        .await?;
+
+    let mut items = vec![];
+    for row in result.results.iter() {
+        items.push(HistoryRow {
+            timestamp: timestamp(row.get_by_index(0))?,
+            state: text(row.get_by_index(1))?,
+            time_remaining: int(row.get_by_index(2))?,
+            door_closed: boolean(row.get_by_index(3))?,
+            not_available_reason: row
+                .get_by_index(4)
+                .and_then(|v| v.as_text())
+                .map(str::to_string),
+            machine_settings: row.get_by_index(5).and_then(value_as_json),
+            backfilled: boolean(row.get_by_index(6))?,
+        });
+    }
+    Ok(Json(Page::new(items, limit, |v| v.timestamp)))
+}
+
+#[instrument(skip_all)]
+async fn pep_cycles(
+    State(state): State<AppState>,
+    Path(pep_id): Path<String>,
+    Query(params): Query<RangeParams>,
+) -> Result<Json<Page<CycleRow>>, ApiError> {
+    let (from, to) = params.range()?;
+    let limit = params.limit()?;
+    let mut conn = state.connection().await?;
+    ensure_pep(&mut conn, &pep_id).await?;
+
+    let query = match state.db_type {
+        DbType::Mssql => MSSQL_SELECT_CYCLES_QUERY,
+        _ => SELECT_CYCLES_QUERY,
+    };
+    let result = conn
+        .query(query)
+        .params(&[
+            RowValues::Text(pep_id),
+            RowValues::Timestamp(from),
+            RowValues::Timestamp(params.after(from)),
+            RowValues::Timestamp(to),
+            RowValues::Int(limit + 1),
+        ])
+        .select()
+        .await?;
+
+    let mut items = vec![];
+    for row in result.results.iter() {
+        items.push(CycleRow {
+            cycle_start: timestamp(row.get_by_index(0))?,
+            cycle_end: row.get_by_index(1).and_then(value_as_timestamp),
+            duration_seconds: row.get_by_index(2).and_then(value_as_int),
+            machine_settings: row.get_by_index(3).and_then(value_as_json),
+            interrupted: boolean(row.get_by_index(4))?,
+            estimated_end: boolean(row.get_by_index(5))?,
+            door_opened: row.get_by_index(6).and_then(value_as_timestamp),
+            door_open_seconds: row.get_by_index(7).and_then(value_as_int),
+            probably_full: boolean(row.get_by_index(8))?,
+        });
+    }
+    Ok(Json(Page::new(items, limit, |v| v.cycle_start)))
+}
+
+/// Time each machine in a room spent running, from `MachineCycles`. Cycles are clipped to the range.
+#[instrument(skip_all)]
+async fn room_utilization(
+    State(state): State<AppState>,
+    Path((location_id, room_id)): Path<(String, String)>,
+    Query(params): Query<UtilizationParams>,
+) -> Result<Json<Utilization>, ApiError> {
+    let mut conn = state.connection().await?;
+    let timezone = location_timezone(&mut conn, &location_id).await?;
+    let (from, to) = params.range(timezone)?;
+    let newest = room_newest(&mut conn, &location_id, &room_id).await?;
+
+    let mut machines: BTreeMap<String, MachineUtilization> = BTreeMap::new();
+    for machine in room_machines(&mut conn, &location_id, &room_id, newest).await? {
+        machines.insert(
+            machine.pep_id.clone(),
+            MachineUtilization {
+                pep_id: machine.pep_id,
+                sticker_number: machine.sticker_number,
+                r#type: machine.r#type,
+                cycles: 0,
+                running_seconds: 0,
+                utilization: 0.0,
+            },
+        );
+    }
+
+    let result = conn
+        .query(SELECT_ROOM_CYCLES_QUERY)
+        .params(&[
+            RowValues::Text(location_id),
+            RowValues::Text(room_id),
+            RowValues::Timestamp(to),
+            RowValues::Timestamp(from),
+            RowValues::Bool(false),
+        ])
+        .select()
+        .await?;
+    let mut running = [0; 24];
+    for row in result.results.iter() {
+        let pep_id = text(row.get_by_index(0))?;
+        let start = timestamp(row.get_by_index(1))?;
+        // Still open, counts up to the newest real poll. A machine that stopped reporting would
+        // otherwise run forever
+        let end = match row.get_by_index(2).and_then(value_as_timestamp) {
+            Some(v) => v,
+            None => timestamp(row.get_by_index(3))?,
+        };
+        if let Some(machine) = machines.get_mut(&pep_id) {
+            machine.cycles += 1;
+            machine.running_seconds += (end.min(to) - start.max(from)).num_seconds().max(0);
+            split_hours(timezone, start.max(from), end.min(to), |hour, seconds| {
+                running[hour] += seconds
+            });
+        }
+    }
+
+    let span = (to - from).num_seconds() as f64;
+    let hours = hour_utilization(timezone, from, to, &running, machines.len());
+
+    let mut machines: Vec<_> = machines.into_values().collect();
+    for machine in machines.iter_mut() {
+        machine.utilization = machine.running_seconds as f64 / span;
+    }
+    machines.sort_by_key(|v| v.sticker_number);
+
+    Ok(Json(Utilization {
+        from,
+        to,
+        timezone: timezone.name().to_string(),
+        machines,
+        hours,
+    }))
+}
+
+/// Timezone local analytics of a location use
+async fn location_timezone(
+    conn: &mut MiddlewarePoolConnection,
+    location_id: &str,
+) -> Result<Tz, ApiError> {
+    let timezone = first_value(
+        conn,
+        SELECT_LOCATION_TIMEZONE_QUERY,
+        vec![RowValues::Text(location_id.to_string())],
+    )
+    .await?
+    .ok_or_else(|| ApiError::NotFound(format!("location {location_id} not found")))?;
+    let timezone = timezone.as_text().ok_or_eyre("Invalid timezone column")?;
+    timezone.parse().map_err(|_| {
+        ApiError::Internal(eyre!(
+            "Location {location_id} has unknown timezone {timezone:?}"
+        ))
+    })
+}
+
+async fn ensure_pep(conn: &mut MiddlewarePoolConnection, pep_id: &str) -> Result<(), ApiError> {
+    match first_value(
+        conn,
+        SELECT_PEP_QUERY,
+        vec![RowValues::Text(pep_id.to_string())],
+    )
+    .await?
+    {
+        Some(_) => Ok(()),
+        None => Err(ApiError::NotFound(format!("machine {pep_id} not found"))),
+    }
+}
+
+/// Newest poll of a room, machines not seen close to it are left out
+pub(super) async fn room_newest(
+    conn: &mut MiddlewarePoolConnection,
+    location_id: &str,
+    room_id: &str,
+) -> Result<NaiveDateTime, ApiError> {
+    first_value(
+        conn,
+        SELECT_ROOM_NEWEST_QUERY,
+        vec![
+            RowValues::Text(location_id.to_string()),
+            RowValues::Text(room_id.to_string()),
+        ],
+    )
+    .await?
+    .as_ref()
+    .and_then(value_as_timestamp)
+    .ok_or_else(|| ApiError::NotFound(format!("room {room_id} not found")))
+}
+
+async fn room_machines(
+    conn: &mut MiddlewarePoolConnection,
+    location_id: &str,
+    room_id: &str,
+    newest: NaiveDateTime,
+) -> Result<Vec<MachineStatus>> {
+    let result = conn
+        .query(SELECT_PROBABLY_FULL_QUERY)
+        .params(&[
+            RowValues::Text(location_id.to_string()),
+            RowValues::Text(room_id.to_string()),
+            RowValues::Bool(true),
+        ])
+        .select()
+        .await?;
+    let mut probably_full = HashSet::new();
+    for row in result.results.iter() {
+        probably_full.insert(text(row.get_by_index(0))?);
+    }
+
+    let result = conn
+        .query(SELECT_ROOM_STATUS_QUERY)
+        .params(&[
+            RowValues::Text(location_id.to_string()),
+            RowValues::Text(room_id.to_string()),
+            RowValues::Timestamp(newest - REMOVED_AFTER),
+        ])
+        .select()
+        .await?;
+    let mut machines = vec![];
+    for row in result.results.iter() {
+        let pep_id = text(row.get_by_index(0))?;
+        machines.push(MachineStatus {
+            probably_full: probably_full.contains(&pep_id),
+            pep_id,
+            sticker_number: int(row.get_by_index(1))?,
+            r#type: text(row.get_by_index(2))?,
+            timestamp: timestamp(row.get_by_index(3))?,
+            state: text(row.get_by_index(4))?,
+            time_remaining: int(row.get_by_index(5))?,
+            door_closed: boolean(row.get_by_index(6))?,
+            not_available_reason: row
+                .get_by_index(7)
+                .and_then(|v| v.as_text())
+                .map(str::to_string),
+            backfilled: boolean(row.get_by_index(8))?,
+        });
+    }
+    Ok(machines)
+}
+
+fn text(value: Option<&RowValues>) -> Result<String> {
+    Ok(value
+        .and_then(|v| v.as_text())
+        .ok_or_eyre("Invalid text column")?
+        .to_string())
+}
+
+fn int(value: Option<&RowValues>) -> Result<i64> {
+    value
+        .and_then(value_as_int)
+        .ok_or_eyre("Invalid int column")
+}
+
+fn boolean(value: Option<&RowValues>) -> Result<bool> {
+    value
+        .and_then(value_as_bool)
+        .ok_or_eyre("Invalid bool column")
+}
+
+fn timestamp(value: Option<&RowValues>) -> Result<NaiveDateTime> {
+    value
+        .and_then(value_as_timestamp)
+        .ok_or_eyre("Invalid timestamp column")
+}
+
+const SELECT_LOCATIONS_QUERY: &str =
+    "SELECT location_id, label, description, timezone FROM locations ORDER BY label";
+const SELECT_LOCATION_TIMEZONE_QUERY: &str =
+    "SELECT timezone FROM locations WHERE location_id = $1";
+// Rooms have no location, it is taken from the machines found in them
+const SELECT_ROOMS_QUERY: &str = "SELECT DISTINCT p.location_id, r.room_id, r.label, r.description \
+    FROM rooms r JOIN physicalendpoint p ON r.room_id = p.room_id ORDER BY r.label";
+const SELECT_PEP_QUERY: &str = "SELECT pep_id FROM physicalendpoint WHERE pep_id = $1";
+
+const SELECT_ROOM_NEWEST_QUERY: &str = "SELECT MAX(l.\"timestamp\") FROM laundrylog l \
+    JOIN physicalendpoint p ON l.pep_id = p.pep_id WHERE p.location_id = $1 AND p.room_id = $2";
+const SELECT_ROOM_STATUS_QUERY: &str = "SELECT p.pep_id, p.sticker_number, m.type, l.\"timestamp\", l.state, l.time_remaining, \
+    l.door_closed, l.not_available_reason, l.backfilled \
+    FROM physicalendpoint p JOIN machines m ON p.machine_id = m.machine_id JOIN laundrylog l ON l.pep_id = p.pep_id \
+    WHERE p.location_id = $1 AND p.room_id = $2 AND l.\"timestamp\" >= $3 \
+    AND l.\"timestamp\" = (SELECT MAX(\"timestamp\") FROM laundrylog WHERE pep_id = p.pep_id) \
+    ORDER BY p.sticker_number";
+const SELECT_PROBABLY_FULL_QUERY: &str = "SELECT c.pep_id FROM machinecycles c \
+    JOIN physicalendpoint p ON c.pep_id = p.pep_id \
+    WHERE p.location_id = $1 AND p.room_id = $2 AND c.probably_full = $3";
+
+const SELECT_HISTORY_QUERY: &str = "SELECT \"timestamp\", state, time_remaining, door_closed, not_available_reason, machine_settings, backfilled \
+    FROM laundrylog WHERE pep_id = $1 AND \"timestamp\" >= $2 AND \"timestamp\" > $3 AND \"timestamp\" < $4 \
+    ORDER BY \"timestamp\" LIMIT $5";
+const MSSQL_SELECT_HISTORY_QUERY: &str = "SELECT TOP ($5) \"timestamp\", state, time_remaining, door_closed, not_available_reason, machine_settings, backfilled \
+    FROM laundrylog WHERE pep_id = $1 AND \"timestamp\" >= $2 AND \"timestamp\" > $3 AND \"timestamp\" < $4 \
+    ORDER BY \"timestamp\"";
+
+const SELECT_CYCLES_QUERY: &str = "SELECT cycle_start, cycle_end, duration_seconds, machine_settings, interrupted, estimated_end, \
+    door_opened, door_open_seconds, probably_full \
+    FROM machinecycles WHERE pep_id = $1 AND cycle_start >= $2 AND cycle_start > $3 AND cycle_start < $4 \
+    ORDER BY cycle_start LIMIT $5";
+const MSSQL_SELECT_CYCLES_QUERY: &str = "SELECT TOP ($5) cycle_start, cycle_end, duration_seconds, machine_settings, interrupted, estimated_end, \
+    door_opened, door_open_seconds, probably_full \
+    FROM machinecycles WHERE pep_id = $1 AND cycle_start >= $2 AND cycle_start > $3 AND cycle_start < $4 \
+    ORDER BY cycle_start";
+
+// Every cycle overlapping the range, with the newest real poll of its pep
+const SELECT_ROOM_CYCLES_QUERY: &str = "SELECT c.pep_id, c.cycle_start, c.cycle_end, \
+    (SELECT MAX(\"timestamp\") FROM laundrylog WHERE pep_id = c.pep_id AND backfilled = $5) \
+    FROM machinecycles c JOIN physicalendpoint p ON c.pep_id = p.pep_id \
+    WHERE p.location_id = $1 AND p.room_id = $2 AND c.cycle_start < $3 AND (c.cycle_end IS NULL OR c.cycle_end > $4)";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{at, date, utc};
+    use chrono_tz::{America, Asia, Europe};
+
+    fn params(from: Option<i64>, to: Option<i64>, limit: Option<i64>) -> RangeParams {
+        RangeParams {
+            from: from.map(at),
+            to: to.map(at),
+            limit,
+            after: None,
+        }
+    }
+
+    #[test]
+    fn range_defaults_to_a_day_before_to() {
+        let range = params(None, Some(86400), None).range().ok();
+        assert_eq!(range, Some((at(0), at(86400))));
+    }
+
+    #[test]
+    fn range_rejects_from_not_before_to() {
+        assert!(matches!(
+            params(Some(60), Some(60), None).range(),
+            Err(ApiError::BadRequest(_))
+        ));
+        assert!(matches!(
+            params(Some(120), Some(60), None).range(),
+            Err(ApiError::BadRequest(_))
+        ));
+    }
+
+    #[test]
+    fn limit_within_bounds() {
+        assert_eq!(params(None, None, None).limit().ok(), Some(DEFAULT_LIMIT));
+        assert_eq!(
+            params(None, None, Some(MAX_LIMIT)).limit().ok(),
+            Some(MAX_LIMIT)
+        );
+        assert!(params(None, None, Some(0)).limit().is_err());
+        assert!(params(None, None, Some(MAX_LIMIT + 1)).limit().is_err());
+    }
+
+    #[test]
+    fn after_includes_from_when_not_paging() {
+        let first = params(Some(0), None, None);
+        assert_eq!(first.after(at(0)), at(-1));
+
+        let next = RangeParams {
+            after: Some(at(600)),
+            ..first
+        };
+        assert_eq!(next.after(at(0)), at(600));
+    }
+
+    #[test]
+    fn page_has_next_only_with_more_rows() {
+        let page = Page::new(vec![at(0), at(60), at(120)], 2, |v| *v);
+        assert_eq!(page.items, vec![at(0), at(60)]);
+        assert_eq!(page.next, Some(at(60)));
+
+        let last = Page::new(vec![at(0), at(60)], 2, |v| *v);
+        assert_eq!(last.items.len(), 2);
+        assert_eq!(last.next, None);
+    }
+
+    fn covered(timezone: Tz, from: NaiveDateTime, to: NaiveDateTime) -> [i64; 24] {
+        let mut covered = [0; 24];
+        split_hours(timezone, from, to, |hour, seconds| covered[hour] += seconds);
+        covered
+    }
+
+    /// The local day of `date` in UTC
+    fn day(timezone: Tz, date: NaiveDate) -> (NaiveDateTime, NaiveDateTime) {
+        let next = date.succ_opt().unwrap_or_default();
+        (day_start(timezone, date), day_start(timezone, next))
+    }
+
+    #[test]
+    fn day_start_follows_offset() {
+        assert_eq!(
+            day_start(Europe::Berlin, date(2026, 1, 5)),
+            utc(2026, 1, 4, 23, 0)
+        );
+        assert_eq!(
+            day_start(Europe::Berlin, date(2026, 7, 1)),
+            utc(2026, 6, 30, 22, 0)
+        );
+    }
+
+    #[test]
+    fn day_start_where_dst_skips_midnight() {
+        // Santiago moves from 00:00 -04 to 01:00 -03
+        assert_eq!(
+            day_start(America::Santiago, date(2026, 9, 6)),
+            utc(2026, 9, 6, 4, 0)
+        );
+    }
+
+    #[test]
+    fn utilization_date_is_a_local_day() {
+        let params = UtilizationParams {
+            from: None,
+            to: None,
+            date: Some(date(2026, 1, 5)),
+        };
+        assert_eq!(
+            params.range(Europe::Berlin).ok(),
+            Some((utc(2026, 1, 4, 23, 0), utc(2026, 1, 5, 23, 0)))
+        );
+
+        let mixed = UtilizationParams {
+            from: Some(utc(2026, 1, 5, 0, 0)),
+            ..params
+        };
+        assert!(mixed.range(Europe::Berlin).is_err());
+    }
+
+    #[test]
+    fn split_hours_spring_forward() {
+        let (from, to) = day(Europe::Berlin, date(2026, 3, 29));
+        let covered = covered(Europe::Berlin, from, to);
+        assert_eq!(covered[2], 0);
+        assert_eq!(covered.iter().filter(|v| **v == 3600).count(), 23);
+        assert_eq!(covered.iter().sum::<i64>(), 23 * 3600);
+    }
+
+    #[test]
+    fn split_hours_fall_back() {
+        let (from, to) = day(Europe::Berlin, date(2026, 10, 25));
+        let covered = covered(Europe::Berlin, from, to);
+        assert_eq!(covered[2], 2 * 3600);
+        assert_eq!(covered.iter().sum::<i64>(), 25 * 3600);
+    }
+
+    #[test]
+    fn split_hours_partial_and_half_hour_offsets() {
+        let partial = covered(Tz::UTC, utc(2026, 1, 5, 8, 20), utc(2026, 1, 5, 9, 10));
+        assert_eq!((partial[8], partial[9]), (2400, 600));
+
+        // 05:30 to 06:30 local
+        let kolkata = covered(Asia::Kolkata, utc(2026, 1, 5, 0, 0), utc(2026, 1, 5, 1, 0));
+        assert_eq!((kolkata[5], kolkata[6]), (1800, 1800));
+    }
+
+    #[test]
+    fn hour_utilization_shares_time_between_machines() {
+        let mut running = [0; 24];
+        running[8] = 3600;
+        let (from, to) = day(Tz::UTC, date(2026, 1, 5));
+        let hours = hour_utilization(Tz::UTC, from, to, &running, 2);
+
+        assert_eq!(hours.len(), 24);
+        assert_eq!(hours[8].running_seconds, 3600);
+        assert_eq!(hours[8].utilization, 0.5);
+        assert!(
+            hours
+                .iter()
+                .filter(|v| v.hour != 8)
+                .all(|v| v.utilization == 0.0)
+        );
+    }
+
+    #[test]
+    fn hour_utilization_of_partial_hours() {
+        let mut running = [0; 24];
+        running[8] = 900;
+        let hours = hour_utilization(
+            Tz::UTC,
+            utc(2026, 1, 5, 8, 30),
+            utc(2026, 1, 5, 10, 0),
+            &running,
+            1,
+        );
+
+        assert_eq!(hours.iter().map(|v| v.hour).collect::<Vec<_>>(), vec![8, 9]);
+        assert_eq!(hours[0].utilization, 0.5);
+    }
+
+    #[test]
+    fn hour_utilization_of_repeated_hour() {
+        let mut running = [0; 24];
+        running[2] = 3600;
+        let (from, to) = day(Europe::Berlin, date(2026, 10, 25));
+        let hours = hour_utilization(Europe::Berlin, from, to, &running, 1);
+
+        let repeated = hours.iter().find(|v| v.hour == 2);
+        assert_eq!(repeated.map(|v| v.utilization), Some(0.5));
+    }
+
+    #[test]
+    fn hour_utilization_without_machines() {
+        let running = [60; 24];
+        let (from, to) = day(Tz::UTC, date(2026, 1, 5));
+        let hours = hour_utilization(Tz::UTC, from, to, &running, 0);
+        assert!(hours.iter().all(|v| v.utilization == 0.0));
+    }
+}
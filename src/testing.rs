@@ -0,0 +1,77 @@
+//! Fixtures shared by unit tests
+
+use crate::utils::prelude::*;
+use chrono::{NaiveDate, NaiveDateTime, TimeDelta};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+
+/// A request read by `serve_once`
+pub(crate) struct Request {
+    /// Request line and headers, lowercased
+    pub(crate) head: String,
+    pub(crate) body: Vec<u8>,
+}
+
+/// Answers a single http request on a local port with `status`.
+/// Returns the address to send it to and the request once it was read.
+pub(crate) async fn serve_once(
+    status: &'static str,
+) -> Result<(String, JoinHandle<Result<Request>>)> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let address = listener.local_addr()?.to_string();
+    let handle = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await?;
+        let mut request = Vec::new();
+        let mut buf = [0; 4096];
+        let (head, start, length) = loop {
+            let read = stream.read(&mut buf).await?;
+            if read == 0 {
+                return Err(Report::msg("Connection closed before the request was read"));
+            }
+            request.extend_from_slice(&buf[..read]);
+            if let Some(end) = request.windows(4).position(|v| v == b"\r\n\r\n") {
+                let head = String::from_utf8_lossy(&request[..end]).to_lowercase();
+                let length = head
+                    .lines()
+                    .find_map(|v| v.strip_prefix("content-length:"))
+                    .and_then(|v| v.trim().parse::<usize>().ok())
+                    .unwrap_or(0);
+                break (head, end + 4, length);
+            }
+        };
+        while request.len() < start + length {
+            let read = stream.read(&mut buf).await?;
+            if read == 0 {
+                break;
+            }
+            request.extend_from_slice(&buf[..read]);
+        }
+        stream
+            .write_all(
+                format!("HTTP/1.1 {status}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+                    .as_bytes(),
+            )
+            .await?;
+        Ok(Request {
+            head,
+            body: request.split_off(start),
+        })
+    });
+    Ok((address, handle))
+}
+
+/// `seconds` after 2026-01-05 08:00, the start of every fixture series
+pub(crate) fn at(seconds: i64) -> NaiveDateTime {
+    utc(2026, 1, 5, 8, 0) + TimeDelta::seconds(seconds)
+}
+
+pub(crate) fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+    NaiveDate::from_ymd_opt(year, month, day).unwrap_or_default()
+}
+
+pub(crate) fn utc(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> NaiveDateTime {
+    date(year, month, day)
+        .and_hms_opt(hour, minute, 0)
+        .unwrap_or_default()
+}
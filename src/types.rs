@@ -1,11 +1,13 @@
 use reqwest::Response;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 /// Represents the api endpoint for all machines in a location + room
 #[derive(Debug, Deserialize, Clone)]
 pub(crate) struct RoomMachinesEndpoint {
     pub(crate) location_id: String,
     pub(crate) room_id: String,
+    /// IANA timezone of the location, e.g. `America/New_York`. Wins over the one sent by CSC
+    pub(crate) timezone: Option<String>,
 }
 
 /// One-liner for the common task tracker and token.
@@ -14,17 +16,51 @@ pub(crate) type TrackerWithToken = (
     tokio_util::sync::CancellationToken,
 );
 
-use tokio::sync::{mpsc, oneshot};
-use crate::models::api::ApiLocation;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use crate::models::api::{ApiLocation, ModeType};
+use chrono::NaiveDateTime;
+use std::time::Duration;
+use tracing::Span;
 
 pub(crate) type Http2DbSender = mpsc::Sender<Http2DbMessage>;
 pub(crate) type Http2DbReceiver = mpsc::Receiver<Http2DbMessage>;
 pub(crate) type Http2DbTxRx = (Http2DbSender, Http2DbReceiver);
 
+/// A single request made to the CSC api. Every row written from the request shares `timestamp`.
+#[derive(Debug, Clone)]
+pub(crate) struct ApiRequest {
+    pub(crate) url: String,
+    pub(crate) location_id: Option<String>,
+    pub(crate) room_id: Option<String>,
+    /// Taken right before the request is sent
+    pub(crate) timestamp: NaiveDateTime,
+    /// Time until response headers, or until the request failed
+    pub(crate) latency: Duration,
+    /// Span the request was sent in. The db task stores the response under it, so a poll is traced
+    /// from request to db write as one trace
+    pub(crate) span: Span,
+}
+
 // http -> db
 pub(crate) enum Http2DbMessage {
-    ApiResponse(Response),
-    ApiError(reqwest::Error),
+    ApiResponse(ApiRequest, Response),
+    ApiError(ApiRequest, reqwest::Error),
+    /// Request that is only recorded in `ApiLog`, the body was consumed by the http task
+    ApiRecord {
+        request: ApiRequest,
+        status: Option<u16>,
+        error: Option<reqwest::Error>,
+    },
+}
+
+impl Http2DbMessage {
+    pub(crate) fn request(&self) -> &ApiRequest {
+        match self {
+            Http2DbMessage::ApiResponse(request, _)
+            | Http2DbMessage::ApiError(request, _)
+            | Http2DbMessage::ApiRecord { request, .. } => request,
+        }
+    }
 }
 
 pub(crate) type Db2HttpSender = mpsc::Sender<Db2HttpMessage>;
@@ -45,3 +81,34 @@ pub(crate) enum Db2HttpMessage {
         return_channel: oneshot::Sender<ApiLocation>,
     },
 }
+
+pub(crate) type NotifySender = mpsc::Sender<NotifyMessage>;
+pub(crate) type NotifyReceiver = mpsc::Receiver<NotifyMessage>;
+
+// any -> notify
+pub(crate) enum NotifyMessage {
+    /// A write to the database failed. Api failures are read from `ApiLog` instead.
+    DbWriteFailed {
+        context: &'static str,
+        error: String,
+    },
+}
+
+pub(crate) type StateSender = broadcast::Sender<StateChange>;
+#[cfg_attr(not(feature = "server"), allow(dead_code))]
+pub(crate) type StateReceiver = broadcast::Receiver<StateChange>;
+
+// db -> server
+/// A machine changed mode or its door opened or closed between two polls
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct StateChange {
+    pub(crate) pep_id: String,
+    pub(crate) location_id: String,
+    pub(crate) room_id: String,
+    pub(crate) sticker_number: i16,
+    pub(crate) timestamp: NaiveDateTime,
+    pub(crate) old_mode: ModeType,
+    pub(crate) new_mode: ModeType,
+    pub(crate) time_remaining: Option<i16>,
+    pub(crate) door_closed: bool,
+}